@@ -1,6 +1,9 @@
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub enum Command {
     LogShow(LogShow),
     LogAdd(LogAdd),
@@ -11,56 +14,242 @@ pub enum Command {
     IteratorList(IteratorList),
     IteratorDelete(IteratorDelete),
     IteratorNext(IteratorNext),
+    Snapshot(Snapshot),
+    SnapshotImport(SnapshotImport),
+    MessageRedact(MessageRedact),
+    RedactionList(RedactionList),
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Clone, Deserialize, Debug)]
 pub struct LogShow {
     pub log_name: String,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Clone, Deserialize, Debug)]
 pub struct LogAdd {
     pub log_name: String,
+    #[serde(flatten)]
+    pub options: LogOptions,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Clone, Deserialize, Debug)]
 pub struct LogDelete {
     pub log_name: String,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Clone, Deserialize, Debug)]
 pub struct MessageAdd {
     pub log_name: String,
+    /// Identifies what the Message is about. In a compacted Log, only the
+    /// latest Message with each key is kept.
+    #[serde(default)]
+    pub key: Option<String>,
     pub message: Vec<u8>,
 }
 
-#[derive(Deserialize, Debug)]
+/// Replaces a Message with a tombstone. Only allowed with the server's admin
+/// token.
+#[derive(Clone, Deserialize)]
+pub struct MessageRedact {
+    pub admin_token: String,
+    pub log_name: String,
+    pub message_id: usize,
+    /// Why the Message is being redacted, as recorded in the audit trail
+    pub reason: String,
+}
+
+/// The admin token is left out, so it never ends up in the server's logs.
+impl fmt::Debug for MessageRedact {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("MessageRedact")
+            .field("log_name", &self.log_name)
+            .field("message_id", &self.message_id)
+            .field("reason", &self.reason)
+            .finish()
+    }
+}
+
+/// Lists the audit trail of redactions. Only allowed with the server's admin
+/// token.
+#[derive(Clone, Deserialize)]
+pub struct RedactionList {
+    pub admin_token: String,
+    pub log_name: Option<String>,
+}
+
+impl fmt::Debug for RedactionList {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RedactionList")
+            .field("log_name", &self.log_name)
+            .finish()
+    }
+}
+
+#[derive(Clone, Deserialize, Debug)]
 pub struct IteratorAdd {
     pub log_name: String,
     pub iterator_name: String,
     pub iterator_kind: IteratorKind,
     pub iterator_func: String,
+    /// The accumulator a Reduce Iterator starts from
+    #[serde(default)]
+    pub iterator_initial: Option<serde_cbor::Value>,
+    /// Whether the Iterator's results are stored as Messages are added
+    #[serde(default)]
+    pub indexed: bool,
+    /// The Indexed Iterator whose results are read instead of the Log
+    #[serde(default)]
+    pub iterator_source: Option<String>,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Clone, Deserialize, Debug)]
 pub struct IteratorList {
     pub log_name: Option<String>,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Clone, Deserialize, Debug)]
+#[serde(try_from = "IteratorNextPayload")]
 pub struct IteratorNext {
     pub iterator_name: String,
-    pub message_id: usize,
+    pub position: Position,
     pub count: usize,
+    /// Whether a Reduce Iterator returns its accumulator after every Message
+    /// rather than just the last one
+    pub intermediate: bool,
+}
+
+/// IteratorNext as sent by clients, who start from either a `message_id` or a
+/// `timestamp`
+#[derive(Deserialize)]
+struct IteratorNextPayload {
+    iterator_name: String,
+    message_id: Option<Position>,
+    timestamp: Option<u64>,
+    count: usize,
+    #[serde(default)]
+    intermediate: bool,
+}
+
+impl TryFrom<IteratorNextPayload> for IteratorNext {
+    type Error = &'static str;
+
+    fn try_from(p: IteratorNextPayload) -> Result<Self, Self::Error> {
+        let position = match (p.message_id, p.timestamp) {
+            (Some(position), None) => position,
+            (None, Some(ts)) => Position::Timestamp(ts),
+            _ => return Err("exactly one of message_id or timestamp must be given"),
+        };
+        Ok(IteratorNext {
+            iterator_name: p.iterator_name,
+            position,
+            count: p.count,
+            intermediate: p.intermediate,
+        })
+    }
+}
+
+/// Where an IteratorNext starts reading from. Integers from 0 up are Message
+/// IDs, while negative integers count back from the end of the Log, so -1 is
+/// the last Message.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Deserialize)]
+#[serde(untagged)]
+pub enum Position {
+    Offset(i64),
+    Anchor(Anchor),
+    /// The first Message added at or after a time, in milliseconds since the
+    /// Unix epoch
+    #[serde(skip_deserializing)]
+    Timestamp(u64),
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Anchor {
+    /// The oldest Message still held by the Log
+    Start,
+    /// Just past the last Message in the Log
+    End,
+}
+
+#[derive(Clone, Deserialize, Debug)]
 pub struct IteratorDelete {
     pub log_name: String,
     pub iterator_name: String,
 }
 
-#[derive(PartialEq, Eq, Debug, Deserialize)]
+#[derive(Clone, Deserialize, Debug)]
+pub struct Snapshot {
+    pub snapshot_name: String,
+}
+
+#[derive(Clone, Deserialize, Debug)]
+pub struct SnapshotImport {
+    pub snapshot_name: String,
+    /// The Logs to import, from their name in the Snapshot to the new name to
+    /// give them
+    pub log_names: HashMap<String, String>,
+}
+
+/// Settings chosen when a Log is created
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LogOptions {
+    pub fsync: FsyncPolicy,
+    pub retention: Retention,
+    pub compression: Compression,
+    /// Whether Messages replaced by a later Message with the same key are
+    /// dropped in the background
+    pub compacted: bool,
+    /// The size in bytes of the largest Message the Log accepts
+    pub max_message_size: Option<u64>,
+}
+
+/// How often a Log's appends are flushed to disk. A MessageAdd is only
+/// acknowledged once its Message is durable under the Log's policy.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FsyncPolicy {
+    /// fsync after every Message
+    #[default]
+    Always,
+    /// fsync at most once every N milliseconds
+    EveryMillis(u64),
+    /// fsync once every N Messages
+    EveryMessages(u64),
+}
+
+impl FsyncPolicy {
+    /// Whether the policy ever lets a Log go unsynced. Intervals of zero are
+    /// refused, since `"always"` is the policy to use instead.
+    pub fn is_valid(self) -> bool {
+        match self {
+            FsyncPolicy::Always => true,
+            FsyncPolicy::EveryMillis(n) | FsyncPolicy::EveryMessages(n) => n > 0,
+        }
+    }
+}
+
+/// Limits on how much of a Log is kept around. Old Segments are dropped once
+/// every Message in them falls outside one of these limits.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Retention {
+    pub max_age_secs: Option<u64>,
+    pub max_bytes: Option<u64>,
+    pub max_messages: Option<u64>,
+}
+
+/// How a Log's sealed Segments are compressed on disk
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Compression {
+    #[default]
+    None,
+    Lz4,
+    Zstd,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
 pub enum IteratorKind {
     Map,
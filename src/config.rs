@@ -1,17 +1,82 @@
 use argh::FromArgs;
 use env_logger::{Builder, Target};
 use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+const DEFAULT_DATA_DIR: &str = "./data";
+const DEFAULT_SNAPSHOT_DIR: &str = "snapshots";
+const DEFAULT_SEGMENT_SIZE: u64 = 64 * 1024 * 1024;
+const DEFAULT_ARCHIVE_AFTER_SECS: u64 = 7 * 24 * 60 * 60;
+const DEFAULT_MAX_FRAME_SIZE: u32 = 8 * 1024 * 1024;
 
 /// Server options
 #[derive(Clone, Debug, Serialize, Deserialize, FromArgs)]
 pub struct RemitsConfig {
     #[argh(option, short = 'p')]
     /// what port to start remits on
-    port: Option<String>,
+    pub port: Option<String>,
     // v can change dont care
     #[argh(option, short = 'v')]
     /// verbosity of logs
-    log_level: Option<String>,
+    pub log_level: Option<String>,
+    #[argh(option, short = 'd')]
+    /// directory that logs and the manifest are stored in
+    pub data_dir: Option<PathBuf>,
+    #[argh(option)]
+    /// size in bytes at which a log rolls over to a new segment file
+    pub segment_size: Option<u64>,
+    #[argh(option)]
+    /// file of keys to encrypt logs and the manifest with
+    pub encryption_key_path: Option<PathBuf>,
+    #[argh(option)]
+    /// encrypt a manifest and audit trail written before encryption was turned on
+    pub migrate_plaintext: Option<bool>,
+    #[argh(option)]
+    /// directory to move cold segments to
+    pub archive_dir: Option<PathBuf>,
+    #[argh(option)]
+    /// seconds a sealed segment goes unmodified before it is archived
+    pub archive_after_secs: Option<u64>,
+    #[argh(option)]
+    /// directory snapshots are written to, by default inside the data directory
+    pub snapshot_dir: Option<PathBuf>,
+    #[argh(option)]
+    /// snapshot to fill an empty data directory from before starting
+    pub restore_from: Option<PathBuf>,
+    #[argh(option)]
+    /// token clients must send for admin requests, which are refused without one
+    pub admin_token: Option<String>,
+    #[argh(option)]
+    /// size in bytes of the largest request frame clients may send
+    pub max_frame_size: Option<u32>,
+    #[argh(option)]
+    /// where messages are stored: "file" (the default) or "memory"
+    pub storage: Option<StorageBackend>,
+}
+
+/// Where Logs keep their Messages
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StorageBackend {
+    /// Segment files in the data directory
+    #[default]
+    File,
+    /// Memory only, so every Message is lost when the server stops
+    Memory,
+}
+
+impl FromStr for StorageBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "file" => Ok(StorageBackend::File),
+            "memory" => Ok(StorageBackend::Memory),
+            _ => Err(format!("unknown storage backend {:?}", s)),
+        }
+    }
 }
 
 impl RemitsConfig {
@@ -34,12 +99,130 @@ impl RemitsConfig {
             self.port = flags.port;
         }
 
+        if flags.data_dir.is_some() {
+            debug!(
+                "Replacing config option \"data_dir\":{:?} with flag \"-d/--data-dir\":{:?}",
+                self.data_dir, flags.data_dir
+            );
+            self.data_dir = flags.data_dir;
+        }
+
+        if flags.segment_size.is_some() {
+            debug!(
+                "Replacing config option \"segment_size\":{:?} with flag \"--segment-size\":{:?}",
+                self.segment_size, flags.segment_size
+            );
+            self.segment_size = flags.segment_size;
+        }
+
+        if flags.encryption_key_path.is_some() {
+            debug!(
+                "Replacing config option \"encryption_key_path\":{:?} with flag \"--encryption-key-path\":{:?}",
+                self.encryption_key_path, flags.encryption_key_path
+            );
+            self.encryption_key_path = flags.encryption_key_path;
+        }
+
+        if flags.migrate_plaintext.is_some() {
+            debug!(
+                "Replacing config option \"migrate_plaintext\":{:?} with flag \"--migrate-plaintext\":{:?}",
+                self.migrate_plaintext, flags.migrate_plaintext
+            );
+            self.migrate_plaintext = flags.migrate_plaintext;
+        }
+
+        if flags.archive_dir.is_some() {
+            debug!(
+                "Replacing config option \"archive_dir\":{:?} with flag \"--archive-dir\":{:?}",
+                self.archive_dir, flags.archive_dir
+            );
+            self.archive_dir = flags.archive_dir;
+        }
+
+        if flags.archive_after_secs.is_some() {
+            debug!(
+                "Replacing config option \"archive_after_secs\":{:?} with flag \"--archive-after-secs\":{:?}",
+                self.archive_after_secs, flags.archive_after_secs
+            );
+            self.archive_after_secs = flags.archive_after_secs;
+        }
+
+        if flags.snapshot_dir.is_some() {
+            debug!(
+                "Replacing config option \"snapshot_dir\":{:?} with flag \"--snapshot-dir\":{:?}",
+                self.snapshot_dir, flags.snapshot_dir
+            );
+            self.snapshot_dir = flags.snapshot_dir;
+        }
+
+        if flags.restore_from.is_some() {
+            debug!(
+                "Replacing config option \"restore_from\":{:?} with flag \"--restore-from\":{:?}",
+                self.restore_from, flags.restore_from
+            );
+            self.restore_from = flags.restore_from;
+        }
+
+        if flags.admin_token.is_some() {
+            debug!("Replacing config option \"admin_token\" with flag \"--admin-token\"");
+            self.admin_token = flags.admin_token;
+        }
+
+        if flags.max_frame_size.is_some() {
+            debug!(
+                "Replacing config option \"max_frame_size\":{:?} with flag \"--max-frame-size\":{:?}",
+                self.max_frame_size, flags.max_frame_size
+            );
+            self.max_frame_size = flags.max_frame_size;
+        }
+
+        if flags.storage.is_some() {
+            debug!(
+                "Replacing config option \"storage\":{:?} with flag \"--storage\":{:?}",
+                self.storage, flags.storage
+            );
+            self.storage = flags.storage;
+        }
+
         self.clone()
     }
 
     pub fn addr(&self) -> String {
         format!("0.0.0.0:{}", self.clone().port.expect("no port defined"))
     }
+
+    pub fn data_dir(&self) -> PathBuf {
+        self.data_dir
+            .clone()
+            .unwrap_or_else(|| DEFAULT_DATA_DIR.into())
+    }
+
+    /// Snapshots are kept on the same filesystem as the data directory by
+    /// default, so that Segments can be hard linked into them.
+    pub fn snapshot_dir(&self) -> PathBuf {
+        self.snapshot_dir
+            .clone()
+            .unwrap_or_else(|| self.data_dir().join(DEFAULT_SNAPSHOT_DIR))
+    }
+
+    pub fn segment_size(&self) -> u64 {
+        self.segment_size.unwrap_or(DEFAULT_SEGMENT_SIZE)
+    }
+
+    pub fn storage(&self) -> StorageBackend {
+        self.storage.unwrap_or_default()
+    }
+
+    pub fn max_frame_size(&self) -> usize {
+        self.max_frame_size.unwrap_or(DEFAULT_MAX_FRAME_SIZE) as usize
+    }
+
+    pub fn archive_after(&self) -> Duration {
+        Duration::from_secs(
+            self.archive_after_secs
+                .unwrap_or(DEFAULT_ARCHIVE_AFTER_SECS),
+        )
+    }
 }
 
 impl ::std::default::Default for RemitsConfig {
@@ -47,6 +230,17 @@ impl ::std::default::Default for RemitsConfig {
         Self {
             port: Some("4242".into()),
             log_level: Some("info".into()),
+            data_dir: Some(DEFAULT_DATA_DIR.into()),
+            segment_size: Some(DEFAULT_SEGMENT_SIZE),
+            encryption_key_path: None,
+            migrate_plaintext: Some(false),
+            archive_dir: None,
+            archive_after_secs: Some(DEFAULT_ARCHIVE_AFTER_SECS),
+            snapshot_dir: None,
+            restore_from: None,
+            admin_token: None,
+            max_frame_size: Some(DEFAULT_MAX_FRAME_SIZE),
+            storage: Some(StorageBackend::File),
         }
     }
 }
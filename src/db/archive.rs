@@ -0,0 +1,344 @@
+use super::crypto::Keyring;
+use super::segment::{self, Segment};
+use crate::commands::Compression;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const STUB_EXT: &str = "archived";
+const TMP_EXT: &str = "tmp";
+const CACHE_DIR: &str = "cache";
+
+/// Somewhere to keep sealed Segments that are too old to be worth keeping in
+/// the data directory. Files are stored by name, which may contain `/`.
+pub trait Archive: fmt::Debug + Send + Sync {
+    /// Stores a copy of the file at `path` as `name`, replacing anything
+    /// already stored as `name`. The copy must be durable once this returns.
+    fn put(&self, name: &str, path: &Path) -> io::Result<()>;
+
+    /// Copies the file stored as `name` to `dest`.
+    fn fetch(&self, name: &str, dest: &Path) -> io::Result<()>;
+
+    /// Removes the file stored as `name`, if there is one.
+    fn delete(&self, name: &str) -> io::Result<()>;
+}
+
+/// An Archive kept in a directory, usually on a slower or cheaper disk than
+/// the data directory.
+#[derive(Debug)]
+pub struct LocalDir {
+    root: PathBuf,
+}
+
+impl LocalDir {
+    pub fn new(root: PathBuf) -> Self {
+        LocalDir { root }
+    }
+}
+
+impl Archive for LocalDir {
+    fn put(&self, name: &str, path: &Path) -> io::Result<()> {
+        let dest = self.root.join(name);
+        let dir = dest.parent().unwrap_or(&self.root);
+        fs::create_dir_all(dir)?;
+        copy(path, &dest)?;
+        File::open(dir)?.sync_all()
+    }
+
+    fn fetch(&self, name: &str, dest: &Path) -> io::Result<()> {
+        copy(&self.root.join(name), dest)
+    }
+
+    fn delete(&self, name: &str) -> io::Result<()> {
+        match fs::remove_file(self.root.join(name)) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Copies `src` to `dest` through a temporary file, so `dest` only ever holds
+/// a complete copy.
+fn copy(src: &Path, dest: &Path) -> io::Result<()> {
+    let tmp = tmp_path(dest);
+    fs::copy(src, &tmp)?;
+    File::open(&tmp)?.sync_all()?;
+    fs::rename(&tmp, dest)
+}
+
+fn tmp_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".");
+    name.push(TMP_EXT);
+    path.with_file_name(name)
+}
+
+/// A sealed Segment that has been moved to the Archive. The Log keeps a small
+/// stub file in its place, recording what the Segment holds.
+///
+/// The first time an archived Segment is read, it is fetched back into the
+/// Log's cache directory and opened like any other sealed Segment. Fetching
+/// can be slow, so reads don't fetch Segments themselves. They mark the
+/// Segment as wanted instead, and it is fetched by a Fetch that runs without
+/// the DB locked. Fetched copies are evicted once they stop being read.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ArchivedSegment {
+    pub base_offset: usize,
+    /// The offset the next Segment starts at
+    pub end: usize,
+    pub compression: Compression,
+    /// Where the Segment is stored in the Archive
+    pub name: String,
+    pub size: u64,
+    /// When the Segment was last modified, in milliseconds since the Unix
+    /// epoch
+    pub modified: u64,
+    pub first_timestamp: Option<u64>,
+    pub last_timestamp: Option<u64>,
+    #[serde(skip)]
+    fetched: Arc<Cached>,
+    /// Whether a read has stopped at the Segment since it was last fetched
+    #[serde(skip)]
+    wanted: AtomicBool,
+}
+
+/// The fetched copy of an archived Segment, shared with any Fetch of it
+#[derive(Debug, Default)]
+struct Cached {
+    segment: OnceLock<Segment>,
+    /// Held while fetching, so the Segment is only fetched once at a time
+    fetching: Mutex<()>,
+    /// Whether the copy has been read since it was last checked for eviction
+    read: AtomicBool,
+}
+
+impl ArchivedSegment {
+    /// Describes `seg`, which has been stored in the Archive as `name`.
+    pub fn new(seg: &Segment, name: String) -> io::Result<Self> {
+        let modified = seg
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0);
+
+        Ok(ArchivedSegment {
+            base_offset: seg.base_offset,
+            end: seg.next_offset(),
+            compression: seg.compression(),
+            name,
+            size: seg.size(),
+            modified,
+            first_timestamp: seg.first_timestamp(),
+            last_timestamp: seg.last_timestamp()?,
+            fetched: Arc::default(),
+            wanted: AtomicBool::new(false),
+        })
+    }
+
+    /// Loads the stub at `path`.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        serde_cbor::from_slice(&fs::read(path)?)
+            .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))
+    }
+
+    /// Durably writes the stub for this Segment into the Log directory `dir`.
+    pub fn save(&self, dir: &Path) -> io::Result<()> {
+        let bytes = serde_cbor::to_vec(self).map_err(io::Error::other)?;
+        let path = stub_path(dir, self.base_offset);
+        let tmp = tmp_path(&path);
+
+        let mut file = File::create(&tmp)?;
+        file.write_all(&bytes)?;
+        file.sync_all()?;
+        fs::rename(&tmp, &path)?;
+        File::open(dir)?.sync_all()
+    }
+
+    /// When the Segment was last modified
+    pub fn modified(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(self.modified)
+    }
+
+    /// Returns the fetched copy of the Segment. If it hasn't been fetched yet,
+    /// the Segment is marked as wanted and this fails with `WouldBlock`, so
+    /// the read can be tried again once `wanted` has fetched it.
+    pub fn get(&self) -> io::Result<&Segment> {
+        match self.fetched.segment.get() {
+            Some(seg) => {
+                self.fetched.read.store(true, Ordering::Relaxed);
+                Ok(seg)
+            }
+            None => {
+                self.wanted.store(true, Ordering::Relaxed);
+                let msg = format!("archived segment {} has not been fetched", self.name);
+                Err(io::Error::new(ErrorKind::WouldBlock, msg))
+            }
+        }
+    }
+
+    /// Returns a Fetch of the Segment from `archive` into the cache of the Log
+    /// in `dir`, if a read has wanted it since the last time this was called.
+    pub fn wanted(
+        &self,
+        archive: &Arc<dyn Archive>,
+        dir: &Path,
+        keyring: &Arc<Keyring>,
+    ) -> Option<Fetch> {
+        if !self.wanted.swap(false, Ordering::Relaxed) {
+            return None;
+        }
+        Some(self.fetch_from(archive, dir, keyring))
+    }
+
+    fn fetch_from(&self, archive: &Arc<dyn Archive>, dir: &Path, keyring: &Arc<Keyring>) -> Fetch {
+        let file_name = self.name.rsplit('/').next().unwrap_or(&self.name);
+        Fetch {
+            archive: archive.clone(),
+            name: self.name.clone(),
+            path: dir.join(CACHE_DIR).join(file_name),
+            base_offset: self.base_offset,
+            end: self.end,
+            compression: self.compression,
+            keyring: keyring.clone(),
+            cached: self.fetched.clone(),
+        }
+    }
+
+    /// Replaces the Message at `offset` with a tombstone, both in the Archive
+    /// and in the stub. The fetched copy is redacted with `segment::redact`
+    /// and stored in the Archive again under the same name. Like reads, this
+    /// fails with `WouldBlock` if the Segment hasn't been fetched yet.
+    pub fn redact(
+        &mut self,
+        archive: &Arc<dyn Archive>,
+        dir: &Path,
+        keyring: &Arc<Keyring>,
+        offset: usize,
+    ) -> io::Result<()> {
+        let path = self.get()?.path().to_path_buf();
+        // The fetched copy is about to be replaced, so it is closed without
+        // being deleted.
+        self.fetched = Arc::default();
+
+        let redacted = segment::redact(&path, self.compression, keyring, offset)?;
+        let res = archive.put(&self.name, &redacted).and_then(|_| {
+            self.size = redacted.metadata()?.len();
+            self.save(dir)
+        });
+        segment::remove(&redacted)?;
+        res
+    }
+
+    /// Drops the fetched copy of the Segment if it hasn't been read since the
+    /// last time this was called. Returns whether a copy was dropped. Copies
+    /// are never dropped while they are being fetched.
+    pub fn evict(&mut self) -> io::Result<bool> {
+        if self.fetched.read.swap(false, Ordering::Relaxed) {
+            return Ok(false);
+        }
+        match Arc::get_mut(&mut self.fetched).and_then(|c| c.segment.take()) {
+            Some(seg) => seg.delete().map(|_| true),
+            None => Ok(false),
+        }
+    }
+
+    /// Removes the Segment from the Archive, along with its stub and any
+    /// fetched copy. A copy that is still being fetched is left in the cache
+    /// until it is next cleared.
+    pub fn delete(mut self, archive: &dyn Archive, dir: &Path) -> io::Result<()> {
+        if let Some(seg) = Arc::get_mut(&mut self.fetched).and_then(|c| c.segment.take()) {
+            seg.delete()?;
+        }
+        archive.delete(&self.name)?;
+        fs::remove_file(stub_path(dir, self.base_offset))
+    }
+}
+
+/// Fetches an archived Segment into the cache of its Log, so that reads of it
+/// can go ahead. Fetches are run without the DB locked.
+#[derive(Debug)]
+pub struct Fetch {
+    archive: Arc<dyn Archive>,
+    name: String,
+    path: PathBuf,
+    base_offset: usize,
+    end: usize,
+    compression: Compression,
+    keyring: Arc<Keyring>,
+    cached: Arc<Cached>,
+}
+
+impl Fetch {
+    /// Fetches and opens the Segment, unless it already has been.
+    pub fn run(self) -> io::Result<()> {
+        let _fetching = self.cached.fetching.lock().unwrap();
+        if self.cached.segment.get().is_some() {
+            return Ok(());
+        }
+
+        if let Some(cache) = self.path.parent() {
+            fs::create_dir_all(cache)?;
+        }
+        debug!("fetching archived segment {} to {:?}", self.name, self.path);
+        self.archive.fetch(&self.name, &self.path)?;
+
+        let (path, keyring) = (self.path, self.keyring);
+        let seg = match self.compression {
+            Compression::None => Segment::open_sealed(path, self.base_offset, self.end, keyring)?,
+            codec => {
+                Segment::open_compressed(path, self.base_offset, codec, Some(self.end), keyring)?
+            }
+        };
+        // Counts as a read, so the copy isn't evicted before the read that
+        // wanted it is tried again.
+        self.cached.read.store(true, Ordering::Relaxed);
+        let _ = self.cached.segment.set(seg);
+        Ok(())
+    }
+}
+
+fn stub_path(dir: &Path, base_offset: usize) -> PathBuf {
+    dir.join(format!("{:020}.{}", base_offset, STUB_EXT))
+}
+
+/// Whether `path` is the stub of an archived Segment
+pub fn is_stub(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == STUB_EXT)
+}
+
+/// Removes the cache of fetched Segments from the Log directory `dir`.
+pub fn clear_cache(dir: &Path) -> io::Result<()> {
+    match fs::remove_dir_all(dir.join(CACHE_DIR)) {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_local_dir_archive() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = LocalDir::new(dir.path().join("archive"));
+        let src = dir.path().join("src");
+        fs::write(&src, b"segment").unwrap();
+
+        archive.put("log/0.log", &src).unwrap();
+        let dest = dir.path().join("dest");
+        archive.fetch("log/0.log", &dest).unwrap();
+        assert_eq!(fs::read(&dest).unwrap(), b"segment");
+        assert!(!tmp_path(&dest).exists());
+
+        archive.delete("log/0.log").unwrap();
+        archive.delete("log/0.log").unwrap();
+        let err = archive.fetch("log/0.log", &dest).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+    }
+}
@@ -0,0 +1,200 @@
+use super::crypto::Keyring;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+pub const AUDIT_FILE: &str = "REDACTIONS";
+
+/// A record of a Message being redacted
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Redaction {
+    /// When the Message was redacted, in milliseconds since the Unix epoch
+    pub timestamp: u64,
+    pub log_name: String,
+    pub message_id: usize,
+    pub reason: String,
+    /// Snapshots that were taken before the redaction, and so still hold the
+    /// original Message
+    #[serde(default)]
+    pub snapshots: Vec<String>,
+}
+
+/// The audit trail of every redaction, kept in the data directory.
+///
+/// Each entry is appended as a 4 byte big endian length followed by the entry
+/// encoded as CBOR, encrypted like the Manifest. Entries are synced before
+/// they are acknowledged. A partially written entry at the end of the file,
+/// left by a crash, is truncated away when the audit trail is opened. While the
+/// Keyring is migrating, the whole file is encrypted again when it is opened.
+#[derive(Debug)]
+pub struct AuditLog {
+    path: PathBuf,
+    keyring: Arc<Keyring>,
+}
+
+impl AuditLog {
+    /// Opens the audit trail kept in `dir`. The file is only created once the
+    /// first entry is written.
+    pub fn open(dir: &Path, keyring: Arc<Keyring>) -> io::Result<Self> {
+        let audit = AuditLog {
+            path: dir.join(AUDIT_FILE),
+            keyring,
+        };
+
+        let data = audit.data()?;
+        let entries = frames(&data);
+        let len: usize = entries.iter().map(|e| 4 + e.len()).sum();
+        if len < data.len() {
+            warn!("truncating torn entry from {:?}", audit.path);
+            let file = OpenOptions::new().write(true).open(&audit.path)?;
+            file.set_len(len as u64)?;
+            file.sync_all()?;
+        }
+        if audit.keyring.is_migrating() && !entries.is_empty() {
+            audit.encrypt_all()?;
+        }
+        Ok(audit)
+    }
+
+    /// Writes every entry again with the Keyring's current key, replacing
+    /// the audit trail only once the new copy is durable.
+    fn encrypt_all(&self) -> io::Result<()> {
+        let tmp = self.path.with_extension("tmp");
+        let _ = fs::remove_file(&tmp);
+        let copy = AuditLog {
+            path: tmp.clone(),
+            keyring: self.keyring.clone(),
+        };
+        for entry in self.read()? {
+            copy.record(&entry)?;
+        }
+        fs::rename(&tmp, &self.path)?;
+        if let Some(dir) = self.path.parent() {
+            File::open(dir)?.sync_all()?;
+        }
+        Ok(())
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Durably appends `entry` to the audit trail.
+    pub fn record(&self, entry: &Redaction) -> io::Result<()> {
+        let bytes = serde_cbor::to_vec(entry).map_err(io::Error::other)?;
+        let bytes = self.keyring.encrypt_file(&bytes)?;
+        let mut buf = Vec::with_capacity(4 + bytes.len());
+        buf.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+        buf.extend_from_slice(&bytes);
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        file.write_all(&buf)?;
+        file.sync_all()?;
+        if let Some(dir) = self.path.parent() {
+            File::open(dir)?.sync_all()?;
+        }
+        Ok(())
+    }
+
+    /// Reads back every entry in the audit trail, oldest first.
+    pub fn read(&self) -> io::Result<Vec<Redaction>> {
+        let data = self.data()?;
+        let mut entries = vec![];
+        for frame in frames(&data) {
+            let bytes = self.keyring.decrypt_file(frame.to_vec())?;
+            let entry = serde_cbor::from_slice(&bytes)
+                .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+            entries.push(entry);
+        }
+        Ok(entries)
+    }
+
+    fn data(&self) -> io::Result<Vec<u8>> {
+        match fs::read(&self.path) {
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(vec![]),
+            res => res,
+        }
+    }
+}
+
+/// Splits the contents of the audit trail into its entries, stopping at the
+/// first one that is incomplete.
+fn frames(mut data: &[u8]) -> Vec<&[u8]> {
+    let mut frames = vec![];
+    while data.len() >= 4 {
+        let len = u32::from_be_bytes([data[0], data[1], data[2], data[3]]) as usize;
+        if data.len() - 4 < len {
+            break;
+        }
+        frames.push(&data[4..4 + len]);
+        data = &data[4 + len..];
+    }
+    frames
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_audit_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let keyring = Arc::new(Keyring::with_test_keys(&[1]));
+        let audit = AuditLog::open(dir.path(), keyring.clone()).unwrap();
+        assert!(audit.read().unwrap().is_empty());
+
+        let entry = |id| Redaction {
+            timestamp: 1,
+            log_name: "log".into(),
+            message_id: id,
+            reason: "erasure request".into(),
+            snapshots: vec![],
+        };
+        audit.record(&entry(3)).unwrap();
+        audit.record(&entry(5)).unwrap();
+        assert!(!fs::read(audit.path())
+            .unwrap()
+            .windows(7)
+            .any(|w| w == b"erasure"));
+
+        // A torn write at the end is dropped, so later entries can be read
+        let mut file = OpenOptions::new().append(true).open(audit.path()).unwrap();
+        file.write_all(&[0, 0, 1, 0, 1]).unwrap();
+        let reopened = AuditLog::open(dir.path(), keyring).unwrap();
+        reopened.record(&entry(8)).unwrap();
+        assert_eq!(reopened.read().unwrap(), vec![entry(3), entry(5), entry(8)]);
+    }
+
+    #[test]
+    fn test_audit_log_migration() {
+        let dir = tempfile::tempdir().unwrap();
+        let audit = AuditLog::open(dir.path(), Arc::default()).unwrap();
+        let entry = Redaction {
+            timestamp: 1,
+            log_name: "log".into(),
+            message_id: 3,
+            reason: "erasure request".into(),
+            snapshots: vec!["s1".into()],
+        };
+        audit.record(&entry).unwrap();
+
+        // Entries that aren't encrypted are refused once there are keys,
+        // unless they are being migrated
+        let keyring = Keyring::with_test_keys(&[1]);
+        let audit = AuditLog::open(dir.path(), Arc::new(keyring.clone())).unwrap();
+        let err = audit.read().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+        AuditLog::open(dir.path(), Arc::new(keyring.clone().migrating())).unwrap();
+        assert!(!fs::read(audit.path())
+            .unwrap()
+            .windows(7)
+            .any(|w| w == b"erasure"));
+        assert_eq!(audit.read().unwrap(), vec![entry]);
+    }
+}
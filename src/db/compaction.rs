@@ -0,0 +1,96 @@
+use super::crypto::Keyring;
+use super::segment::{self, Sealed};
+use std::collections::HashMap;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// Compacts a Log's sealed Segments, given in order. Every keyed Message that
+/// a later Message with the same key has replaced is dropped. Messages without
+/// a key are always kept.
+///
+/// Only Segments that have something to drop are rewritten, and each one keeps
+/// its codec. Returns each one along with the path of its rewritten copy, ready
+/// to be installed in the Log.
+pub fn compact(segments: &[Sealed], keyring: &Arc<Keyring>) -> io::Result<Vec<(Sealed, PathBuf)>> {
+    // The first pass finds the latest offset of each key, and which Segments
+    // hold Messages that have since been replaced.
+    let mut latest: HashMap<Vec<u8>, (usize, usize)> = HashMap::new();
+    let mut dirty = vec![false; segments.len()];
+    for (i, seg) in segments.iter().enumerate() {
+        for record in segment::read_file(&seg.path, keyring.clone())? {
+            let record = record?;
+            let key = match record.key {
+                Some(key) => key.into_owned(),
+                None => continue,
+            };
+            if let Some((_, prev)) = latest.insert(key, (record.offset, i)) {
+                dirty[prev] = true;
+            }
+        }
+    }
+
+    let mut rewritten = vec![];
+    for (i, seg) in segments.iter().enumerate() {
+        if !dirty[i] {
+            continue;
+        }
+
+        let path = &seg.path;
+        let codec = match segment::parse_path(path) {
+            Some((_, codec)) => codec,
+            None => continue,
+        };
+        let new_path = segment::rewrite(path, codec, keyring, |record| match &record.key {
+            Some(key) => latest.get(key.as_ref()).map(|(offset, _)| *offset) == Some(record.offset),
+            None => true,
+        })?;
+        debug!("compacted segment {:?}", path);
+        rewritten.push((seg.clone(), new_path));
+    }
+    Ok(rewritten)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::segment::Segment;
+
+    fn plain() -> Arc<Keyring> {
+        Arc::new(Keyring::default())
+    }
+
+    #[test]
+    fn test_compact() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut segments = vec![];
+        let mut seg = Segment::create(dir.path(), 0, plain()).unwrap();
+        seg.append(b"a", b"1", 1).unwrap();
+        seg.append(b"b", b"2", 1).unwrap();
+        seg.append(&[], b"3", 1).unwrap();
+        seg.seal().unwrap();
+        segments.push(seg.sealed());
+
+        let mut seg = Segment::create(dir.path(), 3, plain()).unwrap();
+        seg.append(b"a", b"4", 1).unwrap();
+        seg.append(b"c", b"5", 1).unwrap();
+        seg.seal().unwrap();
+        segments.push(seg.sealed());
+
+        // Only the first Segment has anything to drop
+        let rewritten = compact(&segments, &plain()).unwrap();
+        assert_eq!(rewritten.len(), 1);
+        let (source, path) = &rewritten[0];
+        assert_eq!(source, &segments[0]);
+        let path = segment::install(path, &source.path).unwrap();
+        assert_eq!(path, source.path);
+
+        let offsets: Vec<usize> = segment::read_file(&path, plain())
+            .unwrap()
+            .map(|r| r.unwrap().offset)
+            .collect();
+        assert_eq!(offsets, vec![1, 2]);
+
+        assert!(compact(&segments, &plain()).unwrap().is_empty());
+    }
+}
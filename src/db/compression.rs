@@ -0,0 +1,60 @@
+use crate::commands::Compression;
+use std::io;
+
+/// The file extension used for Segments compressed with `codec`. Uncompressed
+/// Segments use the plain "log" extension.
+pub fn extension(codec: Compression) -> &'static str {
+    match codec {
+        Compression::None => "log",
+        Compression::Lz4 => "lz4",
+        Compression::Zstd => "zst",
+    }
+}
+
+/// Reverses `extension`, returning None for anything that isn't a Segment.
+pub fn from_extension(ext: &str) -> Option<Compression> {
+    match ext {
+        "log" => Some(Compression::None),
+        "lz4" => Some(Compression::Lz4),
+        "zst" => Some(Compression::Zstd),
+        _ => None,
+    }
+}
+
+pub fn compress(codec: Compression, data: &[u8]) -> io::Result<Vec<u8>> {
+    match codec {
+        Compression::None => Ok(data.to_vec()),
+        Compression::Lz4 => Ok(lz4_flex::compress_prepend_size(data)),
+        Compression::Zstd => zstd::bulk::compress(data, zstd::DEFAULT_COMPRESSION_LEVEL),
+    }
+}
+
+pub fn decompress(codec: Compression, data: &[u8]) -> io::Result<Vec<u8>> {
+    match codec {
+        Compression::None => Ok(data.to_vec()),
+        Compression::Lz4 => lz4_flex::decompress_size_prepended(data)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+        Compression::Zstd => zstd::stream::decode_all(data),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compression_round_trip() {
+        let data: Vec<u8> = b"{\"metric\": 42}".repeat(100);
+        for &codec in [Compression::None, Compression::Lz4, Compression::Zstd].iter() {
+            let compressed = compress(codec, &data).unwrap();
+            assert_eq!(decompress(codec, &compressed).unwrap(), data);
+            assert_eq!(from_extension(extension(codec)), Some(codec));
+        }
+    }
+
+    #[test]
+    fn test_decompress_garbage() {
+        assert!(decompress(Compression::Lz4, &[0xff; 16]).is_err());
+        assert!(decompress(Compression::Zstd, &[0xff; 16]).is_err());
+    }
+}
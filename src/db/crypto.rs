@@ -0,0 +1,283 @@
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::path::Path;
+
+const KEY_SIZE: usize = 32;
+const NONCE_SIZE: usize = 24;
+const TAG_SIZE: usize = 16;
+/// Number of bytes encryption adds to the data it encrypts
+pub const OVERHEAD: usize = NONCE_SIZE + TAG_SIZE;
+
+/// Marks a whole file encrypted with `Keyring::encrypt_file`. A CBOR encoded
+/// Manifest can never start with these bytes.
+const FILE_MAGIC: &[u8] = b"RMXE";
+const FILE_HEADER_SIZE: usize = 6;
+
+/// The id of a key in a Keyring. Id 0 is never used for a key, and marks data
+/// that isn't encrypted.
+pub type KeyId = u16;
+pub const PLAINTEXT: KeyId = 0;
+
+/// The keys used to encrypt data at rest, by id.
+///
+/// Keys are loaded from a file holding one key per line, as its id followed by
+/// the key itself as 64 hex digits. Blank lines and lines starting with `#`
+/// are ignored. New data is always encrypted with the key with the highest id,
+/// so keys are rotated by adding a new key to the file. Older keys must be
+/// kept for as long as anything encrypted with them is still stored.
+///
+/// Data is encrypted with XChaCha20-Poly1305, using a random nonce that is
+/// stored along with it.
+///
+/// An empty Keyring leaves data unencrypted. Otherwise files that aren't
+/// encrypted are refused, unless the Keyring is migrating data written before
+/// encryption was turned on.
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct Keyring {
+    keys: BTreeMap<KeyId, [u8; KEY_SIZE]>,
+    migrating: bool,
+}
+
+impl Keyring {
+    /// Loads the keys in the file at `path`.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let contents = fs::read_to_string(path)?;
+        let mut keys = BTreeMap::new();
+        for (i, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let invalid = |reason: &str| {
+                let msg = format!("line {} of key file {:?}: {}", i + 1, path, reason);
+                io::Error::new(ErrorKind::InvalidData, msg)
+            };
+            let mut fields = line.split_whitespace();
+            let id: KeyId = match fields.next().map(str::parse) {
+                Some(Ok(id)) if id != PLAINTEXT => id,
+                _ => return Err(invalid("key id must be a number from 1 to 65535")),
+            };
+            let key = match fields.next().and_then(parse_key) {
+                Some(key) if fields.next().is_none() => key,
+                _ => return Err(invalid("key must be 64 hex digits")),
+            };
+            if keys.insert(id, key).is_some() {
+                return Err(invalid("key id is used more than once"));
+            }
+        }
+
+        Ok(Keyring {
+            keys,
+            migrating: false,
+        })
+    }
+
+    /// Lets the Keyring read files written before encryption was turned on,
+    /// so that they can be encrypted.
+    pub fn migrating(self) -> Self {
+        Keyring {
+            migrating: true,
+            ..self
+        }
+    }
+
+    /// Whether files that aren't encrypted are being encrypted, so should be
+    /// written again as soon as they are read.
+    pub fn is_migrating(&self) -> bool {
+        self.migrating && self.current() != PLAINTEXT
+    }
+
+    /// The id of the key new data is encrypted with, or `PLAINTEXT` if the
+    /// Keyring is empty.
+    pub fn current(&self) -> KeyId {
+        self.keys.keys().next_back().copied().unwrap_or(PLAINTEXT)
+    }
+
+    /// Encrypts `data` with key `id`. `aad` is authenticated along with it, so
+    /// decrypting fails unless the same `aad` is given.
+    pub fn encrypt(&self, id: KeyId, data: &[u8], aad: &[u8]) -> io::Result<Vec<u8>> {
+        let cipher = self.cipher(id)?;
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let sealed = cipher
+            .encrypt(&nonce, Payload { msg: data, aad })
+            .map_err(|_| io::Error::other("could not encrypt data"))?;
+
+        let mut buf = Vec::with_capacity(NONCE_SIZE + sealed.len());
+        buf.extend_from_slice(&nonce);
+        buf.extend_from_slice(&sealed);
+        Ok(buf)
+    }
+
+    /// Reverses `encrypt`.
+    pub fn decrypt(&self, id: KeyId, data: &[u8], aad: &[u8]) -> io::Result<Vec<u8>> {
+        let cipher = self.cipher(id)?;
+        if data.len() < OVERHEAD {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "encrypted data is truncated",
+            ));
+        }
+
+        let (nonce, sealed) = data.split_at(NONCE_SIZE);
+        cipher
+            .decrypt(XNonce::from_slice(nonce), Payload { msg: sealed, aad })
+            .map_err(|_| io::Error::new(ErrorKind::InvalidData, "could not decrypt data"))
+    }
+
+    /// Encrypts the contents of a whole file with the current key. If the
+    /// Keyring is empty the contents are returned as they are.
+    pub fn encrypt_file(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        let id = self.current();
+        if id == PLAINTEXT {
+            return Ok(data.to_vec());
+        }
+
+        let mut buf = FILE_MAGIC.to_vec();
+        buf.extend_from_slice(&id.to_be_bytes());
+        let encrypted = self.encrypt(id, data, &buf)?;
+        buf.extend_from_slice(&encrypted);
+        Ok(buf)
+    }
+
+    /// Reverses `encrypt_file`. Files that aren't encrypted are returned as
+    /// they are only if the Keyring is empty or migrating, so they can't be
+    /// swapped in for encrypted ones.
+    pub fn decrypt_file(&self, data: Vec<u8>) -> io::Result<Vec<u8>> {
+        if data.len() < FILE_HEADER_SIZE || !data.starts_with(FILE_MAGIC) {
+            if self.current() != PLAINTEXT && !self.migrating {
+                let msg = "file is not encrypted";
+                return Err(io::Error::new(ErrorKind::InvalidData, msg));
+            }
+            return Ok(data);
+        }
+
+        let (header, encrypted) = data.split_at(FILE_HEADER_SIZE);
+        let id = KeyId::from_be_bytes([header[4], header[5]]);
+        self.decrypt(id, encrypted, header)
+    }
+
+    fn cipher(&self, id: KeyId) -> io::Result<XChaCha20Poly1305> {
+        match self.keys.get(&id) {
+            Some(key) => Ok(XChaCha20Poly1305::new(key.into())),
+            None => {
+                let msg = format!("encryption key {} is not in the keyring", id);
+                Err(io::Error::new(ErrorKind::InvalidData, msg))
+            }
+        }
+    }
+}
+
+/// Only the ids of the keys are shown, so keys never end up in logs.
+impl fmt::Debug for Keyring {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_set().entries(self.keys.keys()).finish()
+    }
+}
+
+fn parse_key(hex: &str) -> Option<[u8; KEY_SIZE]> {
+    if hex.len() != KEY_SIZE * 2 {
+        return None;
+    }
+
+    let mut key = [0; KEY_SIZE];
+    for (i, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(hex.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(key)
+}
+
+#[cfg(test)]
+impl Keyring {
+    /// A Keyring holding made up keys with the given ids
+    pub fn with_test_keys(ids: &[KeyId]) -> Self {
+        let keys = ids.iter().map(|&id| (id, [id as u8; KEY_SIZE])).collect();
+        Keyring {
+            keys,
+            migrating: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keyring(ids: &[KeyId]) -> Keyring {
+        Keyring::with_test_keys(ids)
+    }
+
+    #[test]
+    fn test_keyring_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("keys");
+        let key = "01".repeat(KEY_SIZE);
+        fs::write(
+            &path,
+            format!("# old key\n1 {}\n\n3 {}\n", key, "03".repeat(32)),
+        )
+        .unwrap();
+        let loaded = Keyring::load(&path).unwrap();
+        assert_eq!(loaded, keyring(&[1, 3]));
+        assert_eq!(loaded.current(), 3);
+        assert_eq!(format!("{:?}", loaded), "{1, 3}");
+
+        for bad in ["0 00", "1 xyz", "x 00", "1 00 00"].iter() {
+            fs::write(&path, bad).unwrap();
+            assert!(Keyring::load(&path).is_err());
+        }
+        fs::write(&path, format!("1 {}\n1 {}", key, key)).unwrap();
+        assert!(Keyring::load(&path).is_err());
+    }
+
+    #[test]
+    fn test_keyring_encrypt() {
+        let keys = keyring(&[1, 2]);
+        let encrypted = keys.encrypt(1, b"secret", b"aad").unwrap();
+        assert_eq!(encrypted.len(), 6 + OVERHEAD);
+        assert_eq!(keys.decrypt(1, &encrypted, b"aad").unwrap(), b"secret");
+
+        // The key, the associated data and the data must all match
+        assert!(keys.decrypt(2, &encrypted, b"aad").is_err());
+        assert!(keys.decrypt(1, &encrypted, b"other").is_err());
+        let mut tampered = encrypted.clone();
+        tampered[NONCE_SIZE] ^= 1;
+        assert!(keys.decrypt(1, &tampered, b"aad").is_err());
+        assert!(keyring(&[2]).decrypt(1, &encrypted, b"aad").is_err());
+    }
+
+    #[test]
+    fn test_keyring_files() {
+        let keys = keyring(&[1]);
+        let encrypted = keys.encrypt_file(b"manifest").unwrap();
+        assert!(encrypted.starts_with(FILE_MAGIC));
+        assert_eq!(keys.decrypt_file(encrypted).unwrap(), b"manifest");
+
+        // Rotating keys leaves older files readable
+        let rotated = keyring(&[1, 2]);
+        let encrypted = keys.encrypt_file(b"manifest").unwrap();
+        assert_eq!(rotated.decrypt_file(encrypted).unwrap(), b"manifest");
+
+        let plain = Keyring::default();
+        assert_eq!(plain.encrypt_file(b"manifest").unwrap(), b"manifest");
+        assert_eq!(
+            plain.decrypt_file(b"manifest".to_vec()).unwrap(),
+            b"manifest"
+        );
+
+        // Files that aren't encrypted are only read while migrating them
+        let err = keys.decrypt_file(b"manifest".to_vec()).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        let migrating = keys.migrating();
+        assert!(migrating.is_migrating());
+        assert!(!plain.migrating().is_migrating());
+        assert_eq!(
+            migrating.decrypt_file(b"manifest".to_vec()).unwrap(),
+            b"manifest"
+        );
+    }
+}
@@ -0,0 +1,265 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, ErrorKind, Write};
+use std::path::PathBuf;
+
+/// Minimum number of Segment bytes between two entries in an OffsetIndex
+pub const INDEX_INTERVAL: u64 = 4096;
+const ENTRY_SIZE: usize = 20;
+
+/// Index files start with these bytes followed by the 4 byte big endian
+/// version of their format. Index files without them, or with another version,
+/// are rebuilt from their Segment.
+const FILE_MAGIC: &[u8] = b"RMIX";
+const VERSION: u32 = 1;
+const FILE_HEADER_SIZE: usize = 8;
+
+/// Maps the offset of a Message, relative to the start of its Segment, to the
+/// byte position its record starts at and the timestamp it was added at.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IndexEntry {
+    pub offset: u32,
+    pub position: u64,
+    pub timestamp: u64,
+}
+
+/// A sparse index over a Segment, holding an entry for the first Message and
+/// then roughly every `INDEX_INTERVAL` bytes. Readers use it to seek close to
+/// the Message they want instead of scanning the Segment from the start.
+/// Timestamps never go backwards within a Log, so the same entries can be
+/// searched by timestamp too.
+///
+/// The index is stored next to its Segment as a file of 20 byte entries after a
+/// short header naming its version. Since
+/// it can always be rebuilt from the Segment it is only synced when the
+/// Segment is sealed.
+#[derive(Debug)]
+pub struct OffsetIndex {
+    entries: Vec<IndexEntry>,
+    writer: Option<File>,
+}
+
+impl OffsetIndex {
+    /// Writes a new index file holding `entries`, replacing any existing one.
+    pub fn create(path: PathBuf, entries: Vec<IndexEntry>) -> io::Result<Self> {
+        let mut buf = Vec::with_capacity(FILE_HEADER_SIZE + entries.len() * ENTRY_SIZE);
+        buf.extend_from_slice(FILE_MAGIC);
+        buf.extend_from_slice(&VERSION.to_be_bytes());
+        for entry in entries.iter() {
+            buf.extend_from_slice(&encode(entry));
+        }
+
+        let mut writer = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&path)?;
+        writer.write_all(&buf)?;
+
+        Ok(OffsetIndex {
+            entries,
+            writer: Some(writer),
+        })
+    }
+
+    /// Creates an index that is only held in memory
+    pub fn from_entries(entries: Vec<IndexEntry>) -> Self {
+        OffsetIndex {
+            entries,
+            writer: None,
+        }
+    }
+
+    /// Loads the index file at `path` for a Segment of `size` bytes whose
+    /// Messages have relative offsets below `span`. Returns None if the file is
+    /// missing or doesn't match the Segment, in which case the index needs to
+    /// be rebuilt.
+    pub fn load(path: PathBuf, size: u64, span: usize) -> io::Result<Option<Self>> {
+        let bytes = match fs::read(&path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        if bytes.len() < FILE_HEADER_SIZE
+            || !bytes.starts_with(FILE_MAGIC)
+            || bytes[4..FILE_HEADER_SIZE] != VERSION.to_be_bytes()
+        {
+            return Ok(None);
+        }
+        let bytes = &bytes[FILE_HEADER_SIZE..];
+
+        // A trailing partial entry is left over from an interrupted write and
+        // can be ignored, since the index doesn't need to be complete.
+        let mut entries: Vec<IndexEntry> = Vec::with_capacity(bytes.len() / ENTRY_SIZE);
+        for chunk in bytes.chunks_exact(ENTRY_SIZE) {
+            let entry = decode(chunk);
+            let in_order = match entries.last() {
+                Some(last) => {
+                    last.offset < entry.offset
+                        && last.position < entry.position
+                        && last.timestamp <= entry.timestamp
+                }
+                None => entry.offset == 0 && entry.position == 0,
+            };
+            if !in_order || entry.position >= size || entry.offset as usize >= span {
+                return Ok(None);
+            }
+            entries.push(entry);
+        }
+
+        Ok(Some(OffsetIndex {
+            entries,
+            writer: None,
+        }))
+    }
+
+    pub fn append(&mut self, entry: IndexEntry) -> io::Result<()> {
+        if let Some(w) = self.writer.as_mut() {
+            w.write_all(&encode(&entry))?;
+        }
+        self.entries.push(entry);
+        Ok(())
+    }
+
+    /// Returns the closest entry at or before `offset`.
+    pub fn lookup(&self, offset: u32) -> IndexEntry {
+        let i = self.entries.partition_point(|e| e.offset <= offset);
+        self.entry_before(i)
+    }
+
+    /// Returns the closest entry before the first Message added at or after
+    /// `timestamp`.
+    pub fn lookup_time(&self, timestamp: u64) -> IndexEntry {
+        let i = self.entries.partition_point(|e| e.timestamp < timestamp);
+        self.entry_before(i)
+    }
+
+    fn entry_before(&self, i: usize) -> IndexEntry {
+        match i {
+            0 => IndexEntry {
+                offset: 0,
+                position: 0,
+                timestamp: 0,
+            },
+            _ => self.entries[i - 1],
+        }
+    }
+
+    pub fn first(&self) -> Option<&IndexEntry> {
+        self.entries.first()
+    }
+
+    pub fn last(&self) -> Option<&IndexEntry> {
+        self.entries.last()
+    }
+
+    pub fn needs_entry(&self, position: u64) -> bool {
+        needs_entry(self.entries.last(), position)
+    }
+
+    /// Syncs the index file and stops it from being written to.
+    pub fn seal(&mut self) -> io::Result<()> {
+        if let Some(w) = self.writer.take() {
+            w.sync_all()?;
+        }
+        Ok(())
+    }
+}
+
+/// Whether a record starting at `position` is far enough past `last`, the most
+/// recent entry, that it should be indexed too. The first record is always
+/// indexed.
+pub fn needs_entry(last: Option<&IndexEntry>, position: u64) -> bool {
+    match last {
+        Some(last) => position - last.position >= INDEX_INTERVAL,
+        None => true,
+    }
+}
+
+fn encode(entry: &IndexEntry) -> [u8; ENTRY_SIZE] {
+    let mut buf = [0; ENTRY_SIZE];
+    buf[..4].copy_from_slice(&entry.offset.to_be_bytes());
+    buf[4..12].copy_from_slice(&entry.position.to_be_bytes());
+    buf[12..].copy_from_slice(&entry.timestamp.to_be_bytes());
+    buf
+}
+
+fn decode(buf: &[u8]) -> IndexEntry {
+    let mut offset = [0; 4];
+    let mut position = [0; 8];
+    let mut timestamp = [0; 8];
+    offset.copy_from_slice(&buf[..4]);
+    position.copy_from_slice(&buf[4..12]);
+    timestamp.copy_from_slice(&buf[12..ENTRY_SIZE]);
+    IndexEntry {
+        offset: u32::from_be_bytes(offset),
+        position: u64::from_be_bytes(position),
+        timestamp: u64::from_be_bytes(timestamp),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(offset: u32, position: u64) -> IndexEntry {
+        IndexEntry {
+            offset,
+            position,
+            timestamp: position * 2,
+        }
+    }
+
+    #[test]
+    fn test_index_lookup() {
+        let dir = tempfile::tempdir().unwrap();
+        let entries = vec![entry(0, 0), entry(10, 5000), entry(20, 10000)];
+        let index = OffsetIndex::create(dir.path().join("0.index"), entries).unwrap();
+
+        assert_eq!(index.lookup(0), entry(0, 0));
+        assert_eq!(index.lookup(9), entry(0, 0));
+        assert_eq!(index.lookup(10), entry(10, 5000));
+        assert_eq!(index.lookup(19), entry(10, 5000));
+        assert_eq!(index.lookup(500), entry(20, 10000));
+    }
+
+    #[test]
+    fn test_index_lookup_time() {
+        let dir = tempfile::tempdir().unwrap();
+        let entries = vec![entry(0, 0), entry(10, 5000), entry(20, 10000)];
+        let index = OffsetIndex::create(dir.path().join("0.index"), entries).unwrap();
+
+        assert_eq!(index.lookup_time(0), entry(0, 0));
+        assert_eq!(index.lookup_time(10000), entry(0, 0));
+        assert_eq!(index.lookup_time(10001), entry(10, 5000));
+        assert_eq!(index.lookup_time(u64::MAX), entry(20, 10000));
+    }
+
+    #[test]
+    fn test_index_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("0.index");
+        assert!(OffsetIndex::load(path.clone(), 20000, 30)
+            .unwrap()
+            .is_none());
+
+        let mut index =
+            OffsetIndex::create(path.clone(), vec![entry(0, 0), entry(10, 5000)]).unwrap();
+        index.append(entry(20, 10000)).unwrap();
+        index.seal().unwrap();
+
+        // Trailing partial entries are ignored
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[0, 0, 0]).unwrap();
+
+        let loaded = OffsetIndex::load(path.clone(), 20000, 30).unwrap().unwrap();
+        assert_eq!(loaded.entries, index.entries);
+
+        // An index pointing past the end of its Segment is stale
+        assert!(OffsetIndex::load(path.clone(), 8000, 30).unwrap().is_none());
+
+        // So is one written before index files had a header
+        let bytes = fs::read(&path).unwrap();
+        fs::write(&path, &bytes[FILE_HEADER_SIZE..]).unwrap();
+        assert!(OffsetIndex::load(path, 20000, 30).unwrap().is_none());
+    }
+}
@@ -0,0 +1,474 @@
+use super::iters::{self, Cursor, Itr};
+use super::logs;
+use super::segment::Record;
+use super::store::{LogStore, MessageIter};
+use crate::commands::{IteratorKind, LogOptions, Position};
+use crate::errors::Error;
+use std::borrow::Cow;
+use std::io;
+
+/// How many Messages an Index runs its Iterator over before storing the
+/// results, so that a Message the Iterator fails on doesn't lose the work
+/// done before it. Backfilling a new Index also unlocks the DB after each
+/// batch.
+const BATCH_SIZE: usize = 1024;
+
+/// The stored results of an Indexed Iterator. Each result is kept as a Message
+/// in its own LogStore, keyed by the offset and key of the Message in the
+/// Iterator's Log it was returned for, so pages can be read back without
+/// running the Iterator again. A Filter Index stores the Messages it keeps.
+///
+/// Results are stored for Messages in order, and an Index only ever needs to
+/// catch up with Messages added to its Log since it last ran. Reduce
+/// Iterators start from their initial value on each page, so they are never
+/// indexed.
+///
+/// If the Iterator fails on a Message, the Index stops just before it and
+/// keeps failing with the same error rather than running the Iterator again,
+/// until it is rebuilt.
+#[derive(Debug)]
+pub struct Index {
+    results: Box<dyn LogStore>,
+    /// The offset in the Log of the next Message to run the Iterator over
+    next_offset: usize,
+    /// Results for Messages before this offset in the Log may have been
+    /// dropped by Retention
+    first_offset: usize,
+    /// The offset of the Message the Iterator failed on, and why
+    failed: Option<(usize, Error)>,
+}
+
+impl Index {
+    /// Picks up where the results already in `results` leave off.
+    pub fn open(results: Box<dyn LogStore>) -> io::Result<Self> {
+        let mut index = Index {
+            results,
+            next_offset: 0,
+            first_offset: 0,
+            failed: None,
+        };
+        let last = match index.results.len().checked_sub(1) {
+            Some(last) => index.results.messages(last)?.next().transpose()?,
+            None => None,
+        };
+        if let Some(record) = last {
+            index.next_offset = split_key(&record)?.0 + 1;
+        }
+        index.first_offset = index.first_result()?;
+        Ok(index)
+    }
+
+    /// The offset in the Log of the next Message to run the Iterator over.
+    /// An Index ahead of its Log has results for Messages that were lost by a
+    /// crash before they were synced.
+    pub fn next_offset(&self) -> usize {
+        self.next_offset
+    }
+
+    /// The offset of the Message the Iterator failed on, and why, if it has
+    pub fn failed(&self) -> Option<(usize, Error)> {
+        self.failed
+    }
+
+    /// Runs `itr` over the Messages added to `log` since the Index last
+    /// caught up, and stores the results.
+    pub fn catch_up(&mut self, itr: &Itr, log: &dyn LogStore) -> Result<(), Error> {
+        while !self.catch_up_batch(itr, log)? {}
+        Ok(())
+    }
+
+    /// Like `catch_up`, but only runs `itr` over up to `BATCH_SIZE` Messages.
+    /// Returns whether the Index has caught up with `log`.
+    pub fn catch_up_batch(&mut self, itr: &Itr, log: &dyn LogStore) -> Result<bool, Error> {
+        if let Some((_, e)) = self.failed {
+            return Err(e);
+        }
+        let offset = self.next_offset.max(log.first_offset());
+        if offset >= iters::high_water_mark(log) {
+            return Ok(true);
+        }
+        let initial = serde_cbor::to_vec(&itr.initial).expect("could not serialize initial value");
+        let run = itr.run(log, offset, BATCH_SIZE, &initial, true)?;
+        for output in run.results {
+            let key = index_key(output.offset, output.key.as_deref());
+            self.results.add_msg(Some(&key), output.value)?;
+        }
+        self.next_offset = run.cursor.next_offset;
+        if let Some((offset, e)) = run.failed {
+            error!(
+                "iterator {} failed on message {}, its index stops there: {:?}",
+                itr.name, offset, e
+            );
+            self.failed = run.failed;
+            return Err(e);
+        }
+        Ok(run.cursor.end_of_log)
+    }
+
+    /// The stored results, read as though they were a Log. `log` is what the
+    /// Index's Iterator reads from.
+    pub fn results<'a>(&'a self, log: &'a dyn LogStore) -> Results<'a> {
+        Results { index: self, log }
+    }
+
+    /// Reads a page of stored results, like `Itr::next` would return for the
+    /// Messages in `log`. The Index must have caught up with `log` first.
+    pub fn next(
+        &self,
+        itr: &Itr,
+        log: &dyn LogStore,
+        position: Position,
+        count: usize,
+    ) -> Result<(Cursor, Vec<Vec<u8>>), Error> {
+        let results = self.results(log);
+        let high_water_mark = results.len();
+        let read_error = |e: io::Error| {
+            error!("could not read index of iterator {}: {}", itr.name, e);
+            logs::read_error(&e)
+        };
+        let offset = iters::resolve(position, &results).map_err(read_error)?;
+        if offset < results.first_offset() {
+            return Err(Error::MsgExpired);
+        }
+        let count = count.min(high_water_mark.saturating_sub(offset));
+
+        let mut page = vec![];
+        let mut next_offset = offset;
+        for record in results.messages(offset).map_err(read_error)? {
+            if page.len() >= count {
+                break;
+            }
+            let record = record.map_err(read_error)?;
+            if record.is_redacted() {
+                continue;
+            }
+            next_offset = record.offset + 1;
+            page.push(match itr.kind {
+                IteratorKind::Filter => iters::filter_match(record.offset, &record.msg)?,
+                _ => record.msg.into_owned(),
+            });
+        }
+
+        // Like a page worked out from the Log, one that ran out of results
+        // before `count` has read up to the high water mark.
+        if page.len() < count {
+            next_offset = next_offset.max(high_water_mark);
+        }
+        let cursor = Cursor {
+            next_offset,
+            end_of_log: next_offset >= high_water_mark,
+            high_water_mark,
+        };
+        Ok((cursor, page))
+    }
+
+    /// Redacts the result stored for the Message at `offset` in the Log, if
+    /// there is one.
+    pub fn redact(&mut self, offset: usize) -> io::Result<()> {
+        let at = self.find(offset)?;
+        let found = match self.results.messages(at)?.next().transpose()? {
+            Some(record) if split_key(&record)?.0 == offset => Some(record.offset),
+            _ => None,
+        };
+        match found {
+            Some(at) => self.results.redact(at),
+            None => Ok(()),
+        }
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        match self.results.segmented_mut() {
+            Some(results) => results.flush().map(|_| ()),
+            None => Ok(()),
+        }
+    }
+
+    pub fn enforce_retention(&mut self) -> io::Result<usize> {
+        let dropped = self.results.enforce_retention()?;
+        if dropped > 0 {
+            self.first_offset = self.first_result()?;
+        }
+        Ok(dropped)
+    }
+
+    /// Removes the Index and every result stored in it.
+    pub fn destroy(self) -> io::Result<()> {
+        self.results.destroy()
+    }
+
+    /// The offset in the Log of the Message the first stored result was
+    /// returned for, if earlier results have been dropped. Messages between
+    /// the last dropped result and this one may have had results too, so
+    /// they count as dropped as well.
+    fn first_result(&self) -> io::Result<usize> {
+        let first = self.results.first_offset();
+        if first == 0 {
+            return Ok(0);
+        }
+        match self.results.messages(first)?.next().transpose()? {
+            Some(record) => Ok(split_key(&record)?.0),
+            None => Ok(self.next_offset),
+        }
+    }
+
+    /// Finds where the results for Messages at or after `offset` in the Log
+    /// start. Results are stored in the same order as the Messages they were
+    /// returned for, so they can be searched by the offsets in their keys.
+    fn find(&self, offset: usize) -> io::Result<usize> {
+        let (mut lo, mut hi) = (self.results.first_offset(), self.results.len());
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            match self.results.messages(mid)?.next().transpose()? {
+                Some(record) if split_key(&record)?.0 < offset => lo = mid + 1,
+                _ => hi = mid,
+            }
+        }
+        Ok(lo)
+    }
+}
+
+/// An Index's results, read as though they were a Log whose Messages have the
+/// offsets and keys of the Messages the results were returned for. This is
+/// what an Iterator whose source is the Index's Iterator reads, so Iterators
+/// can be built on top of each other. It can't be written to.
+#[derive(Debug)]
+pub struct Results<'a> {
+    index: &'a Index,
+    log: &'a dyn LogStore,
+}
+
+impl LogStore for Results<'_> {
+    fn options(&self) -> LogOptions {
+        self.log.options()
+    }
+
+    /// Results are dropped by the Retention of the Index as well as the Log,
+    /// so whichever has dropped more decides where they start.
+    fn first_offset(&self) -> usize {
+        self.log.first_offset().max(self.index.first_offset)
+    }
+
+    /// Every Message before this offset has had its results stored.
+    fn len(&self) -> usize {
+        self.index.next_offset.max(self.log.first_offset())
+    }
+
+    fn offset_for_time(&self, timestamp: u64) -> io::Result<usize> {
+        Ok(self.log.offset_for_time(timestamp)?.min(self.len()))
+    }
+
+    fn messages(&self, offset: usize) -> io::Result<MessageIter<'_>> {
+        let start = self.index.find(offset)?;
+        let records = self.index.results.messages(start)?.map(|record| {
+            let record = record?;
+            let (offset, key) = split_key(&record)?;
+            let key = key.map(|k| Cow::Owned(k.to_vec()));
+            Ok(Record {
+                offset,
+                timestamp: record.timestamp,
+                key,
+                msg: record.msg,
+            })
+        });
+        Ok(Box::new(records))
+    }
+
+    fn add_msg(&mut self, _key: Option<&[u8]>, _msg: Vec<u8>) -> Result<(), Error> {
+        Err(Error::ErrWritingLog)
+    }
+
+    fn redact(&mut self, _offset: usize) -> io::Result<()> {
+        let msg = "iterator results can't be redacted directly";
+        Err(io::Error::new(io::ErrorKind::Unsupported, msg))
+    }
+
+    fn enforce_retention(&mut self) -> io::Result<usize> {
+        Ok(0)
+    }
+
+    fn destroy(self: Box<Self>) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Results are keyed by the offset of the Message they were returned for,
+/// followed by the Message's own key if it has one.
+fn index_key(offset: usize, key: Option<&[u8]>) -> Vec<u8> {
+    let mut index_key = offset.to_string().into_bytes();
+    if let Some(key) = key {
+        index_key.push(b':');
+        index_key.extend_from_slice(key);
+    }
+    index_key
+}
+
+/// Splits a stored result's key back into the offset and key of the Message
+/// it was returned for.
+fn split_key<'a>(record: &'a Record) -> io::Result<(usize, Option<&'a [u8]>)> {
+    let index_key = record.key.as_deref().unwrap_or_default();
+    let (offset, key) = match index_key.iter().position(|&b| b == b':') {
+        Some(i) => (&index_key[..i], Some(&index_key[i + 1..])),
+        None => (index_key, None),
+    };
+    let offset = std::str::from_utf8(offset)
+        .ok()
+        .and_then(|o| o.parse().ok());
+    match offset {
+        Some(offset) => Ok((offset, key)),
+        None => {
+            let msg = format!("index result {} has no message offset", record.offset);
+            Err(io::Error::new(io::ErrorKind::InvalidData, msg))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::{LogOptions, Retention};
+    use crate::db::store::MemoryLog;
+
+    fn itr(kind: IteratorKind, func: &str) -> Itr {
+        Itr {
+            indexed: true,
+            ..Itr::new("test".into(), "i".into(), kind, func.into())
+        }
+    }
+
+    fn cbor(value: i32) -> Vec<u8> {
+        serde_cbor::to_vec(&value).unwrap()
+    }
+
+    #[test]
+    fn test_index_catch_up() {
+        let mut log = MemoryLog::new(LogOptions::default());
+        for i in 0..5u8 {
+            log.add_msg(None, vec![i]).unwrap();
+        }
+        let itr = itr(IteratorKind::Map, "return msg * 2");
+        let mut index = Index::open(Box::new(MemoryLog::default())).unwrap();
+        index.catch_up(&itr, &log).unwrap();
+        assert_eq!(index.next_offset(), 5);
+
+        log.add_msg(None, vec![5]).unwrap();
+        index.catch_up(&itr, &log).unwrap();
+        let (cursor, results) = index.next(&itr, &log, Position::Offset(4), 10).unwrap();
+        assert_eq!(results, vec![cbor(8), cbor(10)]);
+        assert_eq!(cursor.next_offset, 6);
+        assert!(cursor.end_of_log);
+
+        // Redacted results are skipped, like redacted Messages
+        index.redact(4).unwrap();
+        let (_, results) = index.next(&itr, &log, Position::Offset(3), 2).unwrap();
+        assert_eq!(results, vec![cbor(6), cbor(10)]);
+    }
+
+    #[test]
+    fn test_index_failed() {
+        let mut log = MemoryLog::new(LogOptions::default());
+        for i in 0..5u8 {
+            log.add_msg(None, vec![i]).unwrap();
+        }
+        let itr = itr(
+            IteratorKind::Map,
+            "if msg == 3 then error('bad') end return msg",
+        );
+        let mut index = Index::open(Box::new(MemoryLog::default())).unwrap();
+        let err = index.catch_up(&itr, &log).unwrap_err();
+        assert_eq!(err, Error::ErrRunningLua);
+        assert_eq!(index.failed(), Some((3, Error::ErrRunningLua)));
+
+        // The results before the Message are kept, and the Iterator isn't run
+        // again, even over Messages added since
+        assert_eq!(index.next_offset(), 3);
+        log.add_msg(None, vec![5]).unwrap();
+        assert_eq!(index.catch_up(&itr, &log), Err(Error::ErrRunningLua));
+        assert_eq!(index.next_offset(), 3);
+        let (_, results) = index.next(&itr, &log, Position::Offset(0), 10).unwrap();
+        assert_eq!(results, vec![cbor(0), cbor(1), cbor(2)]);
+    }
+
+    #[test]
+    fn test_index_reopen() {
+        let mut log = MemoryLog::new(LogOptions::default());
+        for i in 1..4u8 {
+            log.add_msg(None, vec![i]).unwrap();
+        }
+        let itr = itr(IteratorKind::Map, "return msg * msg");
+        let mut index = Index::open(Box::new(MemoryLog::default())).unwrap();
+        index.catch_up(&itr, &log).unwrap();
+
+        // Reopening picks up from the last stored result
+        let mut index = Index::open(index.results).unwrap();
+        assert_eq!(index.next_offset(), 3);
+        log.add_msg(None, vec![4]).unwrap();
+        index.catch_up(&itr, &log).unwrap();
+        let (_, results) = index.next(&itr, &log, Position::Offset(0), 10).unwrap();
+        assert_eq!(results, vec![cbor(1), cbor(4), cbor(9), cbor(16)]);
+    }
+
+    #[test]
+    fn test_index_retention() {
+        let mut log = MemoryLog::new(LogOptions::default());
+        for i in 0..6u8 {
+            log.add_msg(None, vec![i]).unwrap();
+        }
+        let odds = itr(IteratorKind::Filter, "return msg % 2 == 1");
+        let options = LogOptions {
+            retention: Retention {
+                max_messages: Some(2),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let mut index = Index::open(Box::new(MemoryLog::new(options))).unwrap();
+        index.catch_up(&odds, &log).unwrap();
+        assert_eq!(index.results(&log).first_offset(), 0);
+
+        // Once results are dropped, reading from before them fails instead
+        // of skipping ahead, even where the Log still has the Messages
+        assert_eq!(index.enforce_retention().unwrap(), 1);
+        assert_eq!(index.results(&log).first_offset(), 3);
+        let expired = index.next(&odds, &log, Position::Offset(0), 10);
+        assert_eq!(expired.unwrap_err(), Error::MsgExpired);
+        let (_, page) = index.next(&odds, &log, Position::Offset(3), 10).unwrap();
+        assert_eq!(page.len(), 2);
+
+        let index = Index::open(index.results).unwrap();
+        assert_eq!(index.results(&log).first_offset(), 3);
+    }
+
+    #[test]
+    fn test_index_results() {
+        let mut log = MemoryLog::new(LogOptions::default());
+        for i in 0..6u8 {
+            log.add_msg(Some(format!("k{}", i).as_bytes()), vec![i])
+                .unwrap();
+        }
+        let odds = itr(IteratorKind::Filter, "return msg % 2 == 1");
+        let mut index = Index::open(Box::new(MemoryLog::default())).unwrap();
+        index.catch_up(&odds, &log).unwrap();
+
+        // The results read back with the offsets and keys of their Messages
+        let results = index.results(&log);
+        assert_eq!(results.len(), 6);
+        let records: Vec<(usize, Vec<u8>, Vec<u8>)> = results
+            .messages(2)
+            .unwrap()
+            .map(|r| r.unwrap())
+            .map(|r| (r.offset, r.key.unwrap().into_owned(), r.msg.into_owned()))
+            .collect();
+        let expected = vec![(3, b"k3".to_vec(), vec![3]), (5, b"k5".to_vec(), vec![5])];
+        assert_eq!(records, expected);
+
+        // So another Iterator can run over them
+        let keys = itr(IteratorKind::Map, "return key");
+        let (cursor, page) = keys.next(&results, Position::Offset(0), 10, false).unwrap();
+        let page: Vec<String> = page
+            .iter()
+            .map(|r| serde_cbor::from_slice(r).unwrap())
+            .collect();
+        assert_eq!(page, vec!["k1", "k3", "k5"]);
+        assert_eq!(cursor.next_offset, 6);
+    }
+}
@@ -1,27 +1,254 @@
-use super::logs::Log;
-use crate::commands::IteratorKind;
+use super::logs;
+use super::store::LogStore;
+use crate::commands::{self, Anchor, FsyncPolicy, IteratorKind, Position};
 use crate::errors::Error;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::fmt;
+use std::io;
+use std::sync::Mutex;
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Itr {
     pub log: String,
     pub name: String,
     pub func: String,
     pub kind: IteratorKind,
+    /// The accumulator a Reduce Iterator starts each page with
+    #[serde(default)]
+    pub initial: Option<serde_cbor::Value>,
+    /// Whether the Iterator's results are stored as Messages are added, rather
+    /// than worked out for each page
+    #[serde(default)]
+    pub indexed: bool,
+    /// The Indexed Iterator whose results are read in place of the Log's
+    /// Messages, if there is one
+    #[serde(default)]
+    pub source: Option<String>,
+    #[serde(skip)]
+    pub(super) lua: LuaState,
+}
+
+/// A Lua state kept for an Iterator between calls, with the Iterator's
+/// function compiled in it once rather than for every Message. It is set up
+/// the first time the Iterator runs, and is never stored or compared.
+#[derive(Default)]
+pub struct LuaState(Mutex<Option<(rlua::Lua, rlua::RegistryKey)>>);
+
+impl PartialEq for LuaState {
+    fn eq(&self, _other: &Self) -> bool {
+        true
+    }
+}
+
+impl Eq for LuaState {}
+
+impl fmt::Debug for LuaState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("LuaState")
+    }
+}
+
+/// Describes where a page of Iterator results leaves off, so clients can
+/// request the next page.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Cursor {
+    /// The offset to request the next page from
+    pub next_offset: usize,
+    /// Whether the page reached the end of the Log
+    pub end_of_log: bool,
+    /// The offset the next Message added to the Log will be given, or for Logs
+    /// that sync every Message, the next one to become durable
+    pub high_water_mark: usize,
+}
+
+/// The results of running an Iterator over some of its Log
+#[derive(Debug)]
+pub struct Run {
+    pub cursor: Cursor,
+    pub results: Vec<Output>,
+    /// The offset of the Message the Iterator failed on, and why. The results
+    /// and cursor stop just before it.
+    pub failed: Option<(usize, Error)>,
+}
+
+/// A result of running an Iterator, along with the offset and key of the
+/// Message it was returned for. A Filter Iterator's result is the Message
+/// itself.
+#[derive(Debug)]
+pub struct Output {
+    pub offset: usize,
+    pub key: Option<Vec<u8>>,
+    pub value: Vec<u8>,
+}
+
+impl From<commands::IteratorAdd> for Itr {
+    fn from(add: commands::IteratorAdd) -> Self {
+        Itr {
+            initial: add.iterator_initial,
+            indexed: add.indexed,
+            source: add.iterator_source,
+            ..Itr::new(
+                add.log_name,
+                add.iterator_name,
+                add.iterator_kind,
+                add.iterator_func,
+            )
+        }
+    }
 }
 
 impl Itr {
-    pub fn next(&self, log: &Log, offset: usize, count: usize) -> Result<Vec<Vec<u8>>, Error> {
-        let mut output: Vec<Vec<u8>> = Vec::with_capacity(count);
+    /// An unindexed Iterator over the Messages in `log`
+    pub fn new(log: String, name: String, kind: IteratorKind, func: String) -> Self {
+        Itr {
+            log,
+            name,
+            func,
+            kind,
+            initial: None,
+            indexed: false,
+            source: None,
+            lua: LuaState::default(),
+        }
+    }
+
+    /// Runs the Iterator over `count` Messages starting at `position`. Pages
+    /// stop early at the end of the Log. The Message's key, if it has one, is
+    /// available to the Iterator as `key`. Redacted Messages are never passed
+    /// to the Iterator.
+    ///
+    /// A Map Iterator returns whatever its function returns for each Message.
+    /// A Filter Iterator returns the Messages its function keeps, along with
+    /// their offsets, and keeps reading until it has found `count` of them.
+    ///
+    /// A Reduce Iterator's function is given the value it returned for the
+    /// previous Message as `acc`, starting from the Iterator's initial value,
+    /// and the page holds the final value. If `intermediate` is set, the value
+    /// returned for every Message is included instead.
+    pub fn next(
+        &self,
+        log: &dyn LogStore,
+        position: Position,
+        count: usize,
+        intermediate: bool,
+    ) -> Result<(Cursor, Vec<Vec<u8>>), Error> {
+        let offset = match resolve(position, log) {
+            Ok(offset) => offset,
+            Err(e) => return Err(self.read_error(e)),
+        };
+        let initial = serde_cbor::to_vec(&self.initial).expect("could not serialize initial value");
+        let run = self.run(log, offset, count, &initial, intermediate)?;
+        if let Some((_, e)) = run.failed {
+            return Err(e);
+        }
+        let results = run
+            .results
+            .into_iter()
+            .map(|output| match self.kind {
+                IteratorKind::Filter => filter_match(output.offset, &output.value),
+                _ => Ok(output.value),
+            })
+            .collect::<Result<_, _>>()?;
+        Ok((run.cursor, results))
+    }
+
+    /// Runs the Iterator over `count` Messages starting at `offset`, like
+    /// `next`. A Reduce Iterator starts from `initial`, a CBOR encoded value,
+    /// rather than the Iterator's own initial value. `log` is either the
+    /// Iterator's Log or, if it has a source, the source's results.
+    ///
+    /// Errors reading `log` fail the whole run. If the Iterator itself fails,
+    /// the Run holds the results up to the Message it failed on instead.
+    pub fn run(
+        &self,
+        log: &dyn LogStore,
+        offset: usize,
+        count: usize,
+        initial: &[u8],
+        intermediate: bool,
+    ) -> Result<Run, Error> {
+        let high_water_mark = high_water_mark(log);
+        let count = count.min(high_water_mark.saturating_sub(offset));
+        let mut output: Vec<Output> = Vec::with_capacity(count);
         let mut error: Option<Error> = None;
+        let mut failed: Option<(usize, Error)> = None;
+        let mut next_offset = offset;
 
-        let lua = rlua::Lua::new();
+        if offset < log.first_offset() {
+            return Err(Error::MsgExpired);
+        }
+
+        let msgs = match log.messages(offset) {
+            Ok(msgs) => msgs,
+            Err(e) => return Err(self.read_error(e)),
+        };
+
+        // How many Messages have been read, and how many of them a Filter
+        // has kept. Pages hold `count` of the latter for Filters, and `count`
+        // of the former for everything else.
+        let mut read = 0;
+        let mut kept = 0;
+        let page_len = |read, kept| match self.kind {
+            IteratorKind::Filter => kept,
+            _ => read,
+        };
+
+        let mut state = self.lua.0.lock().unwrap();
+        if state.is_none() {
+            match self.compile() {
+                Ok(compiled) => *state = Some(compiled),
+                Err(e) => {
+                    let cursor = Cursor {
+                        next_offset: offset,
+                        end_of_log: offset >= high_water_mark,
+                        high_water_mark,
+                    };
+                    return Ok(Run {
+                        cursor,
+                        results: vec![],
+                        failed: Some((offset, e)),
+                    });
+                }
+            }
+        }
+        let (lua, func) = state.as_ref().expect("lua state was just set up");
         lua.context(|ctx| {
             let globals = ctx.globals();
-            for i in 0..count {
-                // TODO: this will panic if count is out of bounds.
-                // Implement `get` on Log and return None if nothing exists.
-                let msg = log[offset + i].clone();
+            let func: rlua::Function = ctx
+                .registry_value(func)
+                .expect("compiled function missing from lua registry");
+            // The initial value goes through CBOR like the Messages do, since
+            // rlua_serde can't take the i128s a serde_cbor::Value holds.
+            let mut deserializer = serde_cbor::Deserializer::from_slice(initial);
+            let serializer = rlua_serde::ser::Serializer { lua: ctx };
+            let mut acc = match serde_transcode::transcode(&mut deserializer, serializer) {
+                Ok(acc) => acc,
+                Err(e) => {
+                    debug!("error transcoding initial value to lua: {:?}", e);
+                    failed = Some((offset, Error::ErrReadingLuaResponse));
+                    return;
+                }
+            };
+
+            // Redacted Messages are skipped, and don't count towards the page.
+            let msgs = msgs
+                .take_while(|r| r.as_ref().map_or(true, |r| r.offset < high_water_mark))
+                .filter(|r| !r.as_ref().is_ok_and(|r| r.is_redacted()));
+            for record in msgs {
+                if page_len(read, kept) >= count {
+                    break;
+                }
+                let record = match record {
+                    Ok(record) => record,
+                    Err(e) => {
+                        error = Some(self.read_error(e));
+                        break;
+                    }
+                };
+                let msg = record.msg;
+                next_offset = record.offset + 1;
+                read += 1;
                 trace!("pulled msg from log: {:?}", msg);
 
                 let mut deserializer = serde_cbor::Deserializer::from_slice(&*msg);
@@ -30,34 +257,68 @@ impl Itr {
                     Ok(msg) => msg,
                     Err(e) => {
                         debug!("error transcoding msgpack to lua: {:?}", e);
-                        error = Some(Error::MsgNotValidCbor);
+                        failed = Some((record.offset, Error::MsgNotValidCbor));
                         break;
                     }
                 };
 
-                globals.set("msg", lua_msg);
-                let res = ctx.load(&*self.func).eval::<rlua::Value>();
+                let key = record.key.map(Cow::into_owned);
+                let lua_key = key
+                    .as_ref()
+                    .map(|k| String::from_utf8_lossy(k).into_owned());
+                let res = globals
+                    .set("key", lua_key)
+                    .and_then(|_| globals.set("msg", lua_msg))
+                    .and_then(|_| globals.set("acc", acc.clone()))
+                    .and_then(|_| func.call::<_, rlua::Value>(()));
                 if let Err(e) = res {
                     debug!("error running lua: {:?} {:?}", e, msg);
-                    error = Some(Error::ErrRunningLua);
+                    failed = Some((record.offset, Error::ErrRunningLua));
                     break;
                 };
-
-                let mut buf: Vec<u8> = vec![];
                 let value = res.expect("couldnt unwrap response from eval");
-                let deserializer = rlua_serde::de::Deserializer {
-                    value: value.clone(),
+
+                let result = match self.kind {
+                    IteratorKind::Map => to_cbor(value),
+                    IteratorKind::Filter => {
+                        // Like in Lua itself, anything but false and nil is true.
+                        if let rlua::Value::Nil | rlua::Value::Boolean(false) = value {
+                            continue;
+                        }
+                        kept += 1;
+                        Ok(msg.into_owned())
+                    }
+                    IteratorKind::Reduce => {
+                        acc = value;
+                        if !intermediate {
+                            continue;
+                        }
+                        to_cbor(acc.clone())
+                    }
                 };
-                let mut serializer = serde_cbor::Serializer::new(&mut buf);
-                match serde_transcode::transcode(deserializer, &mut serializer) {
-                    Ok(ok) => (),
+                match result {
+                    Ok(value) => output.push(Output {
+                        offset: record.offset,
+                        key,
+                        value,
+                    }),
                     Err(e) => {
-                        debug!("error transcoding lua to msgpack: {:?} {:?}", e, value);
-                        error = Some(Error::ErrReadingLuaResponse);
+                        failed = Some((record.offset, e));
+                        break;
                     }
-                };
+                }
+            }
 
-                output.push(buf);
+            let ok = error.is_none() && failed.is_none();
+            if self.kind == IteratorKind::Reduce && !intermediate && read > 0 && ok {
+                match to_cbor(acc) {
+                    Ok(value) => output.push(Output {
+                        offset: next_offset - 1,
+                        key: None,
+                        value,
+                    }),
+                    Err(e) => failed = Some((next_offset - 1, e)),
+                }
             }
         });
 
@@ -65,6 +326,287 @@ impl Itr {
             return Err(e);
         }
 
-        Ok(output)
+        // Compacted Logs have gaps in their offsets, so a page that ran out of
+        // Messages before `count` has read up to the high water mark. One
+        // that failed stops just before the Message it failed on.
+        match failed {
+            Some((offset, _)) => next_offset = offset,
+            None if page_len(read, kept) < count => next_offset = next_offset.max(high_water_mark),
+            None => (),
+        }
+        let cursor = Cursor {
+            next_offset,
+            end_of_log: next_offset >= high_water_mark,
+            high_water_mark,
+        };
+        Ok(Run {
+            cursor,
+            results: output,
+            failed,
+        })
+    }
+
+    /// Converts an error reading from the Iterator's Log into the Error sent to
+    /// clients. Reads that only stopped for an archived Segment to be fetched
+    /// are tried again once it has been, so they aren't logged as errors.
+    fn read_error(&self, e: io::Error) -> Error {
+        match e.kind() {
+            io::ErrorKind::WouldBlock => debug!("read from log {} stopped: {}", self.log, e),
+            _ => error!("could not read from log {}: {}", self.log, e),
+        }
+        logs::read_error(&e)
+    }
+
+    /// Sets up a Lua state with the Iterator's function compiled in it. The
+    /// function is kept in the state's registry, and reads the Message from
+    /// globals set before each call.
+    fn compile(&self) -> Result<(rlua::Lua, rlua::RegistryKey), Error> {
+        let lua = rlua::Lua::new();
+        let func = lua.context(|ctx| {
+            let func = ctx.load(&self.func).set_name(&self.name)?.into_function()?;
+            ctx.create_registry_value(func)
+        });
+        match func {
+            Ok(func) => Ok((lua, func)),
+            Err(e) => {
+                debug!("error compiling lua for iterator {}: {:?}", self.name, e);
+                Err(Error::ErrRunningLua)
+            }
+        }
+    }
+}
+
+/// A Message kept by a Filter Iterator, as returned to clients
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct FilterMatch {
+    pub offset: usize,
+    /// The Message exactly as it was added
+    pub message: serde_cbor::Value,
+}
+
+pub fn filter_match(offset: usize, msg: &[u8]) -> Result<Vec<u8>, Error> {
+    let encode = || {
+        serde_cbor::to_vec(&FilterMatch {
+            offset,
+            message: serde_cbor::from_slice(msg)?,
+        })
+    };
+    encode().map_err(|e| {
+        debug!("error encoding filtered msg: {:?}", e);
+        Error::MsgNotValidCbor
+    })
+}
+
+/// Encodes a value returned by an Iterator's function as CBOR.
+fn to_cbor(value: rlua::Value) -> Result<Vec<u8>, Error> {
+    let mut buf: Vec<u8> = vec![];
+    let deserializer = rlua_serde::de::Deserializer {
+        value: value.clone(),
+    };
+    let mut serializer = serde_cbor::Serializer::new(&mut buf);
+    match serde_transcode::transcode(deserializer, &mut serializer) {
+        Ok(()) => Ok(buf),
+        Err(e) => {
+            debug!("error transcoding lua to msgpack: {:?} {:?}", e, value);
+            Err(Error::ErrReadingLuaResponse)
+        }
+    }
+}
+
+/// The offset just past the last Message of `log` that Iterators may read.
+/// Logs that sync every Message only show Messages once they are durable, so
+/// nothing read from them can be lost in a crash.
+pub fn high_water_mark(log: &dyn LogStore) -> usize {
+    match log.options().fsync {
+        FsyncPolicy::Always => log.segmented().map_or(log.len(), |s| s.synced_len()),
+        _ => log.len(),
+    }
+}
+
+/// Turns a Position into an offset in `log`. Positions counting back from the
+/// end of the Log, or from a timestamp, never go further back than its oldest
+/// Message.
+pub fn resolve(position: Position, log: &dyn LogStore) -> io::Result<usize> {
+    let offset = match position {
+        Position::Offset(n) if n >= 0 => n as usize,
+        Position::Offset(n) => high_water_mark(log)
+            .saturating_sub(n.unsigned_abs() as usize)
+            .max(log.first_offset()),
+        Position::Anchor(Anchor::Start) => log.first_offset(),
+        Position::Anchor(Anchor::End) => high_water_mark(log),
+        Position::Timestamp(ts) => log.offset_for_time(ts)?,
+    };
+    Ok(offset)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::LogOptions;
+    use crate::db::crypto::Keyring;
+    use crate::db::store::MemoryLog;
+    use std::sync::Arc;
+    use std::time::Instant;
+
+    fn test_log(len: u32) -> MemoryLog {
+        let mut log = MemoryLog::new(LogOptions::default());
+        for i in 0..len {
+            let msg = serde_cbor::to_vec(&("payment", i, i % 7 == 0)).unwrap();
+            log.add_msg(None, msg).unwrap();
+        }
+        log
+    }
+
+    #[test]
+    fn test_itr_reuses_lua_state() {
+        let log = test_log(10);
+        let itr = Itr::new(
+            "test".into(),
+            "i".into(),
+            "map".into(),
+            "return msg[2] * 2".into(),
+        );
+        let first = itr.run(&log, 0, 5, &[0xf6], false).unwrap();
+        assert!(itr.lua.0.lock().unwrap().is_some());
+        let second = itr.run(&log, 5, 5, &[0xf6], false).unwrap();
+        let values: Vec<u32> = first
+            .results
+            .iter()
+            .chain(second.results.iter())
+            .map(|out| serde_cbor::from_slice(&out.value).unwrap())
+            .collect();
+        assert_eq!(values, (0..10).map(|i| i * 2).collect::<Vec<_>>());
+
+        // Functions that don't compile fail every page, without a state
+        let bad = Itr::new("test".into(), "bad".into(), "map".into(), "return (".into());
+        let res = bad.run(&log, 0, 5, &[0xf6], false).unwrap();
+        assert_eq!(res.failed, Some((0, Error::ErrRunningLua)));
+        assert!(bad.lua.0.lock().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_itr_stops_at_synced_len() {
+        let dir = tempfile::tempdir().unwrap();
+        let keyring = Arc::new(Keyring::default());
+        let mut log = logs::Log::open(
+            dir.path().join("log"),
+            1024,
+            LogOptions::default(),
+            keyring,
+            None,
+        )
+        .unwrap();
+        let msg = |i: u32| serde_cbor::to_vec(&("payment", i, true)).unwrap();
+        log.add_msg(None, msg(0)).unwrap();
+        log.add_msg_deferred(None, msg(1)).unwrap();
+        log.add_msg_deferred(None, msg(2)).unwrap();
+
+        // Messages waiting on a group commit aren't read yet, even by Filters
+        // that keep reading until their page is full
+        let itr = Itr::new(
+            "test".into(),
+            "i".into(),
+            "filter".into(),
+            "return msg[3]".into(),
+        );
+        let (cursor, msgs) = itr.next(&log, Position::Offset(0), 10, false).unwrap();
+        assert_eq!(msgs.len(), 1);
+        assert_eq!((cursor.next_offset, cursor.high_water_mark), (1, 1));
+        assert!(cursor.end_of_log);
+        assert_eq!(resolve(Position::Anchor(Anchor::End), &log).unwrap(), 1);
+
+        let commit = log.begin_commit().unwrap().unwrap();
+        commit.sync().unwrap();
+        log.finish_commit(commit);
+        let (cursor, msgs) = itr.next(&log, Position::Offset(1), 10, false).unwrap();
+        assert_eq!(msgs.len(), 2);
+        assert_eq!(cursor.high_water_mark, 3);
+    }
+
+    /// Runs a page the way Iterators did before their Lua state was cached: a
+    /// new state for every page, with the function loaded again for every
+    /// Message. Only used to compare against in `bench_itr_next`. Returns the
+    /// offset to read the next page from.
+    fn next_uncached(itr: &Itr, log: &dyn LogStore, offset: usize, count: usize) -> usize {
+        let lua = rlua::Lua::new();
+        let mut next_offset = offset;
+        lua.context(|ctx| {
+            let globals = ctx.globals();
+            let mut acc = rlua::Value::Nil;
+            let mut page = vec![];
+            for record in log.messages(offset).unwrap() {
+                if page.len() >= count {
+                    break;
+                }
+                let record = record.unwrap();
+                next_offset = record.offset + 1;
+                let mut deserializer = serde_cbor::Deserializer::from_slice(&record.msg);
+                let serializer = rlua_serde::ser::Serializer { lua: ctx };
+                let msg = serde_transcode::transcode(&mut deserializer, serializer).unwrap();
+                globals.set("msg", msg).unwrap();
+                globals.set("acc", acc.clone()).unwrap();
+                let value = ctx.load(&itr.func).eval::<rlua::Value>().unwrap();
+                match itr.kind {
+                    IteratorKind::Map => page.push(to_cbor(value).unwrap()),
+                    IteratorKind::Filter => {
+                        if let rlua::Value::Nil | rlua::Value::Boolean(false) = value {
+                            continue;
+                        }
+                        page.push(record.msg.into_owned());
+                    }
+                    IteratorKind::Reduce => {
+                        acc = value;
+                        page.push(vec![]);
+                    }
+                }
+            }
+            if itr.kind == IteratorKind::Reduce {
+                to_cbor(acc).unwrap();
+            }
+        });
+        next_offset.max(offset + 1)
+    }
+
+    /// Reads every Message in a 1M Message Log through each kind of Iterator,
+    /// a page at a time, with the cached Lua state and without it. Run with
+    /// `cargo test --release --bins bench_itr_next -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn bench_itr_next() {
+        let log = test_log(1_000_000);
+        let itrs = [
+            ("map", "return msg[2] * 2"),
+            ("filter", "return msg[3]"),
+            ("reduce", "return (acc or 0) + msg[2]"),
+        ];
+        let time = |next: &dyn Fn(usize) -> usize| {
+            let start = Instant::now();
+            let mut offset = 0;
+            while offset < log.len() {
+                offset = next(offset);
+            }
+            start.elapsed().as_secs_f64()
+        };
+        for &(kind, func) in itrs.iter() {
+            let itr = Itr::new("bench".into(), kind.into(), kind.into(), func.into());
+            for &page in [100, 10_000].iter() {
+                let cached = time(&|offset| {
+                    let position = Position::Offset(offset as i64);
+                    itr.next(&log, position, page, false).unwrap().0.next_offset
+                });
+                let uncached = time(&|offset| next_uncached(&itr, &log, offset, page));
+                println!(
+                    "{:>6} pages of {:>5}: cached {:>6.2}s ({:>9.0} msgs/s), \
+                     uncached {:>6.2}s ({:>9.0} msgs/s), {:>5.1}x",
+                    kind,
+                    page,
+                    cached,
+                    log.len() as f64 / cached,
+                    uncached,
+                    log.len() as f64 / uncached,
+                    uncached / cached
+                );
+            }
+        }
     }
 }
@@ -1,54 +1,1628 @@
+use super::archive::{self, Archive, ArchivedSegment, Fetch};
+use super::crypto::Keyring;
+use super::segment::{self, Record, Sealed, Segment, SegmentReader};
+use super::snapshot::Snapshot;
+use super::store::{LogStore, MessageIter, Segmented};
+use crate::commands::{Compression, FsyncPolicy, LogOptions};
 use crate::errors::Error;
 use serde_cbor::{Error as CborError, Value as CborValue};
-use std::ops::Index;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-#[derive(Debug, PartialEq, Eq)]
+/// A Log is stored as a directory of Segment files. Messages are appended to
+/// the last Segment until it grows past `max_segment_size`, at which point it
+/// is sealed and a new Segment is started.
+///
+/// Appends are flushed to disk according to the Log's FsyncPolicy. Sealed
+/// Segments are always fully synced. A Log that syncs every Message can leave
+/// it to a group commit instead, which syncs every Message appended since the
+/// last one at once.
+///
+/// Every Message is given a timestamp when it is appended. Timestamps never go
+/// backwards within a Log, even if the system clock does.
+///
+/// Sealed Segments are dropped from the front of the Log once they fall
+/// outside its Retention limits, so a Log may not start at offset 0.
+///
+/// If the Log has Compression set, sealed Segments are compressed in the
+/// background. The compressed copy replaces the original only once it has been
+/// fully written.
+///
+/// Messages may be added with a key. If the Log is compacted, sealed Segments
+/// are rewritten in the background without the keyed Messages that a later
+/// Message with the same key has replaced. Offsets are never reused, so a
+/// compacted Log has gaps in its offsets.
+///
+/// If an Archive is given, sealed Segments that have gone cold are moved to it
+/// in the background. They are fetched back the first time they are read.
+#[derive(Debug)]
 pub struct Log {
-    data: Vec<Vec<u8>>,
+    /// Tells the Log apart from a Log opened earlier with the same name
+    id: u64,
+    dir: PathBuf,
+    max_segment_size: u64,
+    segments: Vec<Segment>,
+    options: LogOptions,
+    unsynced: u64,
+    /// Every Message before this offset is durable
+    synced_len: usize,
+    last_sync: Instant,
+    last_timestamp: u64,
+    /// Sealed Segments starting before this offset have already been compacted
+    compacted_through: usize,
+    keyring: Arc<Keyring>,
+    /// Sealed Segments moved to the Archive, which all come before `segments`
+    archived: Vec<ArchivedSegment>,
+    archive: Option<Arc<dyn Archive>>,
+}
+
+static NEXT_LOG_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Messages appended to a Log's active Segment that are waiting on a group
+/// commit. The commit is synced without holding onto the Log, so Messages can
+/// keep being appended in the meantime.
+#[derive(Debug)]
+pub struct Commit {
+    log_id: u64,
+    file: File,
+    /// The Log's length when the commit was started
+    len: usize,
+}
+
+impl Commit {
+    pub fn sync(&self) -> io::Result<()> {
+        self.file.sync_data()
+    }
 }
 
 impl Log {
-    pub fn new() -> Self {
-        Log { data: vec![] }
+    /// Opens the Log stored in `dir`, creating it if it doesn't exist yet.
+    /// Segments are encrypted with `keyring`, and cold ones are moved to
+    /// `archive` if there is one.
+    pub fn open(
+        dir: PathBuf,
+        max_segment_size: u64,
+        options: LogOptions,
+        keyring: Arc<Keyring>,
+        archive: Option<Arc<dyn Archive>>,
+    ) -> io::Result<Self> {
+        fs::create_dir_all(&dir)?;
+        archive::clear_cache(&dir)?;
+
+        // A compressed copy of a Segment is only ever moved into place once it
+        // is complete, so it wins over an uncompressed original that was left
+        // behind by a crash.
+        let mut paths: BTreeMap<usize, (PathBuf, Compression)> = BTreeMap::new();
+        let mut archived: BTreeMap<usize, ArchivedSegment> = BTreeMap::new();
+        let mut stale = vec![];
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if segment::is_tmp(&path) {
+                stale.push(path);
+            } else if archive::is_stub(&path) {
+                let seg = ArchivedSegment::load(&path)?;
+                archived.insert(seg.base_offset, seg);
+            } else if let Some((base, codec)) = segment::parse_path(&path) {
+                match paths.get(&base) {
+                    Some((_, Compression::None)) | None => {
+                        if let Some((old, _)) = paths.insert(base, (path, codec)) {
+                            stale.push(old);
+                        }
+                    }
+                    Some(_) => stale.push(path),
+                }
+            }
+        }
+        for path in stale {
+            warn!("removing leftover segment file {:?}", path);
+            segment::remove(&path)?;
+        }
+
+        let mut kept = Vec::with_capacity(archived.len());
+        for seg in archived.into_values() {
+            let store = match &archive {
+                Some(store) => store,
+                None => {
+                    let msg = format!("log {:?} has archived segments but no archive", dir);
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+                }
+            };
+            // The stub is written before the local Segment is deleted, so a
+            // crash in between leaves both behind. The local copy is kept.
+            if paths.contains_key(&seg.base_offset) {
+                warn!(
+                    "removing leftover stub of segment {} in {:?}",
+                    seg.base_offset, dir
+                );
+                seg.delete(store.as_ref(), &dir)?;
+            } else {
+                kept.push(seg);
+            }
+        }
+        let archived = kept;
+
+        let mut segments = Vec::with_capacity(paths.len() + 1);
+        let mut paths = paths.into_iter().peekable();
+        while let Some((base, (path, codec))) = paths.next() {
+            let end = paths.peek().map(|(next_base, _)| *next_base);
+            let seg = match (end, codec) {
+                (_, Compression::Lz4) | (_, Compression::Zstd) => {
+                    Segment::open_compressed(path, base, codec, end, keyring.clone())?
+                }
+                (Some(end), Compression::None) => {
+                    Segment::open_sealed(path, base, end, keyring.clone())?
+                }
+                (None, Compression::None) => Segment::open_active(path, base, keyring.clone())?,
+            };
+            segments.push(seg);
+        }
+
+        // Only sealed Segments are compressed, so if the last one is, a crash
+        // happened before the next one could be created.
+        if let Some(last) = segments.last().filter(|s| s.is_sealed()) {
+            let base_offset = last.next_offset();
+            segments.push(Segment::create(&dir, base_offset, keyring.clone())?);
+        }
+        if segments.is_empty() {
+            let base_offset = archived.last().map(|s| s.end).unwrap_or(0);
+            segments.push(Segment::create(&dir, base_offset, keyring.clone())?);
+        }
+
+        let mut last_timestamp = None;
+        for seg in segments.iter().rev() {
+            last_timestamp = seg.last_timestamp()?;
+            if last_timestamp.is_some() {
+                break;
+            }
+        }
+        let last_timestamp = last_timestamp
+            .or_else(|| archived.iter().rev().find_map(|s| s.last_timestamp))
+            .unwrap_or(0);
+
+        let synced_len = segments.last().map(|s| s.next_offset()).unwrap_or(0);
+        Ok(Log {
+            id: NEXT_LOG_ID.fetch_add(1, Ordering::Relaxed),
+            dir,
+            max_segment_size,
+            segments,
+            options,
+            unsynced: 0,
+            synced_len,
+            last_sync: Instant::now(),
+            last_timestamp,
+            compacted_through: 0,
+            keyring,
+            archived,
+            archive,
+        })
+    }
+
+    /// Removes the Log and all of its Segments from disk, and from the Archive.
+    pub fn destroy(self) -> io::Result<()> {
+        if let Some(archive) = &self.archive {
+            for seg in self.archived {
+                seg.delete(archive.as_ref(), &self.dir)?;
+            }
+        }
+        fs::remove_dir_all(&self.dir)
+    }
+
+    /// Appends `msg` to the Log. An empty key is the same as no key.
+    pub fn add_msg(&mut self, key: Option<&[u8]>, msg: Vec<u8>) -> Result<(), Error> {
+        check_msg(&self.options, key, &msg)?;
+        self.write(key.unwrap_or(&[]), &msg, true)
+    }
+
+    /// Appends `msg` like `add_msg`, except that a Log which syncs every
+    /// Message leaves syncing it to a later group commit. Returns the offset
+    /// of the Message to wait on with `synced_len` in that case, or None if
+    /// the Message is already as durable as the Log's FsyncPolicy asks.
+    pub fn add_msg_deferred(
+        &mut self,
+        key: Option<&[u8]>,
+        msg: Vec<u8>,
+    ) -> Result<Option<usize>, Error> {
+        check_msg(&self.options, key, &msg)?;
+        let deferred = self.options.fsync == FsyncPolicy::Always;
+        self.write(key.unwrap_or(&[]), &msg, !deferred)?;
+        if !deferred {
+            return Ok(None);
+        }
+        Ok(Some(self.len() - 1))
+    }
+
+    fn write(&mut self, key: &[u8], msg: &[u8], sync: bool) -> Result<(), Error> {
+        if let Err(e) = self.append(key, msg, sync) {
+            error!("could not write to log {:?}: {}", self.dir, e);
+            return Err(Error::ErrWritingLog);
+        }
+        Ok(())
+    }
+
+    fn append(&mut self, key: &[u8], msg: &[u8], sync: bool) -> io::Result<()> {
+        let active = self.active();
+        let entry_size = active.entry_size(key, msg);
+        if active.size() > 0 && active.size() + entry_size > self.max_segment_size {
+            self.roll()?;
+        }
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0);
+        let timestamp = now.max(self.last_timestamp);
+        self.active().append(key, msg, timestamp)?;
+        self.last_timestamp = timestamp;
+        self.unsynced += 1;
+        if sync {
+            self.sync_if_due()?;
+        }
+        Ok(())
+    }
+
+    fn sync_if_due(&mut self) -> io::Result<()> {
+        let due = match self.options.fsync {
+            FsyncPolicy::Always => true,
+            FsyncPolicy::EveryMessages(n) => self.unsynced >= n,
+            FsyncPolicy::EveryMillis(ms) => self.last_sync.elapsed() >= Duration::from_millis(ms),
+        };
+
+        if due {
+            self.sync()?;
+        }
+        Ok(())
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        if self.unsynced > 0 {
+            self.active().sync()?;
+            self.unsynced = 0;
+        }
+        self.synced_len = self.len();
+        self.last_sync = Instant::now();
+        Ok(())
+    }
+
+    /// The offset just past the last Message known to be durable
+    pub fn synced_len(&self) -> usize {
+        self.synced_len
+    }
+
+    /// Starts a group commit of every Message appended but not yet synced,
+    /// or returns None if there aren't any.
+    pub fn begin_commit(&self) -> io::Result<Option<Commit>> {
+        let len = self.len();
+        if self.synced_len >= len {
+            return Ok(None);
+        }
+        let active = self.segments.last();
+        let file = active.map_or_else(
+            || Err(io::Error::other("log has no active segment")),
+            |s| s.try_clone_writer(),
+        )?;
+        Ok(Some(Commit {
+            log_id: self.id,
+            file,
+            len,
+        }))
+    }
+
+    /// Marks the Messages synced by `commit` as durable.
+    pub fn finish_commit(&mut self, commit: Commit) {
+        if commit.log_id != self.id {
+            return;
+        }
+        self.synced_len = self.synced_len.max(commit.len);
+        if self.synced_len == self.len() {
+            self.unsynced = 0;
+        }
+        self.last_sync = Instant::now();
+    }
+
+    /// Drops every Message appended since the Log was last synced, after a
+    /// group commit of them failed. The MessageAdds waiting on them are
+    /// failed, so they mustn't become durable with a later commit.
+    pub fn discard_unsynced(&mut self) -> io::Result<()> {
+        let synced_len = self.synced_len;
+        if synced_len >= self.len() {
+            return Ok(());
+        }
+        self.active().truncate(synced_len)?;
+        self.unsynced = 0;
+        warn!(
+            "discarded unsynced messages from log {:?} after offset {}",
+            self.dir, synced_len
+        );
+        Ok(())
+    }
+
+    /// Syncs Logs using `FsyncPolicy::EveryMillis` once their interval has
+    /// passed, so Messages aren't left unsynced when appends stop coming in.
+    /// Returns how long to wait before flushing again, or None if the Log's
+    /// policy is handled entirely on append.
+    pub fn flush(&mut self) -> io::Result<Option<Duration>> {
+        let interval = match self.options.fsync {
+            FsyncPolicy::EveryMillis(ms) => Duration::from_millis(ms),
+            _ => return Ok(None),
+        };
+
+        let elapsed = self.last_sync.elapsed();
+        if self.unsynced > 0 && elapsed >= interval {
+            self.sync()?;
+            return Ok(Some(interval));
+        }
+        Ok(Some(interval.checked_sub(elapsed).unwrap_or(interval)))
+    }
+
+    /// Seals the active Segment and starts a new one.
+    fn roll(&mut self) -> io::Result<()> {
+        self.sync()?;
+        let base_offset = self.len();
+        let seg = Segment::create(&self.dir, base_offset, self.keyring.clone())?;
+        self.active().seal()?;
+        self.segments.push(seg);
+        debug!("rolled log {:?} at offset {}", self.dir, base_offset);
+        Ok(())
+    }
+
+    fn active(&mut self) -> &mut Segment {
+        self.segments
+            .last_mut()
+            .expect("log should always have an active segment")
+    }
+
+    /// Drops sealed Segments whose Messages all fall outside the Log's
+    /// Retention limits, including archived ones. Returns the number of
+    /// Segments dropped.
+    pub fn enforce_retention(&mut self) -> io::Result<usize> {
+        let retention = self.options.retention;
+        let now = SystemTime::now();
+        let mut bytes: u64 = self.archived.iter().map(|s| s.size).sum::<u64>()
+            + self.segments.iter().map(|s| s.size()).sum::<u64>();
+        let len = self.len();
+
+        let mut dropped = 0;
+        // The active Segment is never dropped.
+        while self.segment_count() > 1 {
+            let (size, modified) = match self.archived.first() {
+                Some(seg) => (seg.size, seg.modified()),
+                None => (self.segments[0].size(), self.segments[0].modified()?),
+            };
+            let after_bytes = bytes - size;
+            let after_msgs = (len - self.base_offset(1)) as u64;
+            let age = now
+                .duration_since(modified)
+                .unwrap_or_else(|_| Duration::from_secs(0));
+
+            let expired = retention
+                .max_age_secs
+                .is_some_and(|max| age.as_secs() >= max)
+                || retention.max_bytes.is_some_and(|max| after_bytes >= max)
+                || retention.max_messages.is_some_and(|max| after_msgs >= max);
+            if !expired {
+                break;
+            }
+
+            bytes = after_bytes;
+            dropped += 1;
+            if !self.archived.is_empty() {
+                let seg = self.archived.remove(0);
+                debug!(
+                    "dropping archived segment {} of log {:?}",
+                    seg.base_offset, self.dir
+                );
+                seg.delete(self.archive()?.as_ref(), &self.dir)?;
+            } else {
+                let seg = self.segments.remove(0);
+                debug!("dropping segment {} of log {:?}", seg.base_offset, self.dir);
+                seg.delete()?;
+            }
+        }
+
+        Ok(dropped)
+    }
+
+    /// The sealed Segments that still need to be compressed
+    pub fn uncompressed_segments(&self) -> Vec<Sealed> {
+        if self.options.compression == Compression::None {
+            return vec![];
+        }
+
+        self.segments
+            .iter()
+            .filter(|s| s.is_sealed() && s.compression() == Compression::None)
+            .map(Segment::sealed)
+            .collect()
+    }
+
+    /// The sealed Segments to compact. Segments are only compacted again once
+    /// new ones have been sealed, since until then there is nothing new to
+    /// replace their Messages.
+    pub fn compaction_due(&self) -> Vec<Sealed> {
+        if !self.options.compacted {
+            return vec![];
+        }
+
+        let sealed: Vec<Sealed> = self
+            .segments
+            .iter()
+            .filter(|s| s.is_sealed())
+            .map(Segment::sealed)
+            .collect();
+        match sealed.last() {
+            Some(last) if last.base_offset >= self.compacted_through => sealed,
+            _ => vec![],
+        }
+    }
+
+    /// Records that every sealed Segment before `offset` has been compacted.
+    pub fn set_compacted_through(&mut self, offset: usize) {
+        self.compacted_through = self.compacted_through.max(offset);
+    }
+
+    /// Swaps the sealed Segment `source` for the copy at `path` made by
+    /// `segment::rewrite`. If the Segment has been dropped or replaced in the
+    /// meantime, even by a rewrite that kept its path such as a redaction, the
+    /// copy is removed instead.
+    ///
+    /// The copy is only moved into place here, so readers never see the new
+    /// file through the old Segment.
+    pub fn install_rewritten(&mut self, source: &Sealed, path: PathBuf) -> io::Result<()> {
+        let idx = self.segments.iter().position(|s| s.sealed() == *source);
+        let idx = match idx {
+            Some(idx) => idx,
+            None => return fs::remove_file(&path),
+        };
+
+        let (base_offset, end) = (source.base_offset, self.segments[idx].next_offset());
+        let path = segment::install(&path, &source.path)?;
+        let seg = match segment::parse_path(&path) {
+            Some((_, Compression::None)) => {
+                Segment::open_sealed(path, base_offset, end, self.keyring.clone())?
+            }
+            Some((_, codec)) => {
+                let keyring = self.keyring.clone();
+                Segment::open_compressed(path, base_offset, codec, Some(end), keyring)?
+            }
+            None => {
+                let msg = format!("{:?} is not a segment", path);
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+            }
+        };
+        let old = std::mem::replace(&mut self.segments[idx], seg);
+        debug!(
+            "rewrote segment {} of log {:?} from {} to {} bytes",
+            base_offset,
+            self.dir,
+            old.size(),
+            self.segments[idx].size()
+        );
+
+        // A copy with the same codec was moved over the original, so there
+        // is nothing left to delete.
+        if old.path() == self.segments[idx].path() {
+            return Ok(());
+        }
+        old.delete()
+    }
+
+    pub fn options(&self) -> LogOptions {
+        self.options
+    }
+
+    /// The offset of the oldest Message still held by the Log
+    pub fn first_offset(&self) -> usize {
+        match self.archived.first() {
+            Some(seg) => seg.base_offset,
+            None => self.segments.first().map(|s| s.base_offset).unwrap_or(0),
+        }
+    }
+
+    /// Number of Messages ever written to the Log. This is also the offset the
+    /// next Message will be written to.
+    pub fn len(&self) -> usize {
+        self.segments.last().map(|s| s.next_offset()).unwrap_or(0)
+    }
+
+    /// Finds the offset of the first Message added at or after `timestamp`, in
+    /// milliseconds since the Unix epoch. Returns the Log's length if every
+    /// Message is older.
+    pub fn offset_for_time(&self, timestamp: u64) -> io::Result<usize> {
+        // Every Message in a Segment is at most as new as the first Message in
+        // the next one, so the search can start at the last Segment that began
+        // before `timestamp`.
+        let before = |ts: Option<u64>| ts.is_some_and(|ts| ts < timestamp);
+        let idx = self
+            .partition_point(|_, first_timestamp| before(first_timestamp))
+            .saturating_sub(1);
+        for i in idx..self.segment_count() {
+            // Archived Segments that are entirely older aren't worth fetching
+            if self
+                .archived
+                .get(i)
+                .is_some_and(|s| s.last_timestamp.is_none_or(|ts| ts < timestamp))
+            {
+                continue;
+            }
+            if let Some(offset) = self.segment(i)?.offset_for_time(timestamp)? {
+                return Ok(offset);
+            }
+        }
+        Ok(self.len())
+    }
+
+    /// Returns an iterator over the Log's Messages, starting at the first one at
+    /// or after `offset`.
+    pub fn iter_from(&self, offset: usize) -> io::Result<Messages<'_>> {
+        let idx = self
+            .partition_point(|base_offset, _| base_offset <= offset)
+            .saturating_sub(1);
+        let reader = self.segment(idx)?.reader(offset)?;
+
+        Ok(Messages {
+            log: self,
+            next: idx + 1,
+            reader,
+        })
+    }
+
+    /// Number of Segments in the Log, counting archived ones
+    fn segment_count(&self) -> usize {
+        self.archived.len() + self.segments.len()
+    }
+
+    /// The base offset of Segment `i`, counting archived Segments first
+    fn base_offset(&self, i: usize) -> usize {
+        match self.archived.get(i) {
+            Some(seg) => seg.base_offset,
+            None => self.segments[i - self.archived.len()].base_offset,
+        }
+    }
+
+    /// Returns Segment `i`, counting archived Segments first. Archived
+    /// Segments that haven't been fetched fail with `WouldBlock`, see
+    /// `pending_fetches`.
+    fn segment(&self, i: usize) -> io::Result<&Segment> {
+        match self.archived.get(i) {
+            Some(seg) => seg.get(),
+            None => Ok(&self.segments[i - self.archived.len()]),
+        }
+    }
+
+    /// Like `slice::partition_point`, over every Segment given its base offset
+    /// and first timestamp.
+    fn partition_point(&self, pred: impl Fn(usize, Option<u64>) -> bool) -> usize {
+        let archived = self
+            .archived
+            .partition_point(|s| pred(s.base_offset, s.first_timestamp));
+        if archived < self.archived.len() {
+            return archived;
+        }
+        archived
+            + self
+                .segments
+                .partition_point(|s| pred(s.base_offset, s.first_timestamp()))
+    }
+
+    fn archive(&self) -> io::Result<&Arc<dyn Archive>> {
+        match &self.archive {
+            Some(archive) => Ok(archive),
+            None => Err(io::Error::other("log has no archive")),
+        }
+    }
+
+    /// Sealed Segments that haven't been modified for `after` and are ready to
+    /// be moved to the Archive, as their base offset, path and the name to
+    /// store them as. Segments are archived oldest first, and only once any
+    /// compression is done.
+    pub fn archive_due(&self, after: Duration) -> Vec<(Sealed, String)> {
+        if self.archive.is_none() {
+            return vec![];
+        }
+
+        let now = SystemTime::now();
+        let compressed = self.options.compression != Compression::None;
+        let log_name = self.dir.file_name().unwrap_or_default().to_string_lossy();
+        self.segments
+            .iter()
+            .take_while(|s| {
+                let cold = s
+                    .modified()
+                    .ok()
+                    .and_then(|m| now.duration_since(m).ok())
+                    .is_some_and(|age| age >= after);
+                s.is_sealed() && cold && (!compressed || s.compression() != Compression::None)
+            })
+            .map(|s| {
+                let file_name = s.path().file_name().unwrap_or_default().to_string_lossy();
+                let name = format!("{}/{}", log_name, file_name);
+                (s.sealed(), name)
+            })
+            .collect()
+    }
+
+    /// Replaces the sealed Segment `source` with the copy stored in the Archive
+    /// as `name`. If the Segment isn't the oldest local one any more, or has
+    /// been rewritten since it was copied, the copy is deleted instead.
+    pub fn install_archived(&mut self, source: &Sealed, name: String) -> io::Result<()> {
+        let current = self.segments.first().filter(|s| s.sealed() == *source);
+        let seg = match current {
+            Some(seg) => seg,
+            None => return self.archive()?.delete(&name),
+        };
+
+        let archived = ArchivedSegment::new(seg, name)?;
+        archived.save(&self.dir)?;
+        debug!(
+            "archived segment {} of log {:?}",
+            source.base_offset, self.dir
+        );
+        self.archived.push(archived);
+        self.segments.remove(0).delete()
+    }
+
+    /// Replaces the Message at `offset` with a tombstone that keeps its offset,
+    /// timestamp and key. The Segment holding it is rewritten, so the active
+    /// Segment is sealed first. Fails with `NotFound` if there is no Message at
+    /// `offset`, which may have been dropped by Retention or compaction, and
+    /// with `WouldBlock` if it is in an archived Segment that hasn't been
+    /// fetched, like reads.
+    pub fn redact(&mut self, offset: usize) -> io::Result<()> {
+        let idx = self
+            .partition_point(|base_offset, _| base_offset <= offset)
+            .saturating_sub(1);
+        let in_range = offset < self.len() && offset >= self.first_offset();
+        let exists = in_range
+            && match self.segment(idx)?.reader(offset)?.next() {
+                Some(record) => record?.offset == offset,
+                None => false,
+            };
+        if !exists {
+            let msg = format!("log {:?} has no message {}", self.dir, offset);
+            return Err(io::Error::new(io::ErrorKind::NotFound, msg));
+        }
+
+        if offset >= self.segments.last().map_or(0, |s| s.base_offset) {
+            self.roll()?;
+        }
+        if idx < self.archived.len() {
+            let archive = self.archive()?.clone();
+            let seg = &mut self.archived[idx];
+            return seg.redact(&archive, &self.dir, &self.keyring, offset);
+        }
+
+        let seg = &self.segments[idx - self.archived.len()];
+        let source = seg.sealed();
+        let redacted = segment::redact(&source.path, seg.compression(), &self.keyring, offset)?;
+        self.install_rewritten(&source, redacted)
     }
 
-    pub fn add_msg(&mut self, msg: Vec<u8>) -> Result<(), Error> {
-        let res: Result<CborValue, CborError> = serde_cbor::from_reader(&mut &*msg);
-        if let Err(e) = res {
-            return Err(Error::MsgNotValidCbor);
+    /// Adds the Log's Segments to `snap` under `dest`, as they are right now.
+    /// Archived Segments are fetched back into the Snapshot as ordinary sealed
+    /// Segments.
+    pub fn snapshot(&self, snap: &mut Snapshot, dest: &Path) -> io::Result<()> {
+        for seg in &self.archived {
+            let file_name = seg.name.rsplit('/').next().unwrap_or(&seg.name);
+            snap.fetch(
+                self.archive()?.clone(),
+                seg.name.clone(),
+                &dest.join(file_name),
+            )?;
+        }
+
+        for seg in &self.segments {
+            let file_name = match seg.path().file_name() {
+                Some(name) => dest.join(name),
+                None => continue,
+            };
+            if !seg.is_sealed() {
+                // The active Segment's index is rebuilt when it is opened.
+                snap.copy_tail(seg.path(), seg.file_len(), &file_name)?;
+                continue;
+            }
+
+            snap.link(seg.path(), &file_name)?;
+            if seg.compression() == Compression::None {
+                let index = segment::index_path(seg.path());
+                snap.link(&index, &segment::index_path(&file_name))?;
+            }
         }
-        self.data.push(msg);
         Ok(())
     }
+
+    /// Drops fetched copies of archived Segments that haven't been read since
+    /// the last time this was called. Returns the number dropped.
+    pub fn evict_fetched(&mut self) -> io::Result<usize> {
+        let mut evicted = 0;
+        for seg in self.archived.iter_mut() {
+            if seg.evict()? {
+                evicted += 1;
+            }
+        }
+        Ok(evicted)
+    }
+
+    /// Fetches of the archived Segments that reads have stopped at since the
+    /// last time this was called
+    pub fn pending_fetches(&self) -> Vec<Fetch> {
+        let archive = match &self.archive {
+            Some(archive) => archive,
+            None => return vec![],
+        };
+        self.archived
+            .iter()
+            .filter_map(|seg| seg.wanted(archive, &self.dir, &self.keyring))
+            .collect()
+    }
+}
+
+/// Iterates over Messages in a Log, moving through Segments as each one is
+/// exhausted.
+pub struct Messages<'a> {
+    log: &'a Log,
+    /// The Segment to read once `reader` is exhausted
+    next: usize,
+    reader: SegmentReader<'a>,
+}
+
+impl<'a> Iterator for Messages<'a> {
+    type Item = io::Result<Record<'a>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(msg) = self.reader.next() {
+                return Some(msg);
+            }
+
+            if self.next >= self.log.segment_count() {
+                return None;
+            }
+            let seg = match self.log.segment(self.next) {
+                Ok(seg) => seg,
+                Err(e) => return Some(Err(e)),
+            };
+            self.next += 1;
+            self.reader = match seg.reader(seg.base_offset) {
+                Ok(r) => r,
+                Err(e) => return Some(Err(e)),
+            };
+        }
+    }
+}
+
+impl LogStore for Log {
+    fn options(&self) -> LogOptions {
+        self.options()
+    }
+
+    fn first_offset(&self) -> usize {
+        self.first_offset()
+    }
+
+    fn len(&self) -> usize {
+        self.len()
+    }
+
+    fn offset_for_time(&self, timestamp: u64) -> io::Result<usize> {
+        self.offset_for_time(timestamp)
+    }
+
+    fn messages(&self, offset: usize) -> io::Result<MessageIter<'_>> {
+        Ok(Box::new(self.iter_from(offset)?))
+    }
+
+    fn add_msg(&mut self, key: Option<&[u8]>, msg: Vec<u8>) -> Result<(), Error> {
+        self.add_msg(key, msg)
+    }
+
+    fn redact(&mut self, offset: usize) -> io::Result<()> {
+        self.redact(offset)
+    }
+
+    fn enforce_retention(&mut self) -> io::Result<usize> {
+        self.enforce_retention()
+    }
+
+    fn destroy(self: Box<Self>) -> io::Result<()> {
+        (*self).destroy()
+    }
+
+    fn segmented(&self) -> Option<&dyn Segmented> {
+        Some(self)
+    }
+
+    fn segmented_mut(&mut self) -> Option<&mut dyn Segmented> {
+        Some(self)
+    }
+}
+
+impl Segmented for Log {
+    fn add_msg_deferred(
+        &mut self,
+        key: Option<&[u8]>,
+        msg: Vec<u8>,
+    ) -> Result<Option<usize>, Error> {
+        self.add_msg_deferred(key, msg)
+    }
+
+    fn synced_len(&self) -> usize {
+        self.synced_len()
+    }
+
+    fn begin_commit(&self) -> io::Result<Option<Commit>> {
+        self.begin_commit()
+    }
+
+    fn finish_commit(&mut self, commit: Commit) {
+        self.finish_commit(commit)
+    }
+
+    fn discard_unsynced(&mut self) -> io::Result<()> {
+        self.discard_unsynced()
+    }
+
+    fn flush(&mut self) -> io::Result<Option<Duration>> {
+        self.flush()
+    }
+
+    fn uncompressed_segments(&self) -> Vec<Sealed> {
+        self.uncompressed_segments()
+    }
+
+    fn compaction_due(&self) -> Vec<Sealed> {
+        self.compaction_due()
+    }
+
+    fn set_compacted_through(&mut self, offset: usize) {
+        self.set_compacted_through(offset)
+    }
+
+    fn install_rewritten(&mut self, source: &Sealed, path: PathBuf) -> io::Result<()> {
+        self.install_rewritten(source, path)
+    }
+
+    fn archive_due(&self, after: Duration) -> Vec<(Sealed, String)> {
+        self.archive_due(after)
+    }
+
+    fn install_archived(&mut self, source: &Sealed, name: String) -> io::Result<()> {
+        self.install_archived(source, name)
+    }
+
+    fn evict_fetched(&mut self) -> io::Result<usize> {
+        self.evict_fetched()
+    }
+
+    fn pending_fetches(&self) -> Vec<Fetch> {
+        self.pending_fetches()
+    }
+
+    fn snapshot(&self, snap: &mut Snapshot, dest: &Path) -> io::Result<()> {
+        self.snapshot(snap, dest)
+    }
+}
+
+/// Checks that `msg` and its key can be added to a Log with `options`.
+pub fn check_msg(options: &LogOptions, key: Option<&[u8]>, msg: &[u8]) -> Result<(), Error> {
+    if options
+        .max_message_size
+        .is_some_and(|max| msg.len() as u64 > max)
+    {
+        return Err(Error::MsgTooLarge);
+    }
+    let res: Result<CborValue, CborError> = serde_cbor::from_reader(msg);
+    if res.is_err() {
+        return Err(Error::MsgNotValidCbor);
+    }
+    if key.is_some_and(|k| k.len() > u16::MAX as usize) {
+        return Err(Error::MsgKeyTooLong);
+    }
+    Ok(())
+}
+
+/// Converts an error from reading a Log into the Error sent to clients.
+pub fn read_error(e: &io::Error) -> Error {
+    match e.kind() {
+        io::ErrorKind::InvalidData => Error::LogCorrupted,
+        _ => Error::ErrReadingLog,
+    }
 }
 
-impl Index<usize> for Log {
-    type Output = Vec<u8>;
+/// Log names can contain any utf8, so they are hex encoded before being used
+/// as a directory name.
+pub fn dir_name(name: &str) -> String {
+    name.bytes().map(|b| format!("{:02x}", b)).collect()
+}
 
-    fn index(&self, i: usize) -> &Vec<u8> {
-        &self.data[i]
+/// Reverses `dir_name`, returning None if `path` is not a Log directory.
+pub fn name_from_dir(path: &Path) -> Option<String> {
+    let hex = path.file_name()?.to_str()?;
+    if hex.len() % 2 != 0 {
+        return None;
     }
+
+    let bytes: Option<Vec<u8>> = (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect();
+    String::from_utf8(bytes?).ok()
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::commands::Retention;
+    use crate::db::compaction;
+
+    fn plain() -> Arc<Keyring> {
+        Arc::new(Keyring::default())
+    }
+
+    /// Reads every Record from `offset` on, fetching archived Segments the
+    /// read stops at like `exec_fetching` does.
+    fn read_records(log: &Log, offset: usize) -> Vec<Record<'static>> {
+        loop {
+            let res: io::Result<Vec<Record>> = log
+                .iter_from(offset)
+                .and_then(|msgs| msgs.map(|m| m.map(Record::into_owned)).collect());
+            match res {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    for fetch in log.pending_fetches() {
+                        fetch.run().unwrap();
+                    }
+                }
+                res => return res.expect("could not read messages"),
+            }
+        }
+    }
+
+    fn read_all(log: &Log, offset: usize) -> Vec<Vec<u8>> {
+        read_records(log, offset)
+            .into_iter()
+            .map(|m| m.msg.into_owned())
+            .collect()
+    }
 
     #[test]
     fn test_add_valid_cbor_msg() {
-        let mut log = Log::new();
+        let dir = tempfile::tempdir().unwrap();
+        let mut log = Log::open(
+            dir.path().join("log"),
+            1024,
+            LogOptions::default(),
+            plain(),
+            None,
+        )
+        .unwrap();
         let msg = vec![0x19, 0x03, 0xE8];
-        if let Err(e) = log.add_msg(msg) {
+        if let Err(e) = log.add_msg(None, msg) {
             panic!("threw error for valid messagepack: {:?}", e);
         };
     }
 
     #[test]
     fn test_add_invalid_cbor_msg() {
-        let mut log = Log::new();
+        let dir = tempfile::tempdir().unwrap();
+        let mut log = Log::open(
+            dir.path().join("log"),
+            1024,
+            LogOptions::default(),
+            plain(),
+            None,
+        )
+        .unwrap();
         let buf = vec![0x1a, 0x01, 0x02];
-        if let Ok(_) = log.add_msg(buf) {
+        if log.add_msg(None, buf).is_ok() {
             panic!("invalid messagepack was allowed into log");
         };
     }
+
+    #[test]
+    fn test_add_msg_key_too_long() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut log = Log::open(
+            dir.path().join("log"),
+            1024,
+            LogOptions::default(),
+            plain(),
+            None,
+        )
+        .unwrap();
+        let key = vec![b'k'; u16::MAX as usize + 1];
+        let res = log.add_msg(Some(&key), vec![0x01]);
+        assert_eq!(res, Err(Error::MsgKeyTooLong));
+        assert_eq!(log.len(), 0);
+    }
+
+    #[test]
+    fn test_add_msg_too_large() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = LogOptions {
+            max_message_size: Some(3),
+            ..Default::default()
+        };
+        let mut log = Log::open(dir.path().join("log"), 1024, options, plain(), None).unwrap();
+        assert_eq!(log.add_msg(None, vec![0x19, 0x03, 0x00]), Ok(()));
+        let res = log.add_msg(None, vec![0x1A, 0x00, 0x01, 0x00, 0x00]);
+        assert_eq!(res, Err(Error::MsgTooLarge));
+        assert_eq!(log.len(), 1);
+    }
+
+    #[test]
+    fn test_log_group_commit() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut log = Log::open(
+            dir.path().join("log"),
+            1024,
+            LogOptions::default(),
+            plain(),
+            None,
+        )
+        .unwrap();
+        assert!(log.begin_commit().unwrap().is_none());
+
+        for i in 0..3 {
+            let offset = log.add_msg_deferred(None, vec![0x19, 0x03, i]).unwrap();
+            assert_eq!(offset, Some(i as usize));
+        }
+        assert_eq!(log.synced_len(), 0);
+        let commit = log.begin_commit().unwrap().unwrap();
+
+        // Messages appended while a commit is running wait for the next one
+        log.add_msg_deferred(None, vec![0x19, 0x03, 3]).unwrap();
+        commit.sync().unwrap();
+        log.finish_commit(commit);
+        assert_eq!(log.synced_len(), 3);
+
+        // Messages from a failed commit are dropped rather than made durable
+        // by the next one
+        log.add_msg_deferred(None, vec![0x19, 0x03, 4]).unwrap();
+        log.discard_unsynced().unwrap();
+        assert_eq!((log.len(), log.synced_len()), (3, 3));
+        assert!(log.begin_commit().unwrap().is_none());
+        log.add_msg_deferred(None, vec![0x19, 0x03, 5]).unwrap();
+        assert_eq!(read_all(&log, 2)[1], vec![0x19, 0x03, 5]);
+
+        // A commit is only ever applied to the Log it was started on
+        let mut other = Log::open(
+            dir.path().join("other"),
+            1024,
+            LogOptions::default(),
+            plain(),
+            None,
+        )
+        .unwrap();
+        other.add_msg_deferred(None, vec![0x01]).unwrap();
+        other.finish_commit(log.begin_commit().unwrap().unwrap());
+        assert_eq!(other.synced_len(), 0);
+
+        // Other policies sync as usual
+        let options = LogOptions {
+            fsync: FsyncPolicy::EveryMessages(2),
+            ..Default::default()
+        };
+        let mut log = Log::open(dir.path().join("batched"), 1024, options, plain(), None).unwrap();
+        assert_eq!(log.add_msg_deferred(None, vec![0x01]), Ok(None));
+    }
+
+    #[test]
+    fn test_log_rolls_segments() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut log = Log::open(
+            dir.path().join("log"),
+            16,
+            LogOptions::default(),
+            plain(),
+            None,
+        )
+        .unwrap();
+        for i in 0..10u8 {
+            log.add_msg(None, vec![0x19, 0x03, i]).unwrap();
+        }
+
+        assert_eq!(log.len(), 10);
+        assert!(log.segments.len() > 1);
+        assert_eq!(read_all(&log, 0).len(), 10);
+        assert_eq!(
+            read_all(&log, 7),
+            vec![
+                vec![0x19, 0x03, 7],
+                vec![0x19, 0x03, 8],
+                vec![0x19, 0x03, 9]
+            ]
+        );
+        assert!(read_all(&log, 10).is_empty());
+    }
+
+    #[test]
+    fn test_log_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("log");
+        {
+            let mut log =
+                Log::open(path.clone(), 16, LogOptions::default(), plain(), None).unwrap();
+            for i in 0..10u8 {
+                log.add_msg(None, vec![0x19, 0x03, i]).unwrap();
+            }
+        }
+
+        let mut log = Log::open(path, 16, LogOptions::default(), plain(), None).unwrap();
+        assert_eq!(log.len(), 10);
+        log.add_msg(None, vec![0x19, 0x03, 10]).unwrap();
+        assert_eq!(
+            read_all(&log, 9),
+            vec![vec![0x19, 0x03, 9], vec![0x19, 0x03, 10]]
+        );
+    }
+
+    #[test]
+    fn test_log_fsync_every_messages() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = LogOptions {
+            fsync: FsyncPolicy::EveryMessages(3),
+            ..Default::default()
+        };
+        let mut log = Log::open(dir.path().join("log"), 1024, options, plain(), None).unwrap();
+
+        log.add_msg(None, vec![0x01]).unwrap();
+        log.add_msg(None, vec![0x02]).unwrap();
+        assert_eq!(log.unsynced, 2);
+        log.add_msg(None, vec![0x03]).unwrap();
+        assert_eq!(log.unsynced, 0);
+        assert_eq!(log.flush().unwrap(), None);
+    }
+
+    #[test]
+    fn test_log_fsync_every_millis() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = LogOptions {
+            fsync: FsyncPolicy::EveryMillis(60_000),
+            ..Default::default()
+        };
+        let mut log = Log::open(dir.path().join("log"), 1024, options, plain(), None).unwrap();
+
+        log.add_msg(None, vec![0x01]).unwrap();
+        assert_eq!(log.unsynced, 1);
+        let wait = log.flush().unwrap().expect("expected a flush interval");
+        assert!(wait <= Duration::from_millis(60_000));
+        assert_eq!(log.unsynced, 1);
+
+        log.last_sync -= Duration::from_millis(60_000);
+        log.flush().unwrap();
+        assert_eq!(log.unsynced, 0);
+    }
+
+    #[test]
+    fn test_log_timestamps() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("log");
+        let mut log = Log::open(path.clone(), 54, LogOptions::default(), plain(), None).unwrap();
+        for i in 0..4u8 {
+            log.add_msg(None, vec![0x19, 0x03, i]).unwrap();
+        }
+
+        // Pretend the clock has jumped backwards since the last append
+        let future = log.last_timestamp + 60_000;
+        log.last_timestamp = future;
+        for i in 4..8u8 {
+            log.add_msg(None, vec![0x19, 0x03, i]).unwrap();
+        }
+
+        let timestamps: Vec<u64> = log
+            .iter_from(0)
+            .unwrap()
+            .map(|r| r.unwrap().timestamp)
+            .collect();
+        assert!(timestamps.windows(2).all(|w| w[0] <= w[1]));
+        assert_eq!(timestamps[4..], [future; 4]);
+
+        assert_eq!(log.offset_for_time(0).unwrap(), 0);
+        assert_eq!(log.offset_for_time(future).unwrap(), 4);
+        assert_eq!(log.offset_for_time(future + 1).unwrap(), 8);
+
+        // The latest timestamp survives a restart
+        let mut log = Log::open(path, 54, LogOptions::default(), plain(), None).unwrap();
+        assert_eq!(log.last_timestamp, future);
+        log.add_msg(None, vec![0x19, 0x03, 8]).unwrap();
+        assert_eq!(log.offset_for_time(future).unwrap(), 4);
+    }
+
+    fn retained_log(dir: &Path, retention: Retention) -> Log {
+        let options = LogOptions {
+            retention,
+            ..Default::default()
+        };
+        // Each Message is 27 bytes on disk, so Segments hold 2 Messages.
+        let mut log = Log::open(dir.join("log"), 54, options, plain(), None).unwrap();
+        for i in 0..10u8 {
+            log.add_msg(None, vec![0x19, 0x03, i]).unwrap();
+        }
+        assert_eq!(log.segments.len(), 5);
+        log
+    }
+
+    #[test]
+    fn test_log_retention_max_messages() {
+        let dir = tempfile::tempdir().unwrap();
+        let retention = Retention {
+            max_messages: Some(5),
+            ..Default::default()
+        };
+        let mut log = retained_log(dir.path(), retention);
+
+        assert_eq!(log.enforce_retention().unwrap(), 2);
+        assert_eq!(log.first_offset(), 4);
+        assert_eq!(log.len(), 10);
+        assert_eq!(read_all(&log, 4).len(), 6);
+        assert_eq!(log.enforce_retention().unwrap(), 0);
+
+        // Dropped Segments stay dropped when the Log is reopened
+        let log = Log::open(dir.path().join("log"), 54, log.options, plain(), None).unwrap();
+        assert_eq!(log.first_offset(), 4);
+    }
+
+    #[test]
+    fn test_log_archive() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("log");
+        let archive: Arc<dyn Archive> = Arc::new(archive::LocalDir::new(dir.path().join("cold")));
+        let mut log = Log::open(path.clone(), 54, LogOptions::default(), plain(), None).unwrap();
+        for i in 0..10u8 {
+            log.add_msg(None, vec![0x19, 0x03, i]).unwrap();
+        }
+        let mut log = Log::open(
+            path.clone(),
+            54,
+            log.options,
+            plain(),
+            Some(archive.clone()),
+        )
+        .unwrap();
+
+        // Every sealed Segment is old enough with no minimum age
+        let due = log.archive_due(Duration::from_secs(0));
+        assert_eq!(due.len(), 4);
+        assert!(log.archive_due(Duration::from_secs(3600)).is_empty());
+        for (source, name) in due {
+            archive.put(&name, &source.path).unwrap();
+            log.install_archived(&source, name).unwrap();
+        }
+        assert_eq!((log.archived.len(), log.segments.len()), (4, 1));
+        assert_eq!(log.first_offset(), 0);
+        assert!(log.archive_due(Duration::from_secs(0)).is_empty());
+
+        // Reads stop at archived Segments until they have been fetched back
+        let err = log.iter_from(0).err().expect("read should stop");
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        let fetches = log.pending_fetches();
+        assert_eq!(fetches.len(), 1);
+        assert!(log.pending_fetches().is_empty());
+        for fetch in fetches {
+            fetch.run().unwrap();
+        }
+        assert_eq!(read_all(&log, 0).len(), 10);
+        assert_eq!(read_all(&log, 3), read_all(&log, 0)[3..].to_vec());
+        assert_eq!(log.offset_for_time(0).unwrap(), 0);
+        assert_eq!(log.evict_fetched().unwrap(), 0);
+        assert_eq!(log.evict_fetched().unwrap(), 4);
+        assert!(!path.join("cache").read_dir().unwrap().any(|_| true));
+
+        // Archived Segments survive a restart, but need the Archive to open
+        assert!(Log::open(path.clone(), 54, log.options, plain(), None).is_err());
+        let mut log = Log::open(
+            path.clone(),
+            54,
+            log.options,
+            plain(),
+            Some(archive.clone()),
+        )
+        .unwrap();
+        assert_eq!((log.archived.len(), log.len()), (4, 10));
+        assert_eq!(read_all(&log, 7).len(), 3);
+
+        // Retention drops archived Segments from the Archive too
+        log.options.retention.max_messages = Some(5);
+        assert_eq!(log.enforce_retention().unwrap(), 2);
+        assert_eq!(log.first_offset(), 4);
+        assert_eq!(read_all(&log, 0).len(), 6);
+        let stored = dir.path().join("cold").join("log").read_dir().unwrap();
+        assert_eq!(stored.count(), 2);
+    }
+
+    #[test]
+    fn test_log_redact() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("log");
+        let archive: Arc<dyn Archive> = Arc::new(archive::LocalDir::new(dir.path().join("cold")));
+        let mut log = Log::open(
+            path.clone(),
+            54,
+            LogOptions::default(),
+            plain(),
+            Some(archive.clone()),
+        )
+        .unwrap();
+        for i in 0..7u8 {
+            log.add_msg(None, vec![0x19, 0x03, i]).unwrap();
+        }
+        for (source, name) in log.archive_due(Duration::from_secs(0)).into_iter().take(1) {
+            archive.put(&name, &source.path).unwrap();
+            log.install_archived(&source, name).unwrap();
+        }
+        assert_eq!(log.archived.len(), 1);
+
+        // Archived Segments are only redacted once they have been fetched
+        let err = log.redact(1).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        for fetch in log.pending_fetches() {
+            fetch.run().unwrap();
+        }
+
+        // Archived, sealed and active Segments can all be redacted
+        for offset in [1, 3, 6].iter() {
+            log.redact(*offset).unwrap();
+        }
+        let redacted: Vec<usize> = read_records(&log, 0)
+            .into_iter()
+            .filter(|r| r.is_redacted())
+            .map(|r| r.offset)
+            .collect();
+        assert_eq!(redacted, vec![1, 3, 6]);
+        assert_eq!(log.len(), 7);
+        log.add_msg(None, vec![0x19, 0x03, 7]).unwrap();
+        assert_eq!(read_all(&log, 7), vec![vec![0x19, 0x03, 7]]);
+
+        let err = log.redact(8).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+
+        // Redactions survive a restart, and are stored in the Archive
+        drop(log);
+        archive::clear_cache(&path).unwrap();
+        let log = Log::open(path, 54, LogOptions::default(), plain(), Some(archive)).unwrap();
+        let msgs = read_all(&log, 0);
+        assert_eq!(msgs[1], Vec::<u8>::new());
+        assert_eq!(msgs[2], vec![0x19, 0x03, 2]);
+        assert_eq!(msgs[6], Vec::<u8>::new());
+    }
+
+    #[test]
+    fn test_log_redact_outdates_background_work() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive: Arc<dyn Archive> = Arc::new(archive::LocalDir::new(dir.path().join("cold")));
+        let options = LogOptions {
+            compression: Compression::Lz4,
+            compacted: true,
+            ..Default::default()
+        };
+        let mut log = Log::open(
+            dir.path().join("log"),
+            64,
+            options,
+            plain(),
+            Some(archive.clone()),
+        )
+        .unwrap();
+        for (i, key) in [b"a", b"b", b"a", b"c", b"d"].iter().enumerate() {
+            log.add_msg(Some(&key[..]), vec![0x19, 0x03, i as u8])
+                .unwrap();
+        }
+
+        // Each job reads the first Segment before one of its Messages is
+        // redacted, and tries to install its copy afterwards
+        let uncompressed = log.uncompressed_segments();
+        let compressed =
+            segment::compress(&uncompressed[0].path, Compression::Lz4, &plain()).unwrap();
+        log.redact(1).unwrap();
+        log.install_rewritten(&uncompressed[0], compressed.clone())
+            .unwrap();
+        assert!(!compressed.exists());
+        assert_eq!(log.uncompressed_segments().len(), uncompressed.len());
+        assert_ne!(log.uncompressed_segments()[0], uncompressed[0]);
+
+        compress_all(&mut log);
+        let due = log.compaction_due();
+        let compacted = compaction::compact(&due, &plain()).unwrap();
+        let (source, name) = log.archive_due(Duration::from_secs(0)).remove(0);
+        assert_eq!(compacted[0].0, source);
+        archive.put(&name, &source.path).unwrap();
+        log.redact(0).unwrap();
+        for (source, path) in compacted {
+            log.install_rewritten(&source, path.clone()).unwrap();
+            assert!(!path.exists());
+        }
+        log.install_archived(&source, name.clone()).unwrap();
+        assert!(!dir.path().join("cold").join(&name).exists());
+        assert!(log.archived.is_empty());
+
+        let msgs = read_all(&log, 0);
+        assert_eq!(msgs[0], Vec::<u8>::new());
+        assert_eq!(msgs[1], Vec::<u8>::new());
+        assert_eq!(msgs[2], vec![0x19, 0x03, 2]);
+    }
+
+    fn compress_all(log: &mut Log) {
+        for source in log.uncompressed_segments() {
+            let compressed =
+                segment::compress(&source.path, log.options.compression, &plain()).unwrap();
+            log.install_rewritten(&source, compressed).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_log_compression() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("log");
+        let options = LogOptions {
+            compression: Compression::Zstd,
+            ..Default::default()
+        };
+        let mut log = Log::open(path.clone(), 16, options, plain(), None).unwrap();
+        for i in 0..10u8 {
+            log.add_msg(None, vec![0x19, 0x03, i]).unwrap();
+        }
+
+        let sealed = log.segments.len() - 1;
+        assert_eq!(log.uncompressed_segments().len(), sealed);
+        compress_all(&mut log);
+        assert!(log.uncompressed_segments().is_empty());
+        assert_eq!(read_all(&log, 3), read_all(&log, 0)[3..].to_vec());
+        assert_eq!(read_all(&log, 9), vec![vec![0x19, 0x03, 9]]);
+
+        let mut log = Log::open(path, 16, options, plain(), None).unwrap();
+        assert_eq!(log.len(), 10);
+        assert_eq!(log.segments.len(), sealed + 1);
+        log.add_msg(None, vec![0x19, 0x03, 10]).unwrap();
+        assert_eq!(read_all(&log, 0).len(), 11);
+    }
+
+    #[test]
+    fn test_log_compression_recovery() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("log");
+        let options = LogOptions {
+            compression: Compression::Lz4,
+            ..Default::default()
+        };
+        let mut log = Log::open(path.clone(), 16, options, plain(), None).unwrap();
+        for i in 0..10u8 {
+            log.add_msg(None, vec![0x19, 0x03, i]).unwrap();
+        }
+        let expected = read_all(&log, 0);
+
+        // Simulate crashes part way through compressing Segments, leaving both
+        // the original and its compressed copy, or a partial copy.
+        let uncompressed = log.uncompressed_segments();
+        let source = &uncompressed[0].path;
+        let copy = segment::compress(source, Compression::Lz4, &plain()).unwrap();
+        segment::install(&copy, source).unwrap();
+        fs::write(path.join("00000000000000000002.lz4.tmp"), b"partial").unwrap();
+        drop(log);
+
+        let mut log = Log::open(path.clone(), 16, options, plain(), None).unwrap();
+        assert_eq!(read_all(&log, 0), expected);
+        assert_eq!(log.uncompressed_segments().len(), uncompressed.len() - 1);
+        assert!(!source.exists());
+        assert!(!source.with_extension("index").exists());
+        assert!(!path.join("00000000000000000002.lz4.tmp").exists());
+
+        // A Log whose every Segment was compressed gets a new active Segment
+        compress_all(&mut log);
+        let active = log.segments.pop().unwrap();
+        let active_base = active.base_offset;
+        active.delete().unwrap();
+        let mut log = Log::open(path, 16, options, plain(), None).unwrap();
+        assert_eq!(log.len(), active_base);
+        log.add_msg(None, vec![0x19, 0x03, 10]).unwrap();
+        assert_eq!(log.len(), active_base + 1);
+    }
+
+    #[test]
+    fn test_log_compaction() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("log");
+        let options = LogOptions {
+            compacted: true,
+            ..Default::default()
+        };
+        // Each keyed Message is 28 bytes on disk, so Segments hold 2 Messages.
+        let mut log = Log::open(path.clone(), 64, options, plain(), None).unwrap();
+        for (i, key) in [b"a", b"b", b"a", b"c", b"a"].iter().enumerate() {
+            log.add_msg(Some(&key[..]), vec![0x19, 0x03, i as u8])
+                .unwrap();
+        }
+        log.add_msg(Some(&[]), vec![0x19, 0x03, 5]).unwrap();
+
+        let due = log.compaction_due();
+        assert_eq!(due.len(), 2);
+        for (source, compacted) in compaction::compact(&due, &plain()).unwrap() {
+            log.install_rewritten(&source, compacted).unwrap();
+        }
+        log.set_compacted_through(due[1].base_offset + 1);
+        assert!(log.compaction_due().is_empty());
+
+        let offsets = |log: &Log| -> Vec<usize> {
+            log.iter_from(0)
+                .unwrap()
+                .map(|r| r.unwrap().offset)
+                .collect()
+        };
+        // Offset 2 was replaced by a Message in the active Segment, which isn't
+        // compacted until it is sealed
+        assert_eq!(offsets(&log), vec![1, 2, 3, 4, 5]);
+        assert_eq!(read_all(&log, 0), read_all(&log, 1));
+        assert_eq!(log.len(), 6);
+
+        let log = Log::open(path, 64, options, plain(), None).unwrap();
+        assert_eq!(offsets(&log), vec![1, 2, 3, 4, 5]);
+        assert_eq!(log.len(), 6);
+    }
+
+    #[test]
+    fn test_log_rewrite_installed_under_lock() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = LogOptions {
+            compression: Compression::Lz4,
+            compacted: true,
+            ..Default::default()
+        };
+        let mut log = Log::open(dir.path().join("log"), 64, options, plain(), None).unwrap();
+        for (i, key) in [b"a", b"a", b"b", b"a", b"c"].iter().enumerate() {
+            log.add_msg(Some(&key[..]), vec![0x19, 0x03, i as u8])
+                .unwrap();
+        }
+        compress_all(&mut log);
+        let before = read_all(&log, 0);
+
+        // Compacted copies of compressed Segments keep their file names, but
+        // the Log reads the originals until the copies are installed
+        let due = log.compaction_due();
+        let rewritten = compaction::compact(&due, &plain()).unwrap();
+        assert!(!rewritten.is_empty());
+        assert_eq!(read_all(&log, 0), before);
+
+        for (source, compacted) in rewritten {
+            log.install_rewritten(&source, compacted).unwrap();
+        }
+        assert!(read_all(&log, 0).len() < before.len());
+    }
+
+    #[test]
+    fn test_log_retention_max_bytes() {
+        let dir = tempfile::tempdir().unwrap();
+        let retention = Retention {
+            max_bytes: Some(108),
+            ..Default::default()
+        };
+        let mut log = retained_log(dir.path(), retention);
+
+        assert_eq!(log.enforce_retention().unwrap(), 3);
+        assert_eq!(log.first_offset(), 6);
+    }
+
+    #[test]
+    fn test_log_retention_max_age() {
+        let dir = tempfile::tempdir().unwrap();
+        let retention = Retention {
+            max_age_secs: Some(0),
+            ..Default::default()
+        };
+        let mut log = retained_log(dir.path(), retention);
+
+        // Everything is expired, but the active Segment is always kept.
+        assert_eq!(log.enforce_retention().unwrap(), 4);
+        assert_eq!(log.first_offset(), 8);
+        assert_eq!(read_all(&log, 8).len(), 2);
+    }
+
+    #[test]
+    fn test_log_dir_name() {
+        let name = "metrics/../ünïcode";
+        let path = Path::new("/tmp").join(dir_name(name));
+        assert_eq!(name_from_dir(&path), Some(name.to_owned()));
+        assert_eq!(name_from_dir(Path::new("/tmp/xyz")), None);
+    }
 }
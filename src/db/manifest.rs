@@ -1,73 +1,145 @@
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use std::collections::hash_map::Entry;
 use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use std::time::SystemTime;
 
+use super::crypto::Keyring;
 use super::iters::Itr;
-use crate::commands::IteratorKind;
+use crate::commands::{IteratorKind, LogOptions};
 use crate::errors::Error;
 
+pub const MANIFEST_FILE: &str = "MANIFEST";
+
 /// The Manifest is a file at the root of the database directory that is used
 /// as a registry for database constructs such as Logs and Iters. It will map
 /// the identifiers of those constructs to their corresponding files, along
 /// with any metadata needed.
 ///
-/// Right now the Manifest is held in memory, just like the rest of POC database
-/// until we are happy with the interface.
-#[derive(Debug, PartialEq, Eq)]
+/// Every change is written to a temporary file which is then renamed over the
+/// old Manifest, so a crash can never leave a partially written Manifest.
+///
+/// The Manifest is encrypted with the current key in its Keyring every time it
+/// is written. A Manifest written before encryption was turned on is only read
+/// while the Keyring is migrating, and is encrypted as soon as it is opened.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Manifest {
+    #[serde(skip)]
+    path: PathBuf,
+    #[serde(skip)]
+    keyring: Arc<Keyring>,
     pub logs: HashMap<String, LogRegistrant>,
     pub itrs: HashMap<String, Itr>,
 }
 
 impl Manifest {
-    pub fn new() -> Self {
-        Manifest {
-            logs: HashMap::new(),
-            itrs: HashMap::new(),
+    /// Loads the Manifest stored in `dir`. If there isn't one yet, an empty
+    /// Manifest is returned and will be written on the first change.
+    pub fn open(dir: &Path, keyring: Arc<Keyring>) -> io::Result<Self> {
+        let path = dir.join(MANIFEST_FILE);
+        if !path.exists() {
+            return Ok(Manifest {
+                path,
+                keyring,
+                logs: HashMap::new(),
+                itrs: HashMap::new(),
+            });
+        }
+
+        let bytes = keyring.decrypt_file(fs::read(&path)?)?;
+        let mut manifest: Manifest = serde_cbor::from_slice(&bytes)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        manifest.path = path;
+        manifest.keyring = keyring;
+        if manifest.keyring.is_migrating() {
+            manifest.write()?;
         }
+        Ok(manifest)
+    }
+
+    /// Where the Manifest is stored. The file doesn't exist until the first
+    /// change is written.
+    pub fn path(&self) -> &Path {
+        &self.path
     }
 
-    pub fn add_log(&mut self, name: String) {
-        self.logs
-            .entry(name.clone())
-            .or_insert_with(|| LogRegistrant {
-                name,
+    /// Durably writes the Manifest to disk.
+    fn save(&self) -> Result<(), Error> {
+        self.write().map_err(|e| {
+            error!("could not write manifest {:?}: {}", self.path, e);
+            Error::ErrWritingManifest
+        })
+    }
+
+    fn write(&self) -> io::Result<()> {
+        let bytes = serde_cbor::to_vec(self).map_err(io::Error::other)?;
+        let bytes = self.keyring.encrypt_file(&bytes)?;
+        let tmp = self.path.with_extension("tmp");
+
+        let mut file = File::create(&tmp)?;
+        file.write_all(&bytes)?;
+        file.sync_all()?;
+        fs::rename(&tmp, &self.path)?;
+
+        // The rename itself is only durable once the directory is synced.
+        if let Some(dir) = self.path.parent() {
+            File::open(dir)?.sync_all()?;
+        }
+        Ok(())
+    }
+
+    pub fn add_log(&mut self, name: String, options: LogOptions) -> Result<(), Error> {
+        if let Entry::Vacant(e) = self.logs.entry(name.clone()) {
+            e.insert(LogRegistrant {
+                name: name.clone(),
+                options,
                 created_at: SystemTime::now()
                     .duration_since(SystemTime::UNIX_EPOCH)
                     .expect("could not get system time")
                     .as_secs() as usize,
             });
+            if let Err(e) = self.save() {
+                self.logs.remove(&name);
+                return Err(e);
+            }
+        }
+        Ok(())
     }
-    pub fn del_log(&mut self, name: String) {
-        self.logs.remove(&name.clone());
-        let to_be_deleted: Vec<String> = self
-            .itrs
-            .iter()
-            .filter(|(_, itr)| itr.log == name)
-            .map(|(_, x)| x.name.clone())
-            .collect();
 
-        for itr in to_be_deleted.iter() {
-            self.del_itr(name.clone(), itr.into())
-                .expect("Could not delete itrs associated with log");
+    pub fn del_log(&mut self, name: String) -> Result<(), Error> {
+        let log = match self.logs.remove(&name) {
+            Some(log) => log,
+            None => return Ok(()),
+        };
+        let (dropped, kept): (HashMap<_, _>, HashMap<_, _>) = std::mem::take(&mut self.itrs)
+            .into_iter()
+            .partition(|(_, itr)| itr.log == name);
+        self.itrs = kept;
+        if let Err(e) = self.save() {
+            self.logs.insert(name, log);
+            self.itrs.extend(dropped);
+            return Err(e);
         }
+        Ok(())
     }
-    pub fn add_itr(
-        &mut self,
-        log: String,
-        name: String,
-        kind: IteratorKind,
-        func: String,
-    ) -> Result<(), Error> {
-        let itr = Itr {
-            log,
-            name: name.clone(),
-            kind: kind,
-            func,
-        };
 
-        let entry = self.itrs.entry(name);
+    /// Adds an Iterator. An Iterator can only have an Indexed Iterator over the
+    /// same Log as its source, and Reduce Iterators can't be indexed.
+    pub fn add_itr(&mut self, itr: Itr) -> Result<(), Error> {
+        if itr.indexed && itr.kind == IteratorKind::Reduce {
+            return Err(Error::ItrTypeInvalid);
+        }
+        if let Some(source) = &itr.source {
+            match self.itrs.get(source) {
+                Some(source) if source.indexed && source.log == itr.log => (),
+                _ => return Err(Error::ItrSourceInvalid),
+            }
+        }
+
+        let entry = self.itrs.entry(itr.name.clone());
         match entry {
             Entry::Occupied(e) => {
                 let stored_itr = e.get();
@@ -76,37 +148,73 @@ impl Manifest {
                 };
             }
             Entry::Vacant(e) => {
+                let name = e.key().clone();
                 e.insert(itr);
+                if let Err(e) = self.save() {
+                    self.itrs.remove(&name);
+                    return Err(e);
+                }
             }
         };
 
         Ok(())
     }
 
+    /// Deletes an Iterator, unless another Iterator has it as its source.
     pub fn del_itr(&mut self, log: String, name: String) -> Result<(), Error> {
-        let entry = self.itrs.entry(name);
-        match entry {
+        if self
+            .itrs
+            .values()
+            .any(|itr| itr.source.as_ref() == Some(&name))
+        {
+            return Err(Error::ItrHasDependents);
+        }
+
+        let entry = self.itrs.entry(name.clone());
+        let itr = match entry {
             Entry::Occupied(e) => {
                 let itr = e.get();
                 if itr.log != log {
                     return Err(Error::ItrDoesNotExist);
                 }
-                let _ = e.remove();
+                e.remove()
             }
             Entry::Vacant(_e) => {
                 return Err(Error::ItrDoesNotExist);
             }
         };
 
+        if let Err(e) = self.save() {
+            self.itrs.insert(name, itr);
+            return Err(e);
+        }
         Ok(())
     }
+
+    /// Every Iterator, with each one coming after the Iterator it reads from
+    pub fn sorted_itrs(&self) -> Vec<&Itr> {
+        let depth = |itr: &Itr| {
+            let mut depth = 0;
+            let mut source = itr.source.as_ref().and_then(|s| self.itrs.get(s));
+            while let Some(itr) = source {
+                depth += 1;
+                source = itr.source.as_ref().and_then(|s| self.itrs.get(s));
+            }
+            depth
+        };
+        let mut itrs: Vec<&Itr> = self.itrs.values().collect();
+        itrs.sort_by_key(|itr| depth(itr));
+        itrs
+    }
 }
 
 /// The Manifest entry for a Log
-#[derive(Debug, PartialEq, Eq, Serialize)]
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct LogRegistrant {
     pub name: String,
     pub created_at: usize,
+    #[serde(flatten)]
+    pub options: LogOptions,
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -120,44 +228,136 @@ pub struct ItrRegistrant {
 #[cfg(test)]
 mod tests {
     use super::*;
+
+    fn map_itr(name: &str, func: &str) -> Itr {
+        Itr::new("test".into(), name.into(), "map".into(), func.into())
+    }
+
     #[test]
     fn test_manifest_new() {
-        let manifest = Manifest::new();
+        let dir = tempfile::tempdir().unwrap();
+        let manifest = Manifest::open(dir.path(), Arc::default()).unwrap();
         assert_eq!(
             manifest,
             Manifest {
+                path: dir.path().join(MANIFEST_FILE),
+                keyring: Arc::default(),
                 logs: HashMap::new(),
                 itrs: HashMap::new(),
             }
         );
     }
+
+    #[test]
+    fn test_manifest_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut manifest = Manifest::open(dir.path(), Arc::default()).unwrap();
+        manifest
+            .add_log("test".into(), LogOptions::default())
+            .unwrap();
+        manifest
+            .add_log("test2".into(), LogOptions::default())
+            .unwrap();
+        manifest.add_itr(map_itr("fun", "func")).unwrap();
+        manifest.del_log("test2".into()).unwrap();
+
+        let reopened = Manifest::open(dir.path(), Arc::default()).unwrap();
+        assert_eq!(reopened, manifest);
+        assert!(!dir.path().join("MANIFEST.tmp").exists());
+    }
+    #[test]
+    fn test_manifest_encrypted() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut manifest = Manifest::open(dir.path(), Arc::default()).unwrap();
+        manifest
+            .add_log("plain".into(), LogOptions::default())
+            .unwrap();
+
+        // An unencrypted Manifest is refused once there are keys, unless it is
+        // being migrated, which encrypts it straight away
+        let keyring = Keyring::with_test_keys(&[1]);
+        let err = Manifest::open(dir.path(), Arc::new(keyring.clone())).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        Manifest::open(dir.path(), Arc::new(keyring.clone().migrating())).unwrap();
+        let bytes = fs::read(dir.path().join(MANIFEST_FILE)).unwrap();
+        assert!(!bytes.windows(5).any(|w| w == b"plain"));
+
+        let keyring = Arc::new(keyring);
+        let mut manifest = Manifest::open(dir.path(), keyring.clone()).unwrap();
+        manifest
+            .add_log("secret".into(), LogOptions::default())
+            .unwrap();
+        let bytes = fs::read(dir.path().join(MANIFEST_FILE)).unwrap();
+        assert!(!bytes.windows(6).any(|w| w == b"secret"));
+
+        let reopened = Manifest::open(dir.path(), keyring).unwrap();
+        assert_eq!(reopened, manifest);
+        assert!(Manifest::open(dir.path(), Arc::default()).is_err());
+    }
+
+    #[test]
+    fn test_manifest_failed_save() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut manifest = Manifest::open(dir.path(), Arc::default()).unwrap();
+        manifest
+            .add_log("test".into(), LogOptions::default())
+            .unwrap();
+        manifest.add_itr(map_itr("fun", "func")).unwrap();
+
+        // Nothing changes in memory when the Manifest can't be written
+        fs::create_dir(dir.path().join("MANIFEST.tmp")).unwrap();
+        let res = manifest.add_itr(map_itr("fun2", "func"));
+        assert_eq!(res, Err(Error::ErrWritingManifest));
+        assert!(!manifest.itrs.contains_key("fun2"));
+        let res = manifest.del_itr("test".into(), "fun".into());
+        assert_eq!(res, Err(Error::ErrWritingManifest));
+        let res = manifest.del_log("test".into());
+        assert_eq!(res, Err(Error::ErrWritingManifest));
+        assert!(manifest.logs.contains_key("test"));
+        assert!(manifest.itrs.contains_key("fun"));
+
+        fs::remove_dir(dir.path().join("MANIFEST.tmp")).unwrap();
+        manifest.add_itr(map_itr("fun2", "func")).unwrap();
+        let reopened = Manifest::open(dir.path(), Arc::default()).unwrap();
+        assert!(reopened.itrs.contains_key("fun2"));
+    }
+
     #[test]
     fn test_manifest_add_log() {
-        let mut manifest = Manifest::new();
-        manifest.add_log("test".into());
-        manifest.add_log("test2".into());
-        manifest.add_log("test3".into());
+        let dir = tempfile::tempdir().unwrap();
+        let mut manifest = Manifest::open(dir.path(), Arc::default()).unwrap();
+        manifest
+            .add_log("test".into(), LogOptions::default())
+            .unwrap();
+        manifest
+            .add_log("test2".into(), LogOptions::default())
+            .unwrap();
+        manifest
+            .add_log("test3".into(), LogOptions::default())
+            .unwrap();
         assert!(manifest.logs.contains_key("test"));
         assert!(manifest.logs.contains_key("test2"));
         assert!(manifest.logs.contains_key("test3"));
         assert_eq!(manifest.logs.contains_key("test1"), false);
 
         // This second add_log is here to make sure code does not panic
-        manifest.add_log("test".into());
+        manifest
+            .add_log("test".into(), LogOptions::default())
+            .unwrap();
     }
     #[test]
     fn test_manifest_add_itr() {
-        let mut manifest = Manifest::new();
-        let _ = manifest.add_itr("test".into(), "fun".into(), "map".into(), "func".into());
-        let _ = manifest.add_itr("test".into(), "fun2".into(), "map".into(), "func".into());
-        let _ = manifest.add_itr("test".into(), "fun3".into(), "map".into(), "func".into());
+        let dir = tempfile::tempdir().unwrap();
+        let mut manifest = Manifest::open(dir.path(), Arc::default()).unwrap();
+        let _ = manifest.add_itr(map_itr("fun", "func"));
+        let _ = manifest.add_itr(map_itr("fun2", "func"));
+        let _ = manifest.add_itr(map_itr("fun3", "func"));
         assert!(manifest.itrs.contains_key("fun"));
         assert!(manifest.itrs.contains_key("fun2"));
         assert!(manifest.itrs.contains_key("fun3"));
         assert_eq!(manifest.logs.contains_key("fun1"), false);
 
-        let duplicate_error =
-            manifest.add_itr("test".into(), "fun".into(), "map".into(), "func2".into());
+        let duplicate_error = manifest.add_itr(map_itr("fun", "func2"));
         assert_eq!(
             format!("{:?}", duplicate_error),
             format!("Err(ItrExistsWithSameName)")
@@ -166,9 +366,10 @@ mod tests {
 
     #[test]
     fn test_manifest_del_itr() {
-        let mut manifest = Manifest::new();
+        let dir = tempfile::tempdir().unwrap();
+        let mut manifest = Manifest::open(dir.path(), Arc::default()).unwrap();
         // Normal
-        let _ = manifest.add_itr("test".into(), "fun".into(), "map".into(), "func".into());
+        let _ = manifest.add_itr(map_itr("fun", "func"));
         assert!(manifest.itrs.contains_key("fun"));
         let _ = manifest.del_itr("test".into(), "fun".into());
         assert_eq!(manifest.logs.contains_key("fun"), false);
@@ -180,7 +381,7 @@ mod tests {
             format!("Err(ItrDoesNotExist)")
         );
         // Neither function or log exist
-        let _ = manifest.add_itr("test".into(), "fun".into(), "map".into(), "func".into());
+        let _ = manifest.add_itr(map_itr("fun", "func"));
 
         let log_does_not_exist_error = manifest.del_itr("test1".into(), "fun".into());
         assert_eq!(
@@ -188,4 +389,54 @@ mod tests {
             format!("Err(ItrDoesNotExist)")
         );
     }
+
+    #[test]
+    fn test_manifest_itr_source() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut manifest = Manifest::open(dir.path(), Arc::default()).unwrap();
+        let itr = |name: &str, source: Option<&str>| Itr {
+            indexed: true,
+            source: source.map(String::from),
+            ..Itr::new("test".into(), name.into(), "filter".into(), "func".into())
+        };
+        manifest.add_itr(itr("base", None)).unwrap();
+        manifest.add_itr(itr("second", Some("base"))).unwrap();
+        manifest.add_itr(itr("third", Some("second"))).unwrap();
+
+        // Sources have to be Indexed Iterators over the same Log
+        let missing = manifest.add_itr(itr("other", Some("missing")));
+        assert_eq!(missing, Err(Error::ItrSourceInvalid));
+        manifest.add_itr(map_itr("plain", "func")).unwrap();
+        let not_indexed = manifest.add_itr(itr("other", Some("plain")));
+        assert_eq!(not_indexed, Err(Error::ItrSourceInvalid));
+        let other_log = Itr {
+            log: "test2".into(),
+            ..itr("other", Some("base"))
+        };
+        assert_eq!(manifest.add_itr(other_log), Err(Error::ItrSourceInvalid));
+
+        // Reduce Iterators start from their initial value on each page, which
+        // stored results can't do
+        let reduce = Itr::new("test".into(), "sum".into(), "reduce".into(), "func".into());
+        let indexed = Itr {
+            indexed: true,
+            ..reduce
+        };
+        assert_eq!(manifest.add_itr(indexed), Err(Error::ItrTypeInvalid));
+
+        let sorted: Vec<&str> = manifest
+            .sorted_itrs()
+            .iter()
+            .filter(|itr| itr.indexed)
+            .map(|itr| itr.name.as_str())
+            .collect();
+        assert_eq!(sorted, vec!["base", "second", "third"]);
+
+        // Sources can't be deleted until nothing reads from them
+        let res = manifest.del_itr("test".into(), "base".into());
+        assert_eq!(res, Err(Error::ItrHasDependents));
+        manifest.del_itr("test".into(), "third".into()).unwrap();
+        manifest.del_itr("test".into(), "second".into()).unwrap();
+        manifest.del_itr("test".into(), "base".into()).unwrap();
+    }
 }
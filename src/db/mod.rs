@@ -1,240 +1,2714 @@
+mod archive;
+mod audit;
+mod compaction;
+mod compression;
+mod crypto;
+mod index;
+mod indexed;
 mod iters;
 mod logs;
 mod manifest;
+mod segment;
+mod snapshot;
+mod store;
 
 use std::collections::hash_map::Entry;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::{oneshot, Notify};
 
 use crate::commands;
-use crate::commands::{Command, IteratorKind};
+use crate::commands::{Command, Compression, FsyncPolicy, LogOptions, Position};
+use crate::config::{RemitsConfig, StorageBackend};
 use crate::errors::Error;
 use crate::protocol::Response;
-use logs::Log;
-use manifest::Manifest;
+use archive::{Archive, Fetch, LocalDir};
+use audit::{AuditLog, Redaction};
+use crypto::Keyring;
+use indexed::Index;
+use iters::Itr;
+use logs::{Commit, Log};
+use manifest::{LogRegistrant, Manifest};
+use segment::Sealed;
+use serde::Serialize;
+use snapshot::Snapshot;
+use store::{LogStore, MemoryLog, Segmented};
 
 const OK_RESP: &[u8] = &[0x62, 0x6F, 0x6B];
 
-// Temporarily, everything will be done in memory until we're happy with the
-// interface.
-#[derive(Debug, PartialEq, Eq)]
+const LOGS_DIR: &str = "logs";
+const INDEXES_DIR: &str = "indexes";
+/// Where Log directories the Manifest doesn't know about are moved to, so an
+/// operator can decide what to do with them
+const LOST_FOUND_DIR: &str = "lost+found";
+const MAX_FLUSH_WAIT: Duration = Duration::from_secs(1);
+/// Extension of the directories Logs are copied into while being imported
+const IMPORT_EXT: &str = "import";
+
+#[derive(Debug)]
 pub struct DB {
+    logs_dir: PathBuf,
+    segment_size: u64,
     manifest: Manifest,
-    logs: HashMap<String, Log>,
+    storage: StorageBackend,
+    logs: HashMap<String, Box<dyn LogStore>>,
+    indexes_dir: PathBuf,
+    /// The stored results of every Indexed Iterator, by the Iterator's name
+    indexes: HashMap<String, Index>,
+    /// Indexed Iterators whose Indexes are still being backfilled, with the
+    /// offsets of the Messages redacted from their Log in the meantime
+    backfills: HashMap<String, Vec<usize>>,
+    keyring: Arc<Keyring>,
+    archive: Option<Arc<dyn Archive>>,
+    /// How long a sealed Segment goes unmodified before it is archived
+    archive_after: Duration,
+    snapshots_dir: PathBuf,
+    audit: AuditLog,
+    /// Admin requests are refused unless they carry this token
+    admin_token: Option<String>,
+    /// MessageAdds waiting on a group commit, oldest first
+    pending_adds: Vec<PendingAdd>,
+    commit_wanted: Arc<Notify>,
+}
+
+/// The Index of an Iterator being added, which is run over the Messages
+/// already in what the Iterator reads from a batch at a time
+#[derive(Debug)]
+struct Backfill {
+    name: String,
+    index: Index,
+    /// Whether the Iterator was already added, and its Index had failed
+    rebuild: bool,
+}
+
+/// A MessageAdd whose Message has been written, but won't be acknowledged
+/// until a group commit has made it durable
+#[derive(Debug)]
+struct PendingAdd {
+    log: String,
+    offset: usize,
+    done: oneshot::Sender<Response>,
 }
 
-impl DB {
-    pub fn new() -> Self {
-        DB {
-            manifest: Manifest::new(),
-            logs: HashMap::new(),
+impl DB {
+    /// Opens the database stored in the configured data directory, loading
+    /// the Manifest and every Log registered in it. If an encryption key file
+    /// is configured, everything is encrypted at rest with its keys. If an
+    /// archive directory is configured, cold Segments are moved to it.
+    pub fn new(cfg: &RemitsConfig) -> io::Result<Self> {
+        let data_dir = cfg.data_dir();
+        let logs_dir = data_dir.join(LOGS_DIR);
+        fs::create_dir_all(&logs_dir)?;
+        let indexes_dir = data_dir.join(INDEXES_DIR);
+        fs::create_dir_all(&indexes_dir)?;
+
+        let keyring = load_keyring(cfg)?;
+        info!("encryption keys loaded: {:?}", keyring);
+        let archive: Option<Arc<dyn Archive>> = match &cfg.archive_dir {
+            Some(dir) => Some(Arc::new(LocalDir::new(dir.clone()))),
+            None => None,
+        };
+
+        let mut db = DB {
+            logs_dir,
+            segment_size: cfg.segment_size(),
+            manifest: Manifest::open(&data_dir, keyring.clone())?,
+            storage: cfg.storage(),
+            audit: AuditLog::open(&data_dir, keyring.clone())?,
+            logs: HashMap::new(),
+            indexes_dir,
+            indexes: HashMap::new(),
+            backfills: HashMap::new(),
+            keyring,
+            archive,
+            archive_after: cfg.archive_after(),
+            snapshots_dir: cfg.snapshot_dir(),
+            admin_token: cfg.admin_token.clone(),
+            pending_adds: vec![],
+            commit_wanted: Arc::new(Notify::new()),
+        };
+
+        for (name, reg) in db.manifest.logs.iter() {
+            let log = db.open_log(name, reg.options)?;
+            info!("loaded log {} with {} messages", name, log.len());
+            db.logs.insert(name.clone(), log);
+        }
+
+        // A Log is removed from the Manifest before its files are deleted, so
+        // anything left over is usually from a delete that was interrupted.
+        // It could also be a Log whose Manifest was lost, so it is moved out
+        // of the way rather than deleted.
+        for entry in fs::read_dir(&db.logs_dir)? {
+            let path = entry?.path();
+            match logs::name_from_dir(&path) {
+                Some(name) if db.logs.contains_key(&name) => (),
+                _ => {
+                    let dest = quarantine(&data_dir, &path)?;
+                    warn!("moved files for unregistered log {:?} to {:?}", path, dest);
+                }
+            }
+        }
+
+        // Indexes pick up where they left off, and catch up with any Messages
+        // added since. An Iterator that fails on one of them only fails when
+        // it is read from.
+        let indexed: Vec<String> = db
+            .manifest
+            .sorted_itrs()
+            .into_iter()
+            .filter(|itr| itr.indexed)
+            .map(|itr| itr.name.clone())
+            .collect();
+        for name in indexed {
+            if let Err(e) = db.load_index(&name) {
+                error!("could not load index of iterator {}: {:?}", name, e);
+            }
+        }
+        for entry in fs::read_dir(&db.indexes_dir)? {
+            let path = entry?.path();
+            let itr = logs::name_from_dir(&path).and_then(|name| db.manifest.itrs.get(&name));
+            match itr {
+                Some(itr) if itr.indexed => (),
+                _ => {
+                    warn!("removing files for deleted index: {:?}", path);
+                    fs::remove_dir_all(&path)?;
+                }
+            }
+        }
+
+        Ok(db)
+    }
+
+    fn log_dir(&self, name: &str) -> PathBuf {
+        self.logs_dir.join(logs::dir_name(name))
+    }
+
+    /// Opens the Log called `name` in the configured storage, creating it if
+    /// it doesn't exist yet.
+    fn open_log(&self, name: &str, options: LogOptions) -> io::Result<Box<dyn LogStore>> {
+        self.open_store(self.log_dir(name), options, self.archive.clone())
+    }
+
+    /// Opens a LogStore in the configured storage, which is kept in `dir` if
+    /// it is stored in files.
+    fn open_store(
+        &self,
+        dir: PathBuf,
+        options: LogOptions,
+        archive: Option<Arc<dyn Archive>>,
+    ) -> io::Result<Box<dyn LogStore>> {
+        Ok(match self.storage {
+            StorageBackend::File => Box::new(Log::open(
+                dir,
+                self.segment_size,
+                options,
+                self.keyring.clone(),
+                archive,
+            )?),
+            StorageBackend::Memory => Box::new(MemoryLog::new(options)),
+        })
+    }
+
+    /// Opens the stored results of the Indexed Iterator `itr`, which reads
+    /// from `log`. If there are results for Messages `log` lost in a crash,
+    /// they are all thrown away to be worked out again.
+    fn open_index(&self, itr: &Itr, log: &dyn LogStore) -> io::Result<Index> {
+        // An Index can always be rebuilt from its Log, so it is only synced
+        // as often as Logs are flushed, and never archived.
+        let options = LogOptions {
+            fsync: FsyncPolicy::EveryMillis(MAX_FLUSH_WAIT.as_millis() as u64),
+            retention: log.options().retention,
+            ..Default::default()
+        };
+        let dir = self.indexes_dir.join(logs::dir_name(&itr.name));
+        let index = Index::open(self.open_store(dir.clone(), options, None)?)?;
+        if index.next_offset() <= log.len() {
+            return Ok(index);
+        }
+
+        warn!(
+            "index of iterator {} is ahead of log {}, rebuilding it",
+            itr.name, itr.log
+        );
+        index.destroy()?;
+        Index::open(self.open_store(dir, options, None)?)
+    }
+
+    /// Calls `f` with what `itr` reads from, which is either its Log or the
+    /// results of its source.
+    fn with_source<T>(
+        &self,
+        itr: &Itr,
+        f: impl FnOnce(&dyn LogStore) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        let log = match self.logs.get(&itr.log) {
+            Some(log) => log.as_ref(),
+            None => return Err(Error::LogDoesNotExist),
+        };
+        // Results are read as though they were Messages in the Log, so a
+        // source's results are read the same way whatever it reads from.
+        match &itr.source {
+            Some(source) => match self.indexes.get(source) {
+                Some(index) => f(&index.results(log)),
+                None => Err(Error::ErrReadingLog),
+            },
+            None => f(log),
+        }
+    }
+
+    /// Opens the Index of the Indexed Iterator called `name`, creating it if
+    /// there isn't one yet, and catches it up with what it reads from.
+    fn load_index(&mut self, name: &str) -> Result<(), Error> {
+        let index = self.open_itr_index(name)?;
+        self.indexes.insert(name.to_string(), index);
+        self.catch_up_index(name)
+    }
+
+    /// Opens the Index of the Indexed Iterator called `name` without adding
+    /// it to the DB.
+    fn open_itr_index(&self, name: &str) -> Result<Index, Error> {
+        let itr = match self.manifest.itrs.get(name) {
+            Some(itr) => itr,
+            None => return Err(Error::ItrDoesNotExist),
+        };
+        self.with_source(itr, |source| {
+            self.open_index(itr, source).map_err(|e| {
+                error!("could not open index of iterator {}: {}", name, e);
+                Error::ErrWritingLog
+            })
+        })
+    }
+
+    /// Runs the Indexed Iterator called `name` over what has been added to
+    /// what it reads from since it last ran.
+    fn catch_up_index(&mut self, name: &str) -> Result<(), Error> {
+        let (itr, mut index) = match (self.manifest.itrs.get(name), self.indexes.remove(name)) {
+            (Some(itr), Some(index)) => (itr, index),
+            _ => return Err(Error::ErrReadingLog),
+        };
+        let res = self.with_source(itr, |source| index.catch_up(itr, source));
+        self.indexes.insert(name.to_string(), index);
+        res
+    }
+
+    /// Why the Index of the Iterator called `name`, or of any Iterator it
+    /// reads from, has stopped, if one has.
+    fn index_failure(&self, name: &str) -> Option<Error> {
+        let mut next = Some(name);
+        while let Some(name) = next {
+            if let Some((_, e)) = self.indexes.get(name).and_then(Index::failed) {
+                return Some(e);
+            }
+            next = self
+                .manifest
+                .itrs
+                .get(name)
+                .and_then(|itr| itr.source.as_deref());
+        }
+        None
+    }
+
+    /// The names of the Indexed Iterators over `log`, with each one coming
+    /// after the Iterator it reads from
+    fn indexed_itrs(&self, log: &str) -> Vec<String> {
+        self.manifest
+            .sorted_itrs()
+            .into_iter()
+            .filter(|itr| itr.log == log && self.indexes.contains_key(&itr.name))
+            .map(|itr| itr.name.clone())
+            .collect()
+    }
+
+    /// Runs every Indexed Iterator over `log` on the Messages added to it
+    /// since they last ran. Indexes that have failed are left until they are
+    /// rebuilt.
+    fn update_indexes(&mut self, log: &str) {
+        for name in self.indexed_itrs(log) {
+            if self.indexes.get(&name).and_then(Index::failed).is_some() {
+                continue;
+            }
+            if let Err(e) = self.catch_up_index(&name) {
+                error!("could not update index of iterator {}: {:?}", name, e);
+            }
+        }
+    }
+
+    /// Removes the results Indexed Iterators over `log` stored for the
+    /// Message at `offset`. Results keep the offset of the Message they were
+    /// returned for, so this covers Iterators that read from a source too.
+    fn redact_indexes(&mut self, log: &str, offset: usize) -> Result<(), Error> {
+        for (name, redacted) in self.backfills.iter_mut() {
+            if self
+                .manifest
+                .itrs
+                .get(name)
+                .is_some_and(|itr| itr.log == log)
+            {
+                redacted.push(offset);
+            }
+        }
+        for name in self.indexed_itrs(log) {
+            let res = match self.indexes.get_mut(&name) {
+                Some(index) => index.redact(offset),
+                None => Ok(()),
+            };
+            if let Err(e) = res {
+                error!(
+                    "could not redact {} from index of iterator {}: {}",
+                    offset, name, e
+                );
+                return Err(Error::ErrWritingLog);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn exec(&mut self, cmd: Command) -> Response {
+        use Command::*;
+
+        match cmd {
+            LogShow(commands::LogShow { log_name }) => self.log_show(log_name),
+            LogAdd(commands::LogAdd { log_name, options }) => self.log_add(log_name, options),
+            LogDelete(commands::LogDelete { log_name }) => self.log_delete(log_name),
+            LogList => self.log_list(),
+            IteratorList(commands::IteratorList { log_name }) => self.itr_list(log_name),
+            MessageAdd(commands::MessageAdd {
+                log_name,
+                key,
+                message,
+            }) => self.msg_add(log_name, key, message),
+            IteratorAdd(add) => self.itr_add(add.into()),
+            //ItrDel { log, name } => self.itr_del(log, name),
+            IteratorNext(commands::IteratorNext {
+                iterator_name,
+                position,
+                count,
+                intermediate,
+            }) => self.itr_next(iterator_name, position, count, intermediate),
+            IteratorDelete(commands::IteratorDelete {
+                log_name,
+                iterator_name,
+            }) => self.itr_del(log_name, iterator_name),
+            Snapshot(commands::Snapshot { snapshot_name }) => {
+                match self.begin_snapshot(&snapshot_name) {
+                    Ok(snap) => finish_snapshot(snap),
+                    Err(e) => e.into(),
+                }
+            }
+            SnapshotImport(commands::SnapshotImport {
+                snapshot_name,
+                log_names,
+            }) => match self.begin_import(&snapshot_name, log_names) {
+                Ok(import) => match import.copy() {
+                    Ok(copied) => self.install_import(copied),
+                    Err(e) => e.into(),
+                },
+                Err(e) => e.into(),
+            },
+            MessageRedact(commands::MessageRedact {
+                admin_token,
+                log_name,
+                message_id,
+                reason,
+            }) => self.msg_redact(&admin_token, log_name, message_id, reason),
+            RedactionList(commands::RedactionList {
+                admin_token,
+                log_name,
+            }) => self.redaction_list(&admin_token, log_name),
+        }
+    }
+
+    /// List all logs in db
+    fn log_list(&mut self) -> Response {
+        let logs: Vec<String> = self.manifest.logs.keys().cloned().collect();
+        let bytes = serde_cbor::to_vec(&logs).unwrap();
+        Response::Data(vec![bytes])
+    }
+
+    /// Displays information about a log
+    fn log_show(&mut self, name: String) -> Response {
+        let (registrant, log) = match (self.manifest.logs.get(&name), self.logs.get(&name)) {
+            (Some(r), Some(l)) => (r, l),
+            _ => return Error::LogDoesNotExist.into(),
+        };
+
+        let info = serde_cbor::to_vec(&LogInfo {
+            registrant,
+            first_offset: log.first_offset(),
+        })
+        .expect("could not serialize log info");
+        Response::Data(vec![info])
+    }
+
+    /// Adds a new log to the DB
+    fn log_add(&mut self, name: String, options: LogOptions) -> Response {
+        if !options.fsync.is_valid() {
+            return Error::LogOptionsInvalid.into();
+        }
+        // Re-adding an existing Log keeps the options it was created with.
+        if self.manifest.logs.contains_key(&name) {
+            return Response::Info(OK_RESP.into());
+        }
+
+        // The Log is only registered once it has been created, so the
+        // Manifest never lists a Log that isn't there.
+        let log = match self.open_log(&name, options) {
+            Ok(log) => log,
+            Err(e) => {
+                error!("could not create log {}: {}", name, e);
+                return Error::ErrWritingLog.into();
+            }
+        };
+        if let Err(e) = self.manifest.add_log(name.clone(), options) {
+            if let Err(e) = log.destroy() {
+                error!("could not remove files for log {}: {}", name, e);
+            }
+            return e.into();
+        }
+        self.logs.insert(name, log);
+        Response::Info(OK_RESP.into())
+    }
+
+    /// Deletes a log from the DB
+    fn log_delete(&mut self, name: String) -> Response {
+        let itrs: Vec<String> = self
+            .manifest
+            .itrs
+            .values()
+            .filter(|itr| itr.log == name)
+            .map(|itr| itr.name.clone())
+            .collect();
+        if let Entry::Occupied(l) = self.logs.entry(name.clone()) {
+            if let Err(e) = self.manifest.del_log(name.clone()) {
+                return e.into();
+            }
+            let (_, log) = l.remove_entry();
+            self.fail_pending_adds(&name);
+            for itr in itrs {
+                if let Some(Err(e)) = self.indexes.remove(&itr).map(Index::destroy) {
+                    error!("could not remove index of iterator {}: {}", itr, e);
+                }
+            }
+            if let Err(e) = log.destroy() {
+                error!("could not remove files for log {}: {}", name, e);
+                return Error::ErrWritingLog.into();
+            }
+        };
+        Response::Info(OK_RESP.into())
+    }
+
+    /// Syncs Logs whose fsync interval has passed. Returns how long to wait
+    /// before calling it again.
+    pub fn flush_logs(&mut self) -> Duration {
+        let mut wait = MAX_FLUSH_WAIT;
+        for (name, log) in self.segmented_logs_mut() {
+            match log.flush() {
+                Ok(Some(d)) => wait = wait.min(d),
+                Ok(None) => (),
+                Err(e) => error!("could not sync log {}: {}", name, e),
+            }
+        }
+        for (name, index) in self.indexes.iter_mut() {
+            if let Err(e) = index.flush() {
+                error!("could not sync index of iterator {}: {}", name, e);
+            }
+        }
+        wait
+    }
+
+    /// Drops old Segments from every Log according to its Retention limits.
+    pub fn enforce_retention(&mut self) {
+        for (name, log) in self.logs.iter_mut() {
+            match log.enforce_retention() {
+                Ok(0) => (),
+                Ok(n) => info!("dropped {} expired segments from log {}", n, name),
+                Err(e) => error!("could not enforce retention on log {}: {}", name, e),
+            }
+        }
+        for (name, index) in self.indexes.iter_mut() {
+            if let Err(e) = index.enforce_retention() {
+                error!("could not enforce retention on iterator {}: {}", name, e);
+            }
+        }
+    }
+
+    /// The Logs stored in Segment files, which are the only ones with any
+    /// upkeep to do
+    fn segmented_logs(&self) -> impl Iterator<Item = (&String, &dyn Segmented)> + '_ {
+        self.logs
+            .iter()
+            .filter_map(|(name, log)| Some((name, log.segmented()?)))
+    }
+
+    fn segmented_logs_mut(&mut self) -> impl Iterator<Item = (&String, &mut dyn Segmented)> + '_ {
+        self.logs
+            .iter_mut()
+            .filter_map(|(name, log)| Some((name, log.segmented_mut()?)))
+    }
+
+    /// Sealed Segments waiting to be compressed, along with the name of their
+    /// Log and the codec to compress them with.
+    fn uncompressed_segments(&self) -> Vec<(String, Sealed, Compression)> {
+        let mut work = vec![];
+        for (name, log) in self.segmented_logs() {
+            for seg in log.uncompressed_segments() {
+                work.push((name.clone(), seg, log.options().compression));
+            }
+        }
+        work
+    }
+
+    /// Sealed Segments of compacted Logs that are due to be compacted, grouped
+    /// by the name of their Log.
+    fn compaction_due(&self) -> Vec<(String, Vec<Sealed>)> {
+        self.segmented_logs()
+            .map(|(name, log)| (name.clone(), log.compaction_due()))
+            .filter(|(_, segments)| !segments.is_empty())
+            .collect()
+    }
+
+    /// Sealed Segments due to be archived, along with the name of their Log
+    /// and the name to store them as. Fetched copies
+    /// of archived Segments that have stopped being read are evicted first.
+    fn archive_due(&mut self) -> Vec<(String, Sealed, String)> {
+        let after = self.archive_after;
+        let mut work = vec![];
+        for (name, log) in self.segmented_logs_mut() {
+            match log.evict_fetched() {
+                Ok(0) => (),
+                Ok(n) => debug!("evicted {} fetched segments of log {}", n, name),
+                Err(e) => error!("could not evict fetched segments of log {}: {}", name, e),
+            }
+            for (seg, archived_name) in log.archive_due(after) {
+                work.push((name.clone(), seg, archived_name));
+            }
+        }
+        work
+    }
+
+    /// Starts a Snapshot of the Manifest and every Log as they are right now.
+    /// Only the quick part of taking it happens here, so that the DB isn't
+    /// locked for long. The rest is left to `finish_snapshot`.
+    fn begin_snapshot(&self, name: &str) -> Result<Snapshot, Error> {
+        if !snapshot::valid_name(name) {
+            return Err(Error::SnapshotNameInvalid);
+        }
+        if self.storage != StorageBackend::File {
+            error!("snapshots can only be taken of file storage");
+            return Err(Error::ErrWritingSnapshot);
+        }
+
+        let res = Snapshot::begin(&self.snapshots_dir, name).and_then(|mut snap| {
+            let manifest = self.manifest.path();
+            if let (true, Some(file_name)) = (manifest.exists(), manifest.file_name()) {
+                snap.link(manifest, Path::new(file_name))?;
+            }
+            // The audit trail is appended to, so only what is there now is
+            // copied.
+            let audit = self.audit.path();
+            if audit.exists() {
+                let len = audit.metadata()?.len();
+                snap.copy_tail(audit, len, Path::new(audit::AUDIT_FILE))?;
+            }
+            for name in self.manifest.logs.keys() {
+                if let Some(log) = self.logs.get(name).and_then(|l| l.segmented()) {
+                    let dest = PathBuf::from(LOGS_DIR).join(logs::dir_name(name));
+                    log.snapshot(&mut snap, &dest)?;
+                }
+            }
+            Ok(snap)
+        });
+        res.map_err(|e| match e.kind() {
+            io::ErrorKind::AlreadyExists => Error::SnapshotExists,
+            _ => {
+                error!("could not take snapshot {}: {}", name, e);
+                Error::ErrWritingSnapshot
+            }
+        })
+    }
+
+    /// Checks that the Logs in `log_names` can be imported from the Snapshot
+    /// called `name` under their new names. The slow part of importing is
+    /// left to `Import::copy`, and the result is installed with
+    /// `install_import`.
+    fn begin_import(
+        &self,
+        name: &str,
+        log_names: HashMap<String, String>,
+    ) -> Result<Import, Error> {
+        if !snapshot::valid_name(name) {
+            return Err(Error::SnapshotNameInvalid);
+        }
+        let path = self.snapshots_dir.join(name);
+        if !path.exists() {
+            return Err(Error::SnapshotDoesNotExist);
+        }
+
+        // Imported Logs are copied in as Segment files.
+        if self.storage != StorageBackend::File {
+            error!("logs can only be imported into file storage");
+            return Err(Error::ErrWritingLog);
+        }
+
+        let mut new_names = HashSet::new();
+        for new_name in log_names.values() {
+            if self.manifest.logs.contains_key(new_name) || !new_names.insert(new_name) {
+                return Err(Error::LogAlreadyExists);
+            }
+        }
+
+        Ok(Import {
+            path,
+            logs_dir: self.logs_dir.clone(),
+            keyring: self.keyring.clone(),
+            log_names,
+        })
+    }
+
+    /// Adds the Logs copied by `Import::copy` to the DB. Either every Log is
+    /// added or, if any of their names has been taken in the meantime, none
+    /// are.
+    fn install_import(&mut self, copied: Vec<ImportedLog>) -> Response {
+        if copied
+            .iter()
+            .any(|log| self.manifest.logs.contains_key(&log.name))
+        {
+            remove_imported(&copied);
+            return Error::LogAlreadyExists.into();
+        }
+
+        for log in copied {
+            let dir = self.log_dir(&log.name);
+            if let Err(e) = fs::rename(&log.tmp, &dir) {
+                error!("could not import log {}: {}", log.name, e);
+                return Error::ErrWritingLog.into();
+            }
+            if let Err(e) = self.manifest.add_log(log.name.clone(), log.options) {
+                return e.into();
+            }
+
+            match self.open_log(&log.name, log.options) {
+                Ok(opened) => {
+                    info!("imported log {} with {} messages", log.name, opened.len());
+                    self.logs.insert(log.name, opened);
+                }
+                Err(e) => {
+                    error!("could not open imported log {}: {}", log.name, e);
+                    return Error::ErrReadingLog.into();
+                }
+            }
+        }
+        Response::Info(OK_RESP.into())
+    }
+
+    /// Whether `token` is the admin token. Admin requests are always refused
+    /// if no admin token is configured.
+    fn authorized(&self, token: &str) -> bool {
+        let expected = match &self.admin_token {
+            Some(expected) => expected.as_bytes(),
+            None => return false,
+        };
+        // Every byte is compared, so the time taken doesn't give away how much
+        // of the token was right.
+        expected.len() == token.len()
+            && expected
+                .iter()
+                .zip(token.as_bytes())
+                .fold(0, |diff, (a, b)| diff | (a ^ b))
+                == 0
+    }
+
+    /// Replaces a Message with a tombstone and records it in the audit trail
+    fn msg_redact(
+        &mut self,
+        token: &str,
+        log_name: String,
+        message_id: usize,
+        reason: String,
+    ) -> Response {
+        if !self.authorized(token) {
+            return Error::NotAuthorized.into();
+        }
+        let log = match self.logs.get_mut(&log_name) {
+            Some(log) => log,
+            None => return Error::LogDoesNotExist.into(),
+        };
+        if message_id < log.first_offset() {
+            return Error::MsgExpired.into();
+        }
+        let found = log
+            .messages(message_id)
+            .and_then(|mut msgs| msgs.next().transpose());
+        match found {
+            Ok(Some(record)) if record.offset == message_id => (),
+            Ok(_) => return Error::MsgDoesNotExist.into(),
+            // `exec_fetching` runs this again once the Segment is fetched
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                debug!("redaction from log {} stopped: {}", log_name, e);
+                return Error::ErrReadingLog.into();
+            }
+            Err(e) => {
+                error!("could not read {} from log {}: {}", message_id, log_name, e);
+                return Error::ErrReadingLog.into();
+            }
+        }
+
+        // Snapshots hard link sealed Segments, so they keep the original
+        // Message. They are listed in the audit entry to be dealt with.
+        let rel = PathBuf::from(LOGS_DIR).join(logs::dir_name(&log_name));
+        let snapshots = match snapshot::holding(&self.snapshots_dir, &rel) {
+            Ok(snapshots) => snapshots,
+            Err(e) => {
+                error!("could not list snapshots of log {}: {}", log_name, e);
+                return Error::ErrReadingLog.into();
+            }
+        };
+
+        // The audit entry is written first, so a Message is never redacted
+        // without a record of it. A redaction that then fails is left in the
+        // audit trail, and is recorded again when the client retries.
+        let entry = Redaction {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis() as u64)
+                .unwrap_or(0),
+            log_name,
+            message_id,
+            reason,
+            snapshots,
+        };
+        if let Err(e) = self.audit.record(&entry) {
+            error!("could not record redaction {:?}: {}", entry, e);
+            return Error::ErrWritingLog.into();
+        }
+        let log = match self.logs.get_mut(&entry.log_name) {
+            Some(log) => log,
+            None => return Error::LogDoesNotExist.into(),
+        };
+        if let Err(e) = log.redact(message_id) {
+            error!(
+                "could not redact {} from log {} after recording it: {}",
+                message_id, entry.log_name, e
+            );
+            return Error::ErrWritingLog.into();
+        }
+        info!(
+            "redacted message {} from log {}",
+            entry.message_id, entry.log_name
+        );
+        if let Err(e) = self.redact_indexes(&entry.log_name, message_id) {
+            return e.into();
+        }
+        Response::Info(OK_RESP.into())
+    }
+
+    /// Lists the audit trail of redactions, optionally only for one Log
+    fn redaction_list(&self, token: &str, log_name: Option<String>) -> Response {
+        if !self.authorized(token) {
+            return Error::NotAuthorized.into();
+        }
+        let entries = match self.audit.read() {
+            Ok(entries) => entries,
+            Err(e) => {
+                error!("could not read audit trail: {}", e);
+                return Error::ErrReadingLog.into();
+            }
+        };
+
+        let out = entries
+            .iter()
+            .filter(|entry| log_name.as_ref().is_none_or(|name| &entry.log_name == name))
+            .map(|entry| serde_cbor::to_vec(entry).expect("could not serialize redaction"))
+            .collect();
+        Response::Data(out)
+    }
+
+    /// Adds a new message to a log
+    fn msg_add(&mut self, log: String, key: Option<String>, msg: Vec<u8>) -> Response {
+        let l = self.logs.get_mut(&log);
+        if l.is_none() {
+            return Error::LogDoesNotExist.into();
+        }
+
+        match l.unwrap().add_msg(key.as_ref().map(|k| k.as_bytes()), msg) {
+            Ok(_) => {
+                self.update_indexes(&log);
+                Response::Info(OK_RESP.into())
+            }
+            Err(e) => e.into(),
+        }
+    }
+
+    /// Adds a new message to a log like `exec`, except that a Log which syncs
+    /// every Message leaves it to the next group commit. The response is sent
+    /// once `commit_logs` has made the Message durable.
+    pub fn msg_add_grouped(&mut self, add: commands::MessageAdd) -> oneshot::Receiver<Response> {
+        let (done, rx) = oneshot::channel();
+        let commands::MessageAdd {
+            log_name,
+            key,
+            message,
+        } = add;
+        let res = match self.logs.get_mut(&log_name) {
+            Some(log) => {
+                let key = key.as_ref().map(|k| k.as_bytes());
+                match log.segmented_mut() {
+                    Some(log) => log.add_msg_deferred(key, message),
+                    None => log.add_msg(key, message).map(|_| None),
+                }
+            }
+            None => Err(Error::LogDoesNotExist),
+        };
+        // Messages waiting on a group commit are indexed by `commit_logs`.
+        if let Ok(None) = res {
+            self.update_indexes(&log_name);
+        }
+
+        let resp = match res {
+            Ok(Some(offset)) => {
+                self.pending_adds.push(PendingAdd {
+                    log: log_name,
+                    offset,
+                    done,
+                });
+                self.commit_wanted.notify();
+                return rx;
+            }
+            Ok(None) => Response::Info(OK_RESP.into()),
+            Err(e) => e.into(),
+        };
+        let _ = done.send(resp);
+        rx
+    }
+
+    /// Notified whenever a MessageAdd starts waiting on a group commit
+    pub fn commit_wanted(&self) -> Arc<Notify> {
+        self.commit_wanted.clone()
+    }
+
+    /// Starts a group commit for every Log with MessageAdds waiting on one.
+    fn commits_due(&self) -> Vec<(String, io::Result<Commit>)> {
+        let mut names: Vec<&String> = self.pending_adds.iter().map(|a| &a.log).collect();
+        names.sort();
+        names.dedup();
+
+        let mut work = vec![];
+        for name in names {
+            let commit = match self.logs.get(name).and_then(|l| l.segmented()) {
+                Some(log) => log.begin_commit().transpose(),
+                None => None,
+            };
+            if let Some(commit) = commit {
+                work.push((name.clone(), commit));
+            }
+        }
+        work
+    }
+
+    /// Acknowledges every MessageAdd whose Message is now durable.
+    fn release_pending_adds(&mut self) {
+        let mut done = vec![];
+        for add in std::mem::take(&mut self.pending_adds) {
+            match self.logs.get(&add.log).and_then(|l| l.segmented()) {
+                Some(log) if log.synced_len() <= add.offset => self.pending_adds.push(add),
+                _ => done.push(add),
+            }
+        }
+        for add in done {
+            let _ = add.done.send(Response::Info(OK_RESP.into()));
+        }
+    }
+
+    /// Fails every MessageAdd waiting on a group commit of `log`. Their
+    /// Messages are truncated from the Log first, so that a retry doesn't add
+    /// them twice.
+    fn fail_pending_adds(&mut self, log: &str) {
+        if let Some(l) = self.logs.get_mut(log).and_then(|l| l.segmented_mut()) {
+            if let Err(e) = l.discard_unsynced() {
+                error!(
+                    "could not discard unsynced messages from log {}: {}",
+                    log, e
+                );
+            }
+        }
+        let (failed, pending) = std::mem::take(&mut self.pending_adds)
+            .into_iter()
+            .partition(|add| add.log == log);
+        self.pending_adds = pending;
+        for add in failed {
+            let _ = add.done.send(Error::ErrWritingLog.into());
+        }
+    }
+
+    /// List all itrs attached to a log
+    fn itr_list(&mut self, name: Option<String>) -> Response {
+        let itrs = &self.manifest.itrs;
+        let out: Vec<Vec<u8>> = match name {
+            Some(name) => itrs
+                .iter()
+                .filter(|(_, itr)| itr.log == name)
+                .map(|(_, x)| serde_cbor::to_vec(&x.name).unwrap())
+                .collect(),
+            None => itrs
+                .keys()
+                .map(|x| serde_cbor::to_vec(x).unwrap())
+                .collect(),
+        };
+        Response::Data(out)
+    }
+
+    /// Adds a new iterator to a log. An indexed iterator is run over every
+    /// message already in what it reads from before it is added, and isn't
+    /// added if it fails on any of them. Unlike the `itr_add` function, the
+    /// DB stays locked throughout.
+    fn itr_add(&mut self, itr: Itr) -> Response {
+        let mut backfill = match self.begin_itr_add(itr) {
+            Ok(Some(backfill)) => backfill,
+            Ok(None) => return Response::Info(OK_RESP.into()),
+            Err(e) => return e.into(),
+        };
+        let res = loop {
+            match self.backfill(&mut backfill) {
+                Ok(true) => break Ok(()),
+                Ok(false) => (),
+                Err(e) => break Err(e),
+            }
+        };
+        self.finish_itr_add(backfill, res)
+    }
+
+    /// Registers `itr`, and returns the empty Index to backfill if it is
+    /// indexed. Adding an Iterator again rebuilds its Index if it has failed.
+    fn begin_itr_add(&mut self, itr: Itr) -> Result<Option<Backfill>, Error> {
+        let (name, indexed) = (itr.name.clone(), itr.indexed);
+        if self.backfills.contains_key(&name) {
+            return match self.manifest.itrs.get(&name) {
+                Some(stored) if *stored == itr => Ok(None),
+                _ => Err(Error::ItrExistsWithSameName),
+            };
+        }
+        self.manifest.add_itr(itr)?;
+
+        let rebuild = self.indexes.get(&name).and_then(Index::failed).is_some();
+        if rebuild {
+            info!("rebuilding index of iterator {}", name);
+            if let Some(Err(e)) = self.indexes.remove(&name).map(Index::destroy) {
+                error!("could not remove index of iterator {}: {}", name, e);
+                return Err(Error::ErrWritingLog);
+            }
+        } else if !indexed || self.indexes.contains_key(&name) {
+            return Ok(None);
+        }
+
+        let index = match self.open_itr_index(&name) {
+            Ok(index) => index,
+            Err(e) if rebuild => return Err(e),
+            Err(e) => {
+                let log = self.manifest.itrs[&name].log.clone();
+                self.manifest.del_itr(log, name)?;
+                return Err(e);
+            }
+        };
+        self.backfills.insert(name.clone(), vec![]);
+        Ok(Some(Backfill {
+            name,
+            index,
+            rebuild,
+        }))
+    }
+
+    /// Runs the Iterator being added over its next batch of Messages.
+    /// Returns whether its Index has caught up.
+    fn backfill(&self, backfill: &mut Backfill) -> Result<bool, Error> {
+        let itr = match self.manifest.itrs.get(&backfill.name) {
+            Some(itr) => itr,
+            None => return Err(Error::ItrDoesNotExist),
+        };
+        self.with_source(itr, |source| backfill.index.catch_up_batch(itr, source))
+    }
+
+    /// Adds the Index of an Iterator once its backfill has finished with
+    /// `res`. A new Iterator whose backfill failed is removed again, unless it
+    /// has been deleted already, but a rebuilt Index is kept along with its
+    /// failure. Messages redacted during the backfill are redacted from the
+    /// Index first.
+    fn finish_itr_add(&mut self, backfill: Backfill, res: Result<(), Error>) -> Response {
+        let Backfill {
+            name,
+            mut index,
+            rebuild,
+        } = backfill;
+        let mut redacted = true;
+        for offset in self.backfills.remove(&name).unwrap_or_default() {
+            if let Err(e) = index.redact(offset) {
+                error!(
+                    "could not redact {} from index of iterator {}: {}",
+                    offset, name, e
+                );
+                redacted = false;
+            }
+        }
+        let res = res.and(if redacted {
+            Ok(())
+        } else {
+            Err(Error::ErrWritingLog)
+        });
+
+        let log = self.manifest.itrs.get(&name).map(|itr| itr.log.clone());
+        match (res, log) {
+            (Ok(()), Some(_)) => {
+                self.indexes.insert(name, index);
+                Response::Info(OK_RESP.into())
+            }
+            (Err(e), Some(_)) if rebuild && redacted => {
+                self.indexes.insert(name, index);
+                e.into()
+            }
+            (res, log) => {
+                if let Err(e) = index.destroy() {
+                    error!("could not remove index of iterator {}: {}", name, e);
+                }
+                if let (Some(log), false) = (log, rebuild) {
+                    if let Err(e) = self.manifest.del_itr(log, name) {
+                        return e.into();
+                    }
+                }
+                res.err().unwrap_or(Error::ItrDoesNotExist).into()
+            }
+        }
+    }
+
+    // Delets an unused iterator to a log, along with its index
+    fn itr_del(&mut self, log: String, name: String) -> Response {
+        if let Err(e) = self.manifest.del_itr(log, name.clone()) {
+            return e.into();
+        }
+        if let Some(Err(e)) = self.indexes.remove(&name).map(Index::destroy) {
+            error!("could not remove index of iterator {}: {}", name, e);
+            return Error::ErrWritingLog.into();
+        }
+        Response::Info(OK_RESP.into())
+    }
+
+    fn itr_next(
+        &mut self,
+        name: String,
+        position: Position,
+        count: usize,
+        intermediate: bool,
+    ) -> Response {
+        let (log, indexed) = match self.manifest.itrs.get(&name) {
+            Some(itr) => (itr.log.clone(), itr.indexed),
+            None => return Error::ItrDoesNotExist.into(),
+        };
+
+        // Indexes are caught up as Messages are added, but one whose Iterator
+        // failed on a Message stops there, and fails every read until it is
+        // rebuilt.
+        self.update_indexes(&log);
+        if let Some(e) = self.index_failure(&name) {
+            return e.into();
+        }
+        if indexed {
+            if let Err(e) = self.catch_up_index(&name) {
+                return e.into();
+            }
+        }
+        let itr = &self.manifest.itrs[&name];
+
+        let res = self.with_source(itr, |source| match (itr.indexed, self.indexes.get(&name)) {
+            (true, Some(index)) => index.next(itr, source, position, count),
+            (true, None) => Err(Error::ErrReadingLog),
+            (false, _) => itr.next(source, position, count, intermediate),
+        });
+        match res {
+            Ok((cursor, results)) => {
+                let mut page = Vec::with_capacity(results.len() + 1);
+                page.push(serde_cbor::to_vec(&cursor).expect("could not marshal cursor"));
+                page.extend(results);
+                Response::Data(page)
+            }
+            Err(e) => e.into(),
+        }
+    }
+}
+
+/// The Log's Manifest entry along with its current state, as shown by LogShow
+#[derive(Debug, Serialize)]
+struct LogInfo<'a> {
+    #[serde(flatten)]
+    registrant: &'a LogRegistrant,
+    first_offset: usize,
+}
+
+/// Where a finished Snapshot was written, as returned by Snapshot
+#[derive(Debug, Serialize)]
+struct SnapshotInfo {
+    path: PathBuf,
+}
+
+/// Takes a Snapshot of the whole DB called `name`. The DB is only locked while
+/// the Snapshot is started, so Messages can keep being added while the rest of
+/// it is copied.
+pub fn snapshot(db: &Mutex<DB>, name: &str) -> Response {
+    let snap = match db.lock().unwrap().begin_snapshot(name) {
+        Ok(snap) => snap,
+        Err(e) => return e.into(),
+    };
+    finish_snapshot(snap)
+}
+
+fn finish_snapshot(snap: Snapshot) -> Response {
+    match snap.finish() {
+        Ok(path) => {
+            info!("wrote snapshot {:?}", path);
+            let info = serde_cbor::to_vec(&SnapshotInfo { path })
+                .expect("could not serialize snapshot info");
+            Response::Data(vec![info])
+        }
+        Err(e) => {
+            error!("could not finish snapshot: {}", e);
+            Error::ErrWritingSnapshot.into()
+        }
+    }
+}
+
+/// Runs `cmd`, which may read from archived Segments. Fetching them can be
+/// slow, so a read that reaches one that hasn't been fetched stops instead,
+/// and `cmd` is run again once it has been fetched without the DB locked.
+pub fn exec_fetching(db: &Mutex<DB>, cmd: Command) -> Response {
+    loop {
+        let fetches = {
+            let mut db = db.lock().unwrap();
+            let resp = db.exec(cmd.clone());
+            let fetches: Vec<Fetch> = db
+                .segmented_logs()
+                .flat_map(|(_, l)| l.pending_fetches())
+                .collect();
+            if fetches.is_empty() {
+                return resp;
+            }
+            fetches
+        };
+        for fetch in fetches {
+            if let Err(e) = fetch.run() {
+                error!("could not fetch archived segment: {}", e);
+                return Error::ErrReadingLog.into();
+            }
+        }
+    }
+}
+
+/// Adds an Iterator. The Index of an Indexed Iterator is backfilled over what
+/// it reads from a batch at a time, so the DB is only locked for one batch at
+/// once. Archived Segments the backfill reaches are fetched without the DB
+/// locked, and the backfill carries on from where it stopped. The Iterator
+/// can't be read from until its Index has caught up.
+pub fn itr_add(db: &Mutex<DB>, add: commands::IteratorAdd) -> Response {
+    let mut backfill = match db.lock().unwrap().begin_itr_add(add.into()) {
+        Ok(Some(backfill)) => backfill,
+        Ok(None) => return Response::Info(OK_RESP.into()),
+        Err(e) => return e.into(),
+    };
+    loop {
+        let fetches = {
+            let mut db = db.lock().unwrap();
+            let res = db.backfill(&mut backfill);
+            let fetches: Vec<Fetch> = db
+                .segmented_logs()
+                .flat_map(|(_, l)| l.pending_fetches())
+                .collect();
+            match res {
+                Ok(true) => return db.finish_itr_add(backfill, Ok(())),
+                Err(e) if fetches.is_empty() => return db.finish_itr_add(backfill, Err(e)),
+                _ => fetches,
+            }
+        };
+        for fetch in fetches {
+            if let Err(e) = fetch.run() {
+                error!("could not fetch archived segment: {}", e);
+                let res = Err(Error::ErrReadingLog);
+                return db.lock().unwrap().finish_itr_add(backfill, res);
+            }
+        }
+    }
+}
+
+/// Copies Logs out of a Snapshot to import them under new names. Imported Logs
+/// are copied into temporary directories next to the DB's Logs, which are
+/// cleaned up when the DB is next opened if they are never installed.
+#[derive(Debug)]
+struct Import {
+    path: PathBuf,
+    logs_dir: PathBuf,
+    keyring: Arc<Keyring>,
+    log_names: HashMap<String, String>,
+}
+
+/// A Log copied out of a Snapshot, waiting to be installed as `name`
+#[derive(Debug)]
+struct ImportedLog {
+    name: String,
+    options: LogOptions,
+    tmp: PathBuf,
+}
+
+impl Import {
+    /// Verifies the Snapshot and copies the Logs to import out of it.
+    fn copy(self) -> Result<Vec<ImportedLog>, Error> {
+        let manifest = snapshot::verify(&self.path)
+            .and_then(|_| Manifest::open(&self.path, self.keyring.clone()))
+            .map_err(|e| match e.kind() {
+                io::ErrorKind::NotFound => Error::SnapshotDoesNotExist,
+                _ => {
+                    error!("could not import from snapshot {:?}: {}", self.path, e);
+                    Error::SnapshotCorrupted
+                }
+            })?;
+
+        let mut copied = vec![];
+        for (old_name, name) in self.log_names {
+            let options = match manifest.logs.get(&old_name) {
+                Some(reg) => reg.options,
+                None => {
+                    remove_imported(&copied);
+                    return Err(Error::LogDoesNotExist);
+                }
+            };
+
+            let src = self.path.join(LOGS_DIR).join(logs::dir_name(&old_name));
+            let tmp = self
+                .logs_dir
+                .join(format!("{}.{}", logs::dir_name(&name), IMPORT_EXT));
+            let log = ImportedLog { name, options, tmp };
+            let res = fs::remove_dir_all(&log.tmp)
+                .or_else(|e| match e.kind() {
+                    io::ErrorKind::NotFound => Ok(()),
+                    _ => Err(e),
+                })
+                .and_then(|_| snapshot::copy_tree(&src, &log.tmp));
+            copied.push(log);
+            if let Err(e) = res {
+                error!(
+                    "could not copy log {} out of {:?}: {}",
+                    old_name, self.path, e
+                );
+                remove_imported(&copied);
+                return Err(Error::ErrWritingLog);
+            }
+        }
+        Ok(copied)
+    }
+}
+
+fn remove_imported(copied: &[ImportedLog]) {
+    for log in copied {
+        if let Err(e) = fs::remove_dir_all(&log.tmp) {
+            error!("could not remove imported copy {:?}: {}", log.tmp, e);
+        }
+    }
+}
+
+/// Imports Logs from a Snapshot under new names. Like Snapshot, the DB is only
+/// locked to check the request and to install the copied Logs.
+pub fn import(db: &Mutex<DB>, name: &str, log_names: HashMap<String, String>) -> Response {
+    let import = match db.lock().unwrap().begin_import(name, log_names) {
+        Ok(import) => import,
+        Err(e) => return e.into(),
+    };
+    match import.copy() {
+        Ok(copied) => db.lock().unwrap().install_import(copied),
+        Err(e) => e.into(),
+    }
+}
+
+/// Fills the configured data directory from the Snapshot at `from`, before
+/// the DB is opened. The Snapshot's checksums and Manifest are checked first,
+/// and a data directory that already has a Manifest is never overwritten.
+pub fn restore(cfg: &RemitsConfig, from: &Path) -> io::Result<()> {
+    let data_dir = cfg.data_dir();
+    let manifest_path = data_dir.join(manifest::MANIFEST_FILE);
+    if manifest_path.exists() {
+        let msg = format!("data directory {:?} already has a manifest", data_dir);
+        return Err(io::Error::new(io::ErrorKind::AlreadyExists, msg));
+    }
+
+    snapshot::verify(from)?;
+    let manifest = Manifest::open(from, load_keyring(cfg)?)?;
+    for name in manifest.logs.keys() {
+        if !from.join(LOGS_DIR).join(logs::dir_name(name)).is_dir() {
+            let msg = format!("log {} is missing from snapshot {:?}", name, from);
+            return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+        }
+    }
+
+    // Any Logs already here aren't in a Manifest, so they are moved out of
+    // the way just as they would be when the DB is opened.
+    let logs_dir = data_dir.join(LOGS_DIR);
+    if logs_dir.exists() {
+        for entry in fs::read_dir(&logs_dir)? {
+            let path = entry?.path();
+            let dest = quarantine(&data_dir, &path)?;
+            warn!("moved files for unregistered log {:?} to {:?}", path, dest);
+        }
+    }
+    if from.join(LOGS_DIR).exists() {
+        snapshot::copy_tree(&from.join(LOGS_DIR), &logs_dir)?;
+    }
+
+    let audit = from.join(audit::AUDIT_FILE);
+    if audit.exists() {
+        let dest = data_dir.join(audit::AUDIT_FILE);
+        fs::copy(&audit, &dest)?;
+        fs::File::open(&dest)?.sync_all()?;
+    }
+
+    // The Manifest goes last, so an interrupted restore can be retried.
+    let src = from.join(manifest::MANIFEST_FILE);
+    if src.exists() {
+        let tmp = manifest_path.with_extension("tmp");
+        fs::copy(&src, &tmp)?;
+        fs::File::open(&tmp)?.sync_all()?;
+        fs::rename(&tmp, &manifest_path)?;
+        fs::File::open(&data_dir)?.sync_all()?;
+    }
+    info!(
+        "restored {} logs from snapshot {:?}",
+        manifest.logs.len(),
+        from
+    );
+    Ok(())
+}
+
+/// Moves `path` into the lost+found directory of `data_dir`, returning where
+/// it was moved to. Anything already there is kept.
+fn quarantine(data_dir: &Path, path: &Path) -> io::Result<PathBuf> {
+    let lost_found = data_dir.join(LOST_FOUND_DIR);
+    fs::create_dir_all(&lost_found)?;
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("could not get system time")
+        .as_secs();
+    let mut dest = lost_found.join(format!("{}.{}", file_name, now));
+    let mut n = 1;
+    while dest.exists() {
+        dest = lost_found.join(format!("{}.{}.{}", file_name, now, n));
+        n += 1;
+    }
+    fs::rename(path, &dest)?;
+    fs::File::open(&lost_found)?.sync_all()?;
+    Ok(dest)
+}
+
+fn load_keyring(cfg: &RemitsConfig) -> io::Result<Arc<Keyring>> {
+    let keyring = match &cfg.encryption_key_path {
+        Some(path) => Keyring::load(path)?,
+        None => Keyring::default(),
+    };
+    if cfg.migrate_plaintext == Some(true) {
+        return Ok(Arc::new(keyring.migrating()));
+    }
+    Ok(Arc::new(keyring))
+}
+
+/// Syncs every Log with MessageAdds waiting on a group commit, then
+/// acknowledges them, so that concurrent appends share a single fsync. The DB
+/// is unlocked while the Logs are synced, and Messages appended in the meantime
+/// wait for the next commit.
+pub fn commit_logs(db: &Mutex<DB>) {
+    let work = db.lock().unwrap().commits_due();
+    let mut synced = vec![];
+    let mut failed = vec![];
+    for (name, commit) in work {
+        match commit.and_then(|c| c.sync().map(|_| c)) {
+            Ok(commit) => synced.push((name, commit)),
+            Err(e) => {
+                error!("could not commit log {}: {}", name, e);
+                failed.push(name);
+            }
+        }
+    }
+
+    let mut db = db.lock().unwrap();
+    let mut committed = vec![];
+    for (name, commit) in synced {
+        if let Some(log) = db.logs.get_mut(&name).and_then(|l| l.segmented_mut()) {
+            log.finish_commit(commit);
+            committed.push(name);
+        }
+    }
+    for name in failed {
+        db.fail_pending_adds(&name);
+    }
+    db.release_pending_adds();
+    // Indexes only see durable Messages, so they catch up once the commit
+    // has made them so.
+    for name in committed {
+        db.update_indexes(&name);
+    }
+}
+
+/// Compresses the sealed Segments of every Log that has Compression set.
+/// Compressing is slow, so the DB is only locked to find the work to do and to
+/// swap in each compressed Segment once it has been written.
+pub fn compress_segments(db: &Mutex<DB>) {
+    let (work, keyring) = {
+        let db = db.lock().unwrap();
+        (db.uncompressed_segments(), db.keyring.clone())
+    };
+    for (name, seg, codec) in work {
+        let compressed = match segment::compress(&seg.path, codec, &keyring) {
+            Ok(compressed) => compressed,
+            Err(e) => {
+                error!("could not compress segment {:?}: {}", seg.path, e);
+                continue;
+            }
+        };
+
+        let mut db = db.lock().unwrap();
+        let res = match db.logs.get_mut(&name).and_then(|l| l.segmented_mut()) {
+            Some(log) => log.install_rewritten(&seg, compressed),
+            None => fs::remove_file(compressed),
+        };
+        if let Err(e) = res {
+            error!("could not install compressed segment {:?}: {}", seg.path, e);
+        }
+    }
+}
+
+/// Compacts the sealed Segments of every compacted Log. Like compression, the
+/// DB is only locked to find the work to do and to swap in each rewritten
+/// Segment.
+pub fn compact_logs(db: &Mutex<DB>) {
+    let (work, keyring) = {
+        let db = db.lock().unwrap();
+        (db.compaction_due(), db.keyring.clone())
+    };
+    for (name, segments) in work {
+        let end = match segments.last() {
+            Some(last) => last.base_offset + 1,
+            None => continue,
+        };
+        let rewritten = match compaction::compact(&segments, &keyring) {
+            Ok(rewritten) => rewritten,
+            Err(e) => {
+                error!("could not compact log {}: {}", name, e);
+                continue;
+            }
+        };
+
+        let mut db = db.lock().unwrap();
+        let log = match db.logs.get_mut(&name).and_then(|l| l.segmented_mut()) {
+            Some(log) => log,
+            None => continue,
+        };
+        for (source, path) in rewritten {
+            if let Err(e) = log.install_rewritten(&source, path) {
+                error!(
+                    "could not install compacted segment {:?}: {}",
+                    source.path, e
+                );
+            }
+        }
+        log.set_compacted_through(end);
+    }
+}
+
+/// Moves cold Segments of every Log to the Archive, if there is one. Copying
+/// can be slow, so the DB is only locked to find the work to do and to swap in
+/// each archived Segment once it is stored.
+pub fn archive_segments(db: &Mutex<DB>) {
+    let (work, archive) = {
+        let mut db = db.lock().unwrap();
+        let archive = match &db.archive {
+            Some(archive) => archive.clone(),
+            None => return,
+        };
+        (db.archive_due(), archive)
+    };
+    for (name, seg, archived_name) in work {
+        if let Err(e) = archive.put(&archived_name, &seg.path) {
+            error!("could not archive segment {:?}: {}", seg.path, e);
+            continue;
+        }
+
+        let mut db = db.lock().unwrap();
+        let res = match db.logs.get_mut(&name).and_then(|l| l.segmented_mut()) {
+            Some(log) => log.install_archived(&seg, archived_name),
+            None => archive.delete(&archived_name),
+        };
+        if let Err(e) = res {
+            error!("could not install archived segment {:?}: {}", seg.path, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::{Anchor, FsyncPolicy};
+    use iters::{Cursor, FilterMatch};
+    use std::time::SystemTime;
+    use tempfile::TempDir;
+
+    fn test_db() -> (TempDir, DB) {
+        let dir = tempfile::tempdir().expect("could not create temp dir");
+        let cfg = RemitsConfig {
+            data_dir: Some(dir.path().into()),
+            ..Default::default()
+        };
+        let db = DB::new(&cfg).expect("could not open db");
+        (dir, db)
+    }
+
+    /// Opens a DB in `dir` with the rest of its config from `cfg`, holding a
+    /// Log called "test" with five Messages over three Segments and a Map
+    /// Iterator "i" over it.
+    fn segmented_db(dir: &Path, cfg: RemitsConfig) -> (RemitsConfig, Mutex<DB>) {
+        let cfg = RemitsConfig {
+            data_dir: Some(dir.join("data")),
+            segment_size: Some(54),
+            ..cfg
+        };
+        let db = Mutex::new(DB::new(&cfg).unwrap());
+        {
+            let mut db = db.lock().unwrap();
+            db.log_add("test".into(), LogOptions::default());
+            db.itr_add(Itr::new(
+                "test".into(),
+                "i".into(),
+                "map".into(),
+                "return 1".into(),
+            ));
+            for i in 0..5u8 {
+                db.msg_add("test".into(), None, vec![0x19, 0x03, i]);
+            }
+        }
+        (cfg, db)
+    }
+
+    #[test]
+    fn test_db_log_list() {
+        let (_dir, mut db) = test_db();
+        db.log_add("metric".into(), LogOptions::default());
+        db.log_add("test".into(), LogOptions::default());
+
+        let resp = db.log_list();
+        match resp {
+            Response::Data(bytes) => {
+                let out: Vec<String> = serde_cbor::from_slice(&*(bytes[0])).unwrap();
+                let l1 = "test".to_owned();
+                let l2 = "metric".to_owned();
+
+                assert!(out[0] == l1 || out[1] == l1);
+                assert!(out[1] == l2 || out[0] == l2);
+            }
+            _ => panic!("error returned from log list"),
+        };
+    }
+
+    #[test]
+    fn test_db_log_show() {
+        let (_dir, mut db) = test_db();
+        db.log_add("test".into(), LogOptions::default());
+        let resp = db.log_show("test".into());
+
+        let log = serde_cbor::to_vec(&LogInfo {
+            registrant: &LogRegistrant {
+                name: "test".into(),
+                created_at: SystemTime::now()
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .expect("could not get system time")
+                    .as_secs() as usize,
+                options: LogOptions::default(),
+            },
+            first_offset: 0,
+        })
+        .expect("could not marshal comparison LogInfo");
+
+        match resp {
+            Response::Data(bytes) => assert_eq!(*bytes[0], *log),
+            Response::Error(e) => panic!("error returned from log show: {:#?}", e),
+            Response::Info(i) => panic!("info returned from log show: {:#?}", i),
+        }
+    }
+
+    #[test]
+    fn test_db_log_show_dne() {
+        let (_dir, mut db) = test_db();
+        match db.log_show("test".into()) {
+            Response::Error(e) => assert_eq!(e, Error::LogDoesNotExist),
+            _ => panic!("expected log show to return an error"),
+        }
+    }
+
+    #[test]
+    fn test_db_retention() {
+        let dir = tempfile::tempdir().expect("could not create temp dir");
+        let cfg = RemitsConfig {
+            data_dir: Some(dir.path().into()),
+            segment_size: Some(54),
+            ..Default::default()
+        };
+        let mut db = DB::new(&cfg).unwrap();
+        let options = LogOptions {
+            retention: commands::Retention {
+                max_messages: Some(2),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        db.log_add("test".into(), options);
+        db.itr_add(Itr::new(
+            "test".into(),
+            "i".into(),
+            "map".into(),
+            "return msg".into(),
+        ));
+        for i in 0..6u8 {
+            db.msg_add("test".into(), None, vec![0x19, 0x03, i]);
+        }
+
+        db.enforce_retention();
+        match db.log_show("test".into()) {
+            Response::Data(bytes) => {
+                let info: serde_cbor::Value = serde_cbor::from_slice(&bytes[0]).unwrap();
+                let first = serde_cbor::Value::Text("first_offset".into());
+                match info {
+                    serde_cbor::Value::Map(m) => {
+                        assert_eq!(m[&first], serde_cbor::Value::Integer(4))
+                    }
+                    _ => panic!("expected log info to be a map"),
+                }
+            }
+            _ => panic!("expected log show to return data"),
+        };
+
+        match db.itr_next("i".into(), Position::Offset(2), 1, false) {
+            Response::Error(e) => assert_eq!(e, Error::MsgExpired),
+            _ => panic!("expected reading an expired message to error"),
+        };
+        match db.itr_next("i".into(), Position::Offset(4), 2, false) {
+            Response::Data(d) => assert_eq!(d.len(), 3),
+            _ => panic!("expected itr next to return data"),
+        };
+    }
+
+    fn page(resp: Response) -> (Cursor, Vec<Vec<u8>>) {
+        match resp {
+            Response::Data(mut d) => {
+                let cursor = serde_cbor::from_slice(&d.remove(0)).unwrap();
+                (cursor, d)
+            }
+            Response::Error(e) => panic!("error returned from itr next: {:?}", e),
+            Response::Info(i) => panic!("info returned from itr next: {:?}", i),
+        }
+    }
+
+    #[test]
+    fn test_db_itr_next_pages() {
+        let (_dir, mut db) = test_db();
+        db.log_add("test".into(), LogOptions::default());
+        db.itr_add(Itr::new(
+            "test".into(),
+            "i".into(),
+            "map".into(),
+            "return msg".into(),
+        ));
+        for i in 0..3u8 {
+            db.msg_add("test".into(), None, vec![0x19, 0x03, i]);
+        }
+
+        let (cursor, results) = page(db.itr_next("i".into(), Position::Offset(0), 2, false));
+        assert_eq!(results, vec![vec![0x19, 0x03, 0], vec![0x19, 0x03, 1]]);
+        assert_eq!(
+            cursor,
+            Cursor {
+                next_offset: 2,
+                end_of_log: false,
+                high_water_mark: 3
+            }
+        );
+
+        // Asking for more than is left stops at the end of the Log
+        let (cursor, results) =
+            page(db.itr_next("i".into(), Position::Offset(2), usize::MAX, false));
+        assert_eq!(results, vec![vec![0x19, 0x03, 2]]);
+        assert_eq!(
+            cursor,
+            Cursor {
+                next_offset: 3,
+                end_of_log: true,
+                high_water_mark: 3
+            }
+        );
+
+        let (cursor, results) = page(db.itr_next("i".into(), Position::Offset(10), 5, false));
+        assert!(results.is_empty());
+        assert_eq!(cursor.next_offset, 10);
+        assert!(cursor.end_of_log);
+    }
+
+    #[test]
+    fn test_db_itr_filter() {
+        let (_dir, mut db) = test_db();
+        db.log_add("test".into(), LogOptions::default());
+        let func = "if msg % 3 == 0 then return true elseif msg % 3 == 1 then return nil end";
+        db.itr_add(Itr::new(
+            "test".into(),
+            "i".into(),
+            "filter".into(),
+            func.into(),
+        ));
+        for i in 0..10u8 {
+            db.msg_add("test".into(), None, vec![0x18, i]);
         }
+
+        // Messages are scanned until `count` of them are kept
+        let (cursor, results) = page(db.itr_next("i".into(), Position::Offset(1), 2, false));
+        let matches: Vec<FilterMatch> = results
+            .iter()
+            .map(|r| serde_cbor::from_slice(r).unwrap())
+            .collect();
+        let expected = |offset: usize| FilterMatch {
+            offset,
+            message: serde_cbor::Value::Integer(offset as i128),
+        };
+        assert_eq!(matches, vec![expected(3), expected(6)]);
+        assert_eq!(cursor.next_offset, 7);
+        assert!(!cursor.end_of_log);
+
+        // Running out of Messages ends the page early
+        let (cursor, results) = page(db.itr_next("i".into(), Position::Offset(7), 5, false));
+        assert_eq!(results.len(), 1);
+        assert_eq!(cursor.next_offset, 10);
+        assert!(cursor.end_of_log);
     }
 
-    pub fn exec(&mut self, cmd: Command) -> Response {
-        use Command::*;
+    #[test]
+    fn test_db_itr_reduce() {
+        let (_dir, mut db) = test_db();
+        db.log_add("test".into(), LogOptions::default());
+        let initial = Some(serde_cbor::Value::Integer(10));
+        let func = "return acc + msg";
+        db.itr_add(Itr {
+            initial,
+            ..Itr::new("test".into(), "i".into(), "reduce".into(), func.into())
+        });
+        for i in 0..5u8 {
+            db.msg_add("test".into(), None, vec![i]);
+        }
 
-        match cmd {
-            LogShow(commands::LogShow { log_name }) => self.log_show(log_name),
-            LogAdd(commands::LogAdd { log_name }) => self.log_add(log_name),
-            LogDelete(commands::LogDelete { log_name }) => self.log_delete(log_name),
-            LogList => self.log_list(),
-            IteratorList(commands::IteratorList { log_name }) => self.itr_list(log_name),
-            MessageAdd(commands::MessageAdd { log_name, message }) => {
-                self.msg_add(log_name, message)
-            }
-            IteratorAdd(commands::IteratorAdd {
-                log_name,
-                iterator_name,
-                iterator_kind,
-                iterator_func,
-            }) => self.itr_add(log_name, iterator_name, iterator_kind, iterator_func),
-            //ItrDel { log, name } => self.itr_del(log, name),
-            IteratorNext(commands::IteratorNext {
-                iterator_name,
-                message_id,
-                count,
-            }) => self.itr_next(iterator_name, message_id, count),
-            IteratorDelete(commands::IteratorDelete {
-                log_name,
-                iterator_name,
-            }) => self.itr_del(log_name, iterator_name),
+        // The page holds only the final value of the accumulator
+        let (cursor, results) = page(db.itr_next("i".into(), Position::Offset(1), 3, false));
+        assert_eq!(results, vec![serde_cbor::to_vec(&16).unwrap()]);
+        assert_eq!(cursor.next_offset, 4);
+        assert!(!cursor.end_of_log);
+
+        // Or every value it took on along the way
+        let (_, results) = page(db.itr_next("i".into(), Position::Offset(1), 3, true));
+        let expected: Vec<Vec<u8>> = [11, 13, 16]
+            .iter()
+            .map(|v| serde_cbor::to_vec(v).unwrap())
+            .collect();
+        assert_eq!(results, expected);
+
+        // A page with no Messages in it has nothing to reduce
+        let (cursor, results) = page(db.itr_next("i".into(), Position::Offset(5), 3, false));
+        assert!(results.is_empty());
+        assert!(cursor.end_of_log);
+
+        // Without an initial value the accumulator starts out nil
+        let func = "return (acc or 0) + 1";
+        db.itr_add(Itr::new(
+            "test".into(),
+            "count".into(),
+            "reduce".into(),
+            func.into(),
+        ));
+        let (_, results) = page(db.itr_next("count".into(), Position::Offset(0), 10, false));
+        assert_eq!(results, vec![serde_cbor::to_vec(&5).unwrap()]);
+    }
+
+    #[test]
+    fn test_db_itr_indexed() {
+        let dir = tempfile::tempdir().expect("could not create temp dir");
+        let cfg = RemitsConfig {
+            data_dir: Some(dir.path().into()),
+            admin_token: Some("secret".into()),
+            ..Default::default()
+        };
+        let mut db = DB::new(&cfg).unwrap();
+        db.log_add("test".into(), LogOptions::default());
+        for i in 0..4u8 {
+            db.msg_add("test".into(), None, vec![i]);
+        }
+
+        // Messages already in the Log are indexed when the Iterator is added,
+        // and later ones as they are added
+        let func = "return msg % 2 == 0";
+        db.itr_add(Itr {
+            indexed: true,
+            ..Itr::new("test".into(), "evens".into(), "filter".into(), func.into())
+        });
+        for i in 4..6u8 {
+            db.msg_add("test".into(), None, vec![i]);
+        }
+        let index_dir = dir.path().join(INDEXES_DIR).join(logs::dir_name("evens"));
+        assert!(index_dir.is_dir());
+
+        let evens = |db: &mut DB| -> Vec<usize> {
+            let (_, results) = page(db.itr_next("evens".into(), Position::Offset(1), 10, false));
+            results
+                .iter()
+                .map(|r| serde_cbor::from_slice::<FilterMatch>(r).unwrap().offset)
+                .collect()
+        };
+        assert_eq!(evens(&mut db), vec![2, 4]);
+
+        // Indexes are kept when the DB is reopened
+        drop(db);
+        let mut db = DB::new(&cfg).unwrap();
+        db.msg_add("test".into(), None, vec![6]);
+        assert_eq!(evens(&mut db), vec![2, 4, 6]);
+
+        // Redacting a Message removes what was stored for it
+        db.exec(Command::MessageRedact(commands::MessageRedact {
+            admin_token: "secret".into(),
+            log_name: "test".into(),
+            message_id: 2,
+            reason: "erasure request".into(),
+        }));
+        assert_eq!(evens(&mut db), vec![4, 6]);
+
+        // An Iterator that fails on a Message already in the Log isn't added
+        let resp = db.itr_add(Itr {
+            indexed: true,
+            ..Itr::new(
+                "test".into(),
+                "bad".into(),
+                "map".into(),
+                "return msg.x".into(),
+            )
+        });
+        assert!(matches!(resp, Response::Error(Error::ErrRunningLua)));
+        assert!(!db.manifest.itrs.contains_key("bad"));
+
+        // One that fails on a later Message stops there, along with every
+        // Iterator that reads from it, until it is added again
+        let picky = || Itr {
+            indexed: true,
+            ..Itr::new(
+                "test".into(),
+                "picky".into(),
+                "map".into(),
+                "if msg == 8 then error('bad') end return msg".into(),
+            )
+        };
+        assert!(matches!(db.itr_add(picky()), Response::Info(_)));
+        db.itr_add(Itr {
+            source: Some("picky".into()),
+            ..Itr::new(
+                "test".into(),
+                "over".into(),
+                "map".into(),
+                "return msg".into(),
+            )
+        });
+        db.msg_add("test".into(), None, vec![8]);
+        db.msg_add("test".into(), None, vec![9]);
+        assert_eq!(db.indexes["picky"].next_offset(), 7);
+        for name in ["picky", "over"].iter() {
+            let resp = db.itr_next(name.to_string(), Position::Offset(0), 10, false);
+            assert!(matches!(resp, Response::Error(Error::ErrRunningLua)));
         }
+        assert!(matches!(
+            db.itr_add(picky()),
+            Response::Error(Error::ErrRunningLua)
+        ));
+
+        db.exec(Command::MessageRedact(commands::MessageRedact {
+            admin_token: "secret".into(),
+            log_name: "test".into(),
+            message_id: 7,
+            reason: "breaks iterator".into(),
+        }));
+        assert!(matches!(db.itr_add(picky()), Response::Info(_)));
+        let (cursor, _) = page(db.itr_next("over".into(), Position::Offset(0), 10, false));
+        assert_eq!(cursor.next_offset, 9);
+
+        db.itr_del("test".into(), "evens".into());
+        assert!(!index_dir.exists());
     }
 
-    /// List all logs in db
-    fn log_list(&mut self) -> Response {
-        let logs: Vec<String> = self.manifest.logs.keys().cloned().collect();
-        let bytes = serde_cbor::to_vec(&logs).unwrap();
-        Response::Data(vec![bytes])
+    #[test]
+    fn test_db_itr_source() {
+        let (_dir, mut db) = test_db();
+        db.log_add("test".into(), LogOptions::default());
+        let payments = ["ok", "failed", "ok", "failed", "failed"];
+        for (i, status) in payments.iter().enumerate() {
+            let msg = serde_cbor::to_vec(&(status, i as u32 * 10)).unwrap();
+            db.msg_add("test".into(), Some(format!("p{}", i)), msg);
+        }
+
+        let failed = "return msg[1] == 'failed'";
+        db.itr_add(Itr {
+            indexed: true,
+            ..Itr::new(
+                "test".into(),
+                "failed".into(),
+                "filter".into(),
+                failed.into(),
+            )
+        });
+        let total = "return (acc or 0) + msg[2]";
+        db.itr_add(Itr {
+            source: Some("failed".into()),
+            ..Itr::new("test".into(), "total".into(), "reduce".into(), total.into())
+        });
+        db.itr_add(Itr {
+            source: Some("failed".into()),
+            ..Itr::new(
+                "test".into(),
+                "keys".into(),
+                "map".into(),
+                "return key".into(),
+            )
+        });
+        db.msg_add(
+            "test".into(),
+            Some("p5".into()),
+            serde_cbor::to_vec(&("failed", 50)).unwrap(),
+        );
+
+        // Iterators over a source read its results in place of the Log, with
+        // the offsets and keys of the Messages they came from
+        let (cursor, results) = page(db.itr_next("keys".into(), Position::Offset(2), 10, false));
+        let keys: Vec<String> = results
+            .iter()
+            .map(|r| serde_cbor::from_slice(r).unwrap())
+            .collect();
+        assert_eq!(keys, vec!["p3", "p4", "p5"]);
+        assert_eq!(cursor.next_offset, 6);
+        let (_, results) = page(db.itr_next("total".into(), Position::Offset(0), 10, false));
+        assert_eq!(results, vec![serde_cbor::to_vec(&130).unwrap()]);
+
+        let unindexed = Itr {
+            source: Some("keys".into()),
+            ..Itr::new(
+                "test".into(),
+                "bad".into(),
+                "map".into(),
+                "return msg".into(),
+            )
+        };
+        match db.itr_add(unindexed) {
+            Response::Error(e) => assert_eq!(e, Error::ItrSourceInvalid),
+            r => panic!("expected an invalid source to be refused, got {:?}", r),
+        }
+        match db.itr_del("test".into(), "failed".into()) {
+            Response::Error(e) => assert_eq!(e, Error::ItrHasDependents),
+            r => panic!("expected deleting a source to be refused, got {:?}", r),
+        }
     }
 
-    /// Displays information about a log
-    fn log_show(&mut self, name: String) -> Response {
-        let info = serde_cbor::to_vec(&self.manifest.logs[&name])
-            .expect("could not serialize log registrant");
-        Response::Data(vec![info])
+    #[test]
+    fn test_db_itr_next_positions() {
+        let (_dir, mut db) = test_db();
+        db.log_add("test".into(), LogOptions::default());
+        db.itr_add(Itr::new(
+            "test".into(),
+            "i".into(),
+            "map".into(),
+            "return msg".into(),
+        ));
+        for i in 0..3u8 {
+            db.msg_add("test".into(), None, vec![0x19, 0x03, i]);
+        }
+
+        let (_, results) = page(db.itr_next("i".into(), Position::Offset(-1), 10, false));
+        assert_eq!(results, vec![vec![0x19, 0x03, 2]]);
+
+        let (_, results) = page(db.itr_next("i".into(), Position::Offset(-2), 10, false));
+        assert_eq!(results, vec![vec![0x19, 0x03, 1], vec![0x19, 0x03, 2]]);
+
+        // Counting back past the start of the Log starts at its first Message
+        let (_, results) = page(db.itr_next("i".into(), Position::Offset(-10), 10, false));
+        assert_eq!(results.len(), 3);
+
+        let (_, results) = page(db.itr_next("i".into(), Position::Anchor(Anchor::Start), 1, false));
+        assert_eq!(results, vec![vec![0x19, 0x03, 0]]);
+
+        let (cursor, results) =
+            page(db.itr_next("i".into(), Position::Anchor(Anchor::End), 10, false));
+        assert!(results.is_empty());
+        assert_eq!(cursor.next_offset, 3);
     }
 
-    /// Adds a new log to the DB
-    fn log_add(&mut self, name: String) -> Response {
-        self.manifest.add_log(name.clone());
-        self.logs.entry(name).or_insert_with(Log::new);
-        Response::Info(OK_RESP.into())
+    #[test]
+    fn test_db_itr_next_timestamp() {
+        let (_dir, mut db) = test_db();
+        db.log_add("test".into(), LogOptions::default());
+        db.itr_add(Itr::new(
+            "test".into(),
+            "i".into(),
+            "map".into(),
+            "return msg".into(),
+        ));
+        db.msg_add("test".into(), None, vec![0x19, 0x03, 0]);
+
+        let (_, results) = page(db.itr_next("i".into(), Position::Timestamp(0), 10, false));
+        assert_eq!(results.len(), 1);
+
+        let (cursor, results) =
+            page(db.itr_next("i".into(), Position::Timestamp(u64::MAX), 10, false));
+        assert!(results.is_empty());
+        assert_eq!(cursor.next_offset, 1);
     }
 
-    /// Deletes a log from the DB
-    fn log_delete(&mut self, name: String) -> Response {
-        if let Entry::Occupied(l) = self.logs.entry(name.clone()) {
-            l.remove_entry();
-            self.manifest.del_log(name);
+    #[test]
+    fn test_db_position_from_cbor() {
+        let parse = |v: serde_cbor::Value| -> Position {
+            serde_cbor::value::from_value(v).expect("could not parse position")
         };
-        Response::Info(OK_RESP.into())
+        assert_eq!(parse(serde_cbor::Value::Integer(7)), Position::Offset(7));
+        assert_eq!(parse(serde_cbor::Value::Integer(-1)), Position::Offset(-1));
+        assert_eq!(
+            parse(serde_cbor::Value::Text("end".into())),
+            Position::Anchor(Anchor::End)
+        );
     }
 
-    /// Adds a new message to a log
-    fn msg_add(&mut self, log: String, msg: Vec<u8>) -> Response {
-        let l = self.logs.get_mut(&log);
-        if l.is_none() {
-            return Error::LogDoesNotExist.into();
+    #[test]
+    fn test_db_compression() {
+        let dir = tempfile::tempdir().expect("could not create temp dir");
+        let cfg = RemitsConfig {
+            data_dir: Some(dir.path().into()),
+            segment_size: Some(54),
+            ..Default::default()
+        };
+        let db = Mutex::new(DB::new(&cfg).unwrap());
+        let options = LogOptions {
+            compression: Compression::Lz4,
+            ..Default::default()
+        };
+        {
+            let mut db = db.lock().unwrap();
+            db.log_add("test".into(), options);
+            db.itr_add(Itr::new(
+                "test".into(),
+                "i".into(),
+                "map".into(),
+                "return msg".into(),
+            ));
+            for i in 0..6u8 {
+                db.msg_add("test".into(), None, vec![0x19, 0x03, i]);
+            }
         }
 
-        match l.unwrap().add_msg(msg) {
-            Ok(_) => Response::Info(OK_RESP.into()),
-            Err(e) => e.into(),
+        compress_segments(&db);
+        let mut db = db.lock().unwrap();
+        assert!(db.uncompressed_segments().is_empty());
+        match db.itr_next("i".into(), Position::Offset(1), 10, false) {
+            Response::Data(msgs) => assert_eq!(msgs.len(), 6),
+            _ => panic!("expected iterator next to return data"),
         }
     }
 
-    /// List all itrs attached to a log
-    fn itr_list(&mut self, name: Option<String>) -> Response {
-        let itrs = &self.manifest.itrs;
-        let out: Vec<Vec<u8>> = match name {
-            Some(name) => itrs
-                .iter()
-                .filter(|(_, itr)| itr.log == name)
-                .map(|(_, x)| serde_cbor::to_vec(&x.name).unwrap())
-                .collect(),
-            None => itrs
-                .keys()
-                .map(|x| serde_cbor::to_vec(x).unwrap())
-                .collect(),
+    #[test]
+    fn test_db_compaction() {
+        let dir = tempfile::tempdir().expect("could not create temp dir");
+        let cfg = RemitsConfig {
+            data_dir: Some(dir.path().into()),
+            segment_size: Some(64),
+            ..Default::default()
         };
-        Response::Data(out)
-    }
-
-    /// Adds a new unindexed iterator to a log
-    fn itr_add(&mut self, log: String, name: String, kind: IteratorKind, func: String) -> Response {
-        match self.manifest.add_itr(log, name, kind, func) {
-            Ok(_) => Response::Info(OK_RESP.into()),
-            Err(e) => e.into(),
+        let db = Mutex::new(DB::new(&cfg).unwrap());
+        let options = LogOptions {
+            compacted: true,
+            ..Default::default()
+        };
+        {
+            let mut db = db.lock().unwrap();
+            db.log_add("test".into(), options);
+            db.itr_add(Itr::new(
+                "test".into(),
+                "i".into(),
+                "map".into(),
+                "return key".into(),
+            ));
+            for (i, key) in ["a", "b", "a", "b", "c"].iter().enumerate() {
+                db.msg_add(
+                    "test".into(),
+                    Some(key.to_string()),
+                    vec![0x19, 0x03, i as u8],
+                );
+            }
         }
+
+        compact_logs(&db);
+        let mut db = db.lock().unwrap();
+        assert!(db.compaction_due().is_empty());
+        let (cursor, results) = page(db.itr_next("i".into(), Position::Offset(0), 10, false));
+        let keys: Vec<String> = results
+            .iter()
+            .map(|r| serde_cbor::from_slice(r).unwrap())
+            .collect();
+        assert_eq!(keys, vec!["a", "b", "c"]);
+        assert_eq!(cursor.next_offset, 5);
+        assert!(cursor.end_of_log);
+
+        // Pages count Messages, not offsets
+        let (cursor, results) = page(db.itr_next("i".into(), Position::Offset(0), 1, false));
+        assert_eq!(results.len(), 1);
+        assert_eq!(cursor.next_offset, 3);
+        assert!(!cursor.end_of_log);
     }
-    // Delets an unused unindexed iterator to a log
-    fn itr_del(&mut self, log: String, name: String) -> Response {
-        match self.manifest.del_itr(log, name) {
-            Ok(_) => Response::Info(OK_RESP.into()),
-            Err(e) => e.into(),
-        }
+
+    #[test]
+    fn test_db_archive() {
+        let dir = tempfile::tempdir().expect("could not create temp dir");
+        let cold = dir.path().join("cold");
+        let cfg = RemitsConfig {
+            archive_dir: Some(cold.clone()),
+            archive_after_secs: Some(0),
+            ..Default::default()
+        };
+        let (_cfg, db) = segmented_db(dir.path(), cfg);
+
+        archive_segments(&db);
+        assert!(db.lock().unwrap().archive_due().is_empty());
+        let log_dir = cold.join(logs::dir_name("test"));
+        assert_eq!(fs::read_dir(&log_dir).unwrap().count(), 2);
+
+        // Archived Segments are fetched without the DB locked, between tries
+        let next = Command::IteratorNext(commands::IteratorNext {
+            iterator_name: "i".into(),
+            position: Position::Offset(0),
+            count: 10,
+            intermediate: false,
+        });
+        let (cursor, results) = page(exec_fetching(&db, next));
+        assert_eq!(results.len(), 5);
+        assert_eq!(cursor.next_offset, 5);
+
+        // Deleting the Log deletes its archived Segments
+        let mut db = db.lock().unwrap();
+        db.log_delete("test".into());
+        assert_eq!(fs::read_dir(&log_dir).unwrap().count(), 0);
     }
 
-    fn itr_next(&mut self, name: String, msg_id: usize, count: usize) -> Response {
-        let itr = match self.manifest.itrs.get(&name) {
-            Some(itr) => itr,
-            None => return Error::ItrDoesNotExist.into(),
+    #[test]
+    fn test_db_itr_add_backfill() {
+        let dir = tempfile::tempdir().expect("could not create temp dir");
+        let cfg = RemitsConfig {
+            archive_dir: Some(dir.path().join("cold")),
+            archive_after_secs: Some(0),
+            admin_token: Some("secret".into()),
+            ..Default::default()
+        };
+        let (_cfg, db) = segmented_db(dir.path(), cfg);
+        archive_segments(&db);
+        let add = |name: &str| commands::IteratorAdd {
+            log_name: "test".into(),
+            iterator_name: name.into(),
+            iterator_kind: "map".into(),
+            iterator_func: "return msg".into(),
+            iterator_initial: None,
+            indexed: true,
+            iterator_source: None,
         };
 
-        let log = match self.logs.get(&itr.log) {
-            Some(log) => log,
-            None => return Error::LogDoesNotExist.into(),
+        // The backfill stops at archived Segments until they are fetched,
+        // and carries on from where it stopped
+        assert!(matches!(itr_add(&db, add("a")), Response::Info(_)));
+        let mut db = db.lock().unwrap();
+        assert_eq!(db.indexes["a"].next_offset(), 5);
+
+        // The Iterator can't be read until its backfill has finished, and
+        // Messages redacted in the meantime are redacted from its results
+        let mut backfill = db.begin_itr_add(add("b").into()).unwrap().unwrap();
+        assert!(db.backfill(&mut backfill).unwrap());
+        let resp = db.itr_next("b".into(), Position::Offset(0), 10, false);
+        assert!(matches!(resp, Response::Error(Error::ErrReadingLog)));
+        db.exec(Command::MessageRedact(commands::MessageRedact {
+            admin_token: "secret".into(),
+            log_name: "test".into(),
+            message_id: 1,
+            reason: "erasure request".into(),
+        }));
+        assert!(matches!(
+            db.finish_itr_add(backfill, Ok(())),
+            Response::Info(_)
+        ));
+        let (_, results) = page(db.itr_next("b".into(), Position::Offset(0), 10, false));
+        assert_eq!(results.len(), 4);
+    }
+
+    #[test]
+    fn test_db_snapshot() {
+        let dir = tempfile::tempdir().expect("could not create temp dir");
+        let (cfg, db) = segmented_db(dir.path(), RemitsConfig::default());
+
+        let info = match snapshot(&db, "s1") {
+            Response::Data(d) => d,
+            r => panic!("expected data, got {:?}", r),
         };
+        let path = cfg.snapshot_dir().join("s1");
+        let info: HashMap<String, PathBuf> = serde_cbor::from_slice(&info[0]).unwrap();
+        assert_eq!(info["path"], path);
+        assert!(path.join(snapshot::CHECKSUMS_FILE).exists());
 
-        match itr.next(log, msg_id, count) {
-            Ok(d) => Response::Data(d),
-            Err(e) => e.into(),
-        }
+        // Messages added after the Snapshot are not in it
+        db.lock()
+            .unwrap()
+            .msg_add("test".into(), None, vec![0x19, 0x03, 5]);
+        let mut snap_db = DB::new(&RemitsConfig {
+            data_dir: Some(path),
+            ..cfg.clone()
+        })
+        .unwrap();
+        let (cursor, results) = page(snap_db.itr_next("i".into(), Position::Offset(0), 10, false));
+        assert_eq!(results.len(), 5);
+        assert_eq!(cursor.high_water_mark, 5);
+
+        let mut db = db.lock().unwrap();
+        let resp = db.exec(Command::Snapshot(commands::Snapshot {
+            snapshot_name: "s1".into(),
+        }));
+        assert!(matches!(resp, Response::Error(Error::SnapshotExists)));
+        let resp = db.exec(Command::Snapshot(commands::Snapshot {
+            snapshot_name: "../s2".into(),
+        }));
+        assert!(matches!(resp, Response::Error(Error::SnapshotNameInvalid)));
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::time::SystemTime;
+    /// A DB holding a Log called `test` with five Messages, and a Snapshot of
+    /// it called `s1`
+    fn snapshotted_db(dir: &Path) -> (RemitsConfig, Mutex<DB>) {
+        let (cfg, db) = segmented_db(dir, RemitsConfig::default());
+        assert!(matches!(snapshot(&db, "s1"), Response::Data(_)));
+        (cfg, db)
+    }
 
     #[test]
-    fn test_db_log_list() {
-        let mut db = DB::new();
-        db.log_add("metric".into());
-        db.log_add("test".into());
+    fn test_db_snapshot_import() {
+        let dir = tempfile::tempdir().expect("could not create temp dir");
+        let (_cfg, db) = snapshotted_db(dir.path());
+        db.lock()
+            .unwrap()
+            .msg_add("test".into(), None, vec![0x19, 0x03, 5]);
 
-        let resp = db.log_list();
-        match resp {
-            Response::Data(bytes) => {
-                let out: Vec<String> = serde_cbor::from_slice(&*(bytes[0])).unwrap();
-                let l1 = "test".to_owned();
-                let l2 = "metric".to_owned();
+        let names = |pairs: &[(&str, &str)]| -> HashMap<String, String> {
+            pairs
+                .iter()
+                .map(|(a, b)| (a.to_string(), b.to_string()))
+                .collect()
+        };
+        match import(&db, "s1", names(&[("test", "restored")])) {
+            Response::Info(i) => assert_eq!(i, OK_RESP),
+            r => panic!("expected info, got {:?}", r),
+        }
 
-                assert!(out[0] == l1 || out[1] == l1);
-                assert!(out[1] == l2 || out[0] == l2);
-            }
-            _ => panic!("error returned from log list"),
+        let mut db = db.lock().unwrap();
+        assert_eq!(db.logs["restored"].len(), 5);
+        assert_eq!(db.logs["test"].len(), 6);
+        db.itr_add(Itr::new(
+            "restored".into(),
+            "r".into(),
+            "map".into(),
+            "return 1".into(),
+        ));
+        let (_, results) = page(db.itr_next("r".into(), Position::Offset(0), 10, false));
+        assert_eq!(results.len(), 5);
+
+        let import = |db: &mut DB, name: &str, pairs| {
+            db.exec(Command::SnapshotImport(commands::SnapshotImport {
+                snapshot_name: name.into(),
+                log_names: names(pairs),
+            }))
         };
+        let resp = import(&mut db, "s1", &[("test", "restored")]);
+        assert!(matches!(resp, Response::Error(Error::LogAlreadyExists)));
+        let resp = import(&mut db, "s2", &[("test", "other")]);
+        assert!(matches!(resp, Response::Error(Error::SnapshotDoesNotExist)));
+        let resp = import(&mut db, "s1", &[("missing", "other")]);
+        assert!(matches!(resp, Response::Error(Error::LogDoesNotExist)));
+        assert!(!db.logs.contains_key("other"));
+
+        // Nothing is imported from a Snapshot that has been tampered with
+        let segment = db
+            .snapshots_dir
+            .join("s1/logs/74657374/00000000000000000000.log");
+        fs::write(segment, b"tampered").unwrap();
+        let resp = import(&mut db, "s1", &[("test", "other")]);
+        assert!(matches!(resp, Response::Error(Error::SnapshotCorrupted)));
+        assert_eq!(fs::read_dir(&db.logs_dir).unwrap().count(), 2);
     }
 
     #[test]
-    fn test_db_log_show() {
-        let mut db = DB::new();
-        db.log_add("test".into());
-        let resp = db.log_show("test".into());
+    fn test_db_restore() {
+        let dir = tempfile::tempdir().expect("could not create temp dir");
+        let (cfg, db) = snapshotted_db(dir.path());
+        let from = cfg.snapshot_dir().join("s1");
+        drop(db);
 
-        let log = serde_cbor::to_vec(&manifest::LogRegistrant {
-            name: "test".into(),
-            created_at: SystemTime::now()
-                .duration_since(SystemTime::UNIX_EPOCH)
-                .expect("could not get system time")
-                .as_secs() as usize,
-        })
-        .expect("could not marshal comparison LogRegistrant");
+        // An existing database is never overwritten
+        let err = restore(&cfg, &from).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
 
-        match resp {
-            Response::Data(bytes) => assert_eq!(*bytes[0], *log),
-            Response::Error(e) => panic!("error returned from log show: {:#?}", e),
-            Response::Info(i) => panic!("info returned from log show: {:#?}", i),
+        let restored = RemitsConfig {
+            data_dir: Some(dir.path().join("restored")),
+            ..cfg.clone()
+        };
+        // Logs already in a data directory without a Manifest are kept
+        let old = restored
+            .data_dir()
+            .join(LOGS_DIR)
+            .join(logs::dir_name("old"));
+        fs::create_dir_all(&old).unwrap();
+        restore(&restored, &from).unwrap();
+        let mut db = DB::new(&restored).unwrap();
+        assert_eq!(db.logs["test"].len(), 5);
+        assert!(!old.exists());
+        let lost_found = restored.data_dir().join(LOST_FOUND_DIR);
+        assert_eq!(fs::read_dir(&lost_found).unwrap().count(), 1);
+        let (_, results) = page(db.itr_next("i".into(), Position::Offset(0), 10, false));
+        assert_eq!(results.len(), 5);
+
+        // Restoring doesn't touch the Snapshot, which can be restored again
+        db.msg_add("test".into(), None, vec![0x19, 0x03, 5]);
+        snapshot::verify(&from).unwrap();
+
+        fs::write(from.join(manifest::MANIFEST_FILE), b"tampered").unwrap();
+        let other = RemitsConfig {
+            data_dir: Some(dir.path().join("other")),
+            ..cfg
+        };
+        let err = restore(&other, &from).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(!other.data_dir().join(manifest::MANIFEST_FILE).exists());
+    }
+
+    #[test]
+    fn test_db_memory_storage() {
+        let dir = tempfile::tempdir().expect("could not create temp dir");
+        let cfg = RemitsConfig {
+            data_dir: Some(dir.path().into()),
+            storage: Some(StorageBackend::Memory),
+            ..Default::default()
+        };
+        let mut db = DB::new(&cfg).unwrap();
+        db.log_add("test".into(), LogOptions::default());
+        db.itr_add(Itr::new(
+            "test".into(),
+            "i".into(),
+            "map".into(),
+            "return msg".into(),
+        ));
+        for i in 0..3u8 {
+            db.msg_add("test".into(), None, vec![0x19, 0x03, i]);
+        }
+
+        let (cursor, results) = page(db.itr_next("i".into(), Position::Offset(0), 10, false));
+        assert_eq!(results.len(), 3);
+        assert_eq!(cursor.high_water_mark, 3);
+        assert!(!dir
+            .path()
+            .join(LOGS_DIR)
+            .join(logs::dir_name("test"))
+            .exists());
+        assert!(matches!(
+            db.exec(Command::Snapshot(commands::Snapshot {
+                snapshot_name: "snap".into()
+            })),
+            Response::Error(Error::ErrWritingSnapshot)
+        ));
+
+        // The Log is still registered, but its Messages are gone
+        drop(db);
+        let db = DB::new(&cfg).unwrap();
+        assert_eq!(db.logs["test"].len(), 0);
+    }
+
+    #[test]
+    fn test_db_group_commit() {
+        let dir = tempfile::tempdir().expect("could not create temp dir");
+        let cfg = RemitsConfig {
+            data_dir: Some(dir.path().into()),
+            ..Default::default()
+        };
+        let db = Mutex::new(DB::new(&cfg).unwrap());
+        let add = |log: &str, i| {
+            db.lock().unwrap().msg_add_grouped(commands::MessageAdd {
+                log_name: log.into(),
+                key: None,
+                message: vec![0x19, 0x03, i],
+            })
+        };
+        {
+            let mut db = db.lock().unwrap();
+            db.log_add("test".into(), LogOptions::default());
+            let options = LogOptions {
+                fsync: FsyncPolicy::EveryMessages(10),
+                ..Default::default()
+            };
+            db.log_add("batched".into(), options);
+            db.itr_add(Itr {
+                indexed: true,
+                ..Itr::new("test".into(), "i".into(), "map".into(), "return msg".into())
+            });
+        }
+        let indexed = || db.lock().unwrap().indexes["i"].next_offset();
+
+        // Appends to a Log that syncs every Message wait on the next commit,
+        // and are only indexed once it has finished
+        let mut pending: Vec<_> = (0..3).map(|i| add("test", i)).collect();
+        for rx in pending.iter_mut() {
+            assert!(rx.try_recv().is_err());
+        }
+        assert_eq!(indexed(), 0);
+        commit_logs(&db);
+        for rx in pending.iter_mut() {
+            assert!(matches!(rx.try_recv(), Ok(Response::Info(_))));
+        }
+        let synced_len = db.lock().unwrap().logs["test"]
+            .segmented()
+            .map(|l| l.synced_len());
+        assert_eq!(synced_len, Some(3));
+        assert_eq!(indexed(), 3);
+
+        // Anything else is answered straight away
+        let resp = add("batched", 0).try_recv();
+        assert!(matches!(resp, Ok(Response::Info(_))));
+        let resp = add("missing", 0).try_recv();
+        assert!(matches!(resp, Ok(Response::Error(Error::LogDoesNotExist))));
+
+        // Deleting a Log fails the appends still waiting on it
+        let mut rx = add("test", 3);
+        db.lock().unwrap().log_delete("test".into());
+        assert!(matches!(
+            rx.try_recv(),
+            Ok(Response::Error(Error::ErrWritingLog))
+        ));
+    }
+
+    #[test]
+    fn test_db_redact() {
+        let dir = tempfile::tempdir().expect("could not create temp dir");
+        let cfg = RemitsConfig {
+            data_dir: Some(dir.path().into()),
+            admin_token: Some("secret".into()),
+            ..Default::default()
+        };
+        let mut db = DB::new(&cfg).unwrap();
+        db.log_add("test".into(), LogOptions::default());
+        db.itr_add(Itr::new(
+            "test".into(),
+            "i".into(),
+            "map".into(),
+            "return msg".into(),
+        ));
+        for i in 0..4u8 {
+            db.msg_add("test".into(), None, vec![0x19, 0x03, i]);
         }
+        let snap = db.exec(Command::Snapshot(commands::Snapshot {
+            snapshot_name: "s1".into(),
+        }));
+        assert!(matches!(snap, Response::Data(_)));
+
+        let mut redact = |token: &str, id| {
+            db.exec(Command::MessageRedact(commands::MessageRedact {
+                admin_token: token.into(),
+                log_name: "test".into(),
+                message_id: id,
+                reason: "erasure request".into(),
+            }))
+        };
+        assert!(matches!(
+            redact("wrong", 1),
+            Response::Error(Error::NotAuthorized)
+        ));
+        assert!(matches!(redact("secret", 1), Response::Info(_)));
+        assert!(matches!(
+            redact("secret", 9),
+            Response::Error(Error::MsgDoesNotExist)
+        ));
+
+        // Iterators skip redacted Messages
+        let (cursor, results) = page(db.itr_next("i".into(), Position::Offset(0), 2, false));
+        let msgs: Vec<u32> = results
+            .iter()
+            .map(|r| serde_cbor::from_slice(r).unwrap())
+            .collect();
+        assert_eq!(msgs, vec![0x0300, 0x0302]);
+        assert_eq!(cursor.next_offset, 3);
+
+        let list = |db: &mut DB, token: &str| {
+            db.exec(Command::RedactionList(commands::RedactionList {
+                admin_token: token.into(),
+                log_name: Some("test".into()),
+            }))
+        };
+        assert!(matches!(
+            list(&mut db, "wrong"),
+            Response::Error(Error::NotAuthorized)
+        ));
+        let entries = match list(&mut db, "secret") {
+            Response::Data(d) => d,
+            r => panic!("expected data, got {:?}", r),
+        };
+        let entries: Vec<Redaction> = entries
+            .iter()
+            .map(|e| serde_cbor::from_slice(e).unwrap())
+            .collect();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(
+            (entries[0].message_id, &*entries[0].reason),
+            (1, "erasure request")
+        );
+        assert_eq!(entries[0].snapshots, vec!["s1".to_string()]);
+
+        // Without an admin token, redaction is turned off
+        let mut db = DB::new(&RemitsConfig {
+            admin_token: None,
+            ..cfg
+        })
+        .unwrap();
+        assert!(matches!(
+            list(&mut db, ""),
+            Response::Error(Error::NotAuthorized)
+        ));
     }
 
     #[test]
     fn test_db_log_add() {
-        let mut db = DB::new();
+        let (_dir, mut db) = test_db();
 
-        match db.log_add("test".into()) {
+        match db.log_add("test".into(), LogOptions::default()) {
             Response::Info(i) => assert_eq!(i, OK_RESP),
             _ => panic!("expected info to be returned"),
         };
 
-        match db.log_add("test".into()) {
+        match db.log_add("test".into(), LogOptions::default()) {
             Response::Info(i) => assert_eq!(i, OK_RESP),
             _ => panic!("expected info to be returned"),
         };
+
+        // Fsync intervals have to be greater than zero
+        for fsync in [FsyncPolicy::EveryMillis(0), FsyncPolicy::EveryMessages(0)].iter() {
+            let options = LogOptions {
+                fsync: *fsync,
+                ..Default::default()
+            };
+            let resp = db.log_add("zero".into(), options);
+            assert!(matches!(resp, Response::Error(Error::LogOptionsInvalid)));
+        }
+        assert!(!db.manifest.logs.contains_key("zero"));
+
+        // A Log that can't be created isn't registered.
+        fs::write(db.log_dir("blocked"), b"not a dir").unwrap();
+        let resp = db.log_add("blocked".into(), LogOptions::default());
+        assert!(matches!(resp, Response::Error(Error::ErrWritingLog)));
+        assert!(!db.manifest.logs.contains_key("blocked"));
+        assert!(!db.logs.contains_key("blocked"));
     }
 
     #[test]
     fn test_db_msg_add() {
-        let mut db = DB::new();
-        db.log_add("test".into());
+        let (_dir, mut db) = test_db();
+        db.log_add("test".into(), LogOptions::default());
 
         let msg = vec![0x19, 0x03, 0xE8];
-        match db.msg_add("test".into(), msg.clone()) {
+        match db.msg_add("test".into(), None, msg.clone()) {
             Response::Info(i) => assert_eq!(i, OK_RESP),
             _ => panic!("expected info to be returned"),
         };
 
         assert_eq!(db.logs.len(), 1);
-        assert_eq!(db.logs["test"][0], msg.clone());
+        let stored = db.logs["test"].messages(0).unwrap().next().unwrap();
+        assert_eq!(stored.unwrap().msg, msg);
+    }
+
+    #[test]
+    fn test_db_reopen() {
+        let (dir, mut db) = test_db();
+        db.log_add("test".into(), LogOptions::default());
+        db.msg_add("test".into(), None, vec![0x19, 0x03, 0xE8]);
+        drop(db);
+
+        let cfg = RemitsConfig {
+            data_dir: Some(dir.path().into()),
+            ..Default::default()
+        };
+        let db = DB::new(&cfg).unwrap();
+        assert!(db.manifest.logs.contains_key("test"));
+        assert_eq!(db.logs["test"].len(), 1);
+    }
+
+    #[test]
+    fn test_db_reopen_keeps_manifest() {
+        let (dir, mut db) = test_db();
+        db.log_add("test".into(), LogOptions::default());
+        db.itr_add(Itr::new(
+            "test".into(),
+            "i".into(),
+            "map".into(),
+            "return msg".into(),
+        ));
+        let created_at = db.manifest.logs["test"].created_at;
+        drop(db);
+
+        // Leftovers from an interrupted delete should be moved out of the way.
+        let stale = dir.path().join(LOGS_DIR).join(logs::dir_name("stale"));
+        fs::create_dir_all(&stale).unwrap();
+
+        let cfg = RemitsConfig {
+            data_dir: Some(dir.path().into()),
+            ..Default::default()
+        };
+        let db = DB::new(&cfg).unwrap();
+        assert_eq!(db.manifest.logs["test"].created_at, created_at);
+        assert_eq!(db.manifest.itrs["i"].log, "test");
+        assert!(!stale.exists());
+        let lost_found = dir.path().join(LOST_FOUND_DIR);
+        assert_eq!(fs::read_dir(&lost_found).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_db_keeps_logs_without_manifest() {
+        let (dir, mut db) = test_db();
+        db.log_add("test".into(), LogOptions::default());
+        db.msg_add("test".into(), None, vec![0x19, 0x03, 0xE8]);
+        drop(db);
+        fs::remove_file(dir.path().join(manifest::MANIFEST_FILE)).unwrap();
+
+        // Without a Manifest the Log isn't opened, but its files are kept.
+        let cfg = RemitsConfig {
+            data_dir: Some(dir.path().into()),
+            ..Default::default()
+        };
+        let db = DB::new(&cfg).unwrap();
+        assert!(db.logs.is_empty());
+        let kept: Vec<PathBuf> = fs::read_dir(dir.path().join(LOST_FOUND_DIR))
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        assert_eq!(kept.len(), 1);
+        let name = kept[0].file_name().unwrap().to_str().unwrap();
+        assert!(name.starts_with(&logs::dir_name("test")));
+        assert!(fs::read_dir(&kept[0]).unwrap().count() > 0);
     }
 
     #[test]
     fn test_db_msg_add_log_dne() {
-        let mut db = DB::new();
-        match db.msg_add("test".into(), "hello".as_bytes().to_vec()) {
+        let (_dir, mut db) = test_db();
+        match db.msg_add("test".into(), None, "hello".as_bytes().to_vec()) {
             Response::Error(e) => (),
             _ => panic!("expected response to be an error"),
         }
@@ -242,14 +2716,16 @@ mod tests {
 
     #[test]
     fn test_db_log_del() {
-        let mut db = DB::new();
-        db.log_add("test".into());
-        db.manifest.add_itr(
-            "test".into(),
-            "fun".into(),
-            "map".into(),
-            "return msg".into(),
-        );
+        let (_dir, mut db) = test_db();
+        db.log_add("test".into(), LogOptions::default());
+        db.manifest
+            .add_itr(Itr::new(
+                "test".into(),
+                "fun".into(),
+                "map".into(),
+                "return msg".into(),
+            ))
+            .unwrap();
         assert_eq!(db.manifest.logs.len(), 1);
 
         match db.log_delete("test".into()) {
@@ -257,19 +2733,25 @@ mod tests {
             _ => panic!("expected response to be info"),
         };
         assert_eq!(db.manifest.logs.len(), 0);
+        assert_eq!(fs::read_dir(&db.logs_dir).unwrap().count(), 0);
     }
 
     #[test]
     fn test_db_itr_list() {
-        let mut db = DB::new();
-        db.log_add("log".into());
-        db.itr_add("log".into(), "i1".into(), "map".into(), "return msg".into());
-        db.itr_add(
+        let (_dir, mut db) = test_db();
+        db.log_add("log".into(), LogOptions::default());
+        db.itr_add(Itr::new(
+            "log".into(),
+            "i1".into(),
+            "map".into(),
+            "return msg".into(),
+        ));
+        db.itr_add(Itr::new(
             "log2".into(),
             "i2".into(),
             "map".into(),
             "return msg".into(),
-        );
+        ));
         match db.itr_list(Some("log".into())) {
             Response::Data(bytes) => {
                 let out: String = serde_cbor::from_slice(&*(bytes[0])).unwrap();
@@ -293,8 +2775,13 @@ mod tests {
 
     #[test]
     fn test_db_itr_add() {
-        let mut db = DB::new();
-        match db.itr_add("log".into(), "i".into(), "map".into(), "return msg".into()) {
+        let (_dir, mut db) = test_db();
+        match db.itr_add(Itr::new(
+            "log".into(),
+            "i".into(),
+            "map".into(),
+            "return msg".into(),
+        )) {
             Response::Info(i) => assert_eq!(i, OK_RESP),
             _ => panic!("expected itr_add to return info"),
         };
@@ -303,8 +2790,13 @@ mod tests {
 
     #[test]
     fn test_db_itr_del() {
-        let mut db = DB::new();
-        db.itr_add("log".into(), "i".into(), "map".into(), "return msg".into());
+        let (_dir, mut db) = test_db();
+        db.itr_add(Itr::new(
+            "log".into(),
+            "i".into(),
+            "map".into(),
+            "return msg".into(),
+        ));
         match db.itr_del("log".into(), "i".into()) {
             Response::Info(i) => assert_eq!(i, OK_RESP),
             _ => panic!("expected itr_add to return info"),
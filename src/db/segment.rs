@@ -0,0 +1,1633 @@
+use super::compression;
+use super::crypto::{self, Keyring, PLAINTEXT};
+use super::index::{self, IndexEntry, OffsetIndex};
+use crate::commands::Compression;
+use memmap2::Mmap;
+use std::borrow::Cow;
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+const INDEX_EXT: &str = "index";
+const TMP_EXT: &str = "tmp";
+const HEADER_SIZE: usize = 24;
+
+/// Every Segment file starts with these bytes, followed by the 4 byte big
+/// endian version of the format it is written in.
+const FILE_MAGIC: &[u8] = b"RMSG";
+pub const VERSION: u32 = 1;
+const FILE_HEADER_SIZE: u64 = 8;
+const BLOCK_SIZE: usize = 64 * 1024;
+
+/// Numbers the copies written by `rewrite`
+static NEXT_COPY: AtomicU64 = AtomicU64::new(0);
+
+/// Numbers every Segment that is created or opened, see `Sealed`
+static NEXT_GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// A Segment is a single append-only file holding a run of a Log's Messages,
+/// starting at `base_offset`.
+///
+/// The file starts with a header naming the version of its format, and then
+/// each Message is stored as a record made up of a 24 byte header followed by
+/// the Message's key, if it has one, and then the Message itself. The header
+/// holds, all big endian:
+///
+/// - the 4 byte length of the rest of the record after the header
+/// - a 4 byte CRC32 of everything in the record after the CRC32
+/// - an 8 byte timestamp of when the Message was added, in milliseconds since
+///   the Unix epoch
+/// - the 4 byte offset of the Message, relative to `base_offset`
+/// - the 2 byte length of the key, which is 0 if the Message has no key
+/// - the 2 byte id of the key the record is encrypted with, which is 0 if it
+///   isn't encrypted
+///
+/// When the Keyring has a key, the key and Message are encrypted together with
+/// the Keyring's current key, with the rest of the header authenticated along
+/// with them. Each record names its own key, so a Segment may hold records
+/// encrypted with several keys after a key is rotated.
+///
+/// Only the last Segment of a Log is ever written to. Every other Segment is
+/// "sealed" and read only. Sealed Segments may be rewritten without some of
+/// their Messages, so offsets within a Segment always increase but may skip
+/// some values.
+///
+/// When the active Segment is opened, a partially written record at the end of
+/// the file is truncated away. A corrupt record anywhere else means the
+/// Segment can't be trusted, so it is refused. Sealed Segments are verified as
+/// they are read.
+///
+/// Each Segment has a sparse OffsetIndex so readers can seek close to the
+/// Message they want, either by offset or by timestamp.
+///
+/// Sealed, uncompressed Segments are memory mapped, so their unencrypted
+/// Messages can be handed out as slices of the map rather than being copied out
+/// of the file.
+///
+/// Logs created with compression have their sealed Segments rewritten as
+/// compressed blocks. Each block is stored as a record without a key, with the
+/// timestamp and offset of its first Message. Its contents are a 4 byte big
+/// endian count of the Messages in the block, followed by the records of those
+/// Messages compressed together, which are never encrypted themselves since the
+/// block is. Compressed Segments use the file extension of
+/// their codec, and index every block in memory rather than keeping an index
+/// file.
+#[derive(Debug)]
+pub struct Segment {
+    pub base_offset: usize,
+    generation: u64,
+    path: PathBuf,
+    compression: Compression,
+    writer: Option<File>,
+    map: Option<Mmap>,
+    index: OffsetIndex,
+    size: u64,
+    next_offset: usize,
+    keyring: Arc<Keyring>,
+}
+
+impl Segment {
+    /// Creates a new, empty Segment in `dir` that is ready to be appended to.
+    pub fn create(dir: &Path, base_offset: usize, keyring: Arc<Keyring>) -> io::Result<Self> {
+        let path = segment_path(dir, base_offset, Compression::None);
+        let mut writer = OpenOptions::new()
+            .create_new(true)
+            .append(true)
+            .open(&path)?;
+        writer.write_all(&file_header())?;
+        let index = OffsetIndex::create(path.with_extension(INDEX_EXT), vec![])?;
+        File::open(dir)?.sync_all()?;
+
+        Ok(Segment {
+            base_offset,
+            generation: NEXT_GENERATION.fetch_add(1, Ordering::Relaxed),
+            path,
+            compression: Compression::None,
+            writer: Some(writer),
+            map: None,
+            index,
+            size: 0,
+            next_offset: base_offset,
+            keyring,
+        })
+    }
+
+    /// Opens an existing Segment so that it can be appended to. The file is
+    /// scanned to find out which Messages it holds, and to recover from any
+    /// write that was torn by a crash.
+    pub fn open_active(
+        path: PathBuf,
+        base_offset: usize,
+        keyring: Arc<Keyring>,
+    ) -> io::Result<Self> {
+        let (scan, file_size) = scan(&path)?;
+
+        let writer = OpenOptions::new().append(true).open(&path)?;
+        if scan.size < file_size {
+            warn!(
+                "truncating torn write from segment {:?}: {} bytes",
+                path,
+                file_size - scan.size
+            );
+            writer.set_len(FILE_HEADER_SIZE + scan.size)?;
+            writer.sync_all()?;
+        }
+        let index = OffsetIndex::create(path.with_extension(INDEX_EXT), scan.entries)?;
+
+        Ok(Segment {
+            base_offset,
+            generation: NEXT_GENERATION.fetch_add(1, Ordering::Relaxed),
+            path,
+            compression: Compression::None,
+            writer: Some(writer),
+            map: None,
+            index,
+            size: scan.size,
+            next_offset: base_offset + scan.span,
+            keyring,
+        })
+    }
+
+    /// Opens an existing read only Segment. Since sealed Segments sit between
+    /// two others, the offset the next Segment starts at, `end`, is already
+    /// known. The Segment is only scanned if its index needs to be rebuilt.
+    pub fn open_sealed(
+        path: PathBuf,
+        base_offset: usize,
+        end: usize,
+        keyring: Arc<Keyring>,
+    ) -> io::Result<Self> {
+        check_version(&mut File::open(&path)?, &path)?;
+        let size = path.metadata()?.len() - FILE_HEADER_SIZE;
+        let span = end - base_offset;
+        let index_path = path.with_extension(INDEX_EXT);
+        let index = match OffsetIndex::load(index_path.clone(), size, span)? {
+            Some(index) => index,
+            None => {
+                info!("rebuilding index for segment {:?}", path);
+                let (scan, _) = scan(&path)?;
+                if scan.size != size || scan.span > span {
+                    let msg = format!("sealed segment {:?} is incomplete", path);
+                    return Err(io::Error::new(ErrorKind::InvalidData, msg));
+                }
+                let mut index = OffsetIndex::create(index_path, scan.entries)?;
+                index.seal()?;
+                index
+            }
+        };
+
+        let map = map(&path, size)?;
+        Ok(Segment {
+            base_offset,
+            generation: NEXT_GENERATION.fetch_add(1, Ordering::Relaxed),
+            path,
+            compression: Compression::None,
+            writer: None,
+            map,
+            index,
+            size,
+            next_offset: end,
+            keyring,
+        })
+    }
+
+    /// Opens a compressed Segment written by `rewrite`. Only the block headers
+    /// are read, to build the Segment's index. If `end` is not given, the last
+    /// block is read to find it.
+    pub fn open_compressed(
+        path: PathBuf,
+        base_offset: usize,
+        compression: Compression,
+        end: Option<usize>,
+        keyring: Arc<Keyring>,
+    ) -> io::Result<Self> {
+        let mut file = File::open(&path)?;
+        check_version(&mut file, &path)?;
+        let size = file.metadata()?.len() - FILE_HEADER_SIZE;
+
+        let mut entries: Vec<IndexEntry> = vec![];
+        let mut position = 0;
+        while position < size {
+            let mut header = [0; HEADER_SIZE];
+            file.read_exact(&mut header)?;
+            let header = Header::decode(&header);
+            if entries.last().is_some_and(|e| e.offset >= header.offset) {
+                let msg = format!("compressed segment {:?} is out of order", path);
+                return Err(io::Error::new(ErrorKind::InvalidData, msg));
+            }
+
+            entries.push(IndexEntry {
+                offset: header.offset,
+                position,
+                timestamp: header.timestamp,
+            });
+            position += (HEADER_SIZE + header.len as usize) as u64;
+            file.seek(SeekFrom::Start(FILE_HEADER_SIZE + position))?;
+        }
+
+        let out_of_range = match (end, entries.last()) {
+            (Some(end), Some(last)) => base_offset + last.offset as usize >= end,
+            _ => false,
+        };
+        if position != size || out_of_range {
+            let msg = format!("compressed segment {:?} is incomplete", path);
+            return Err(io::Error::new(ErrorKind::InvalidData, msg));
+        }
+
+        let mut seg = Segment {
+            base_offset,
+            generation: NEXT_GENERATION.fetch_add(1, Ordering::Relaxed),
+            path,
+            compression,
+            writer: None,
+            map: None,
+            index: OffsetIndex::from_entries(entries),
+            size,
+            next_offset: end.unwrap_or(base_offset),
+            keyring,
+        };
+        if end.is_none() {
+            if let Some(last) = seg.tail()? {
+                seg.next_offset = last.offset + 1;
+            }
+        }
+        Ok(seg)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Describes the Segment for work done on its file in the background.
+    pub fn sealed(&self) -> Sealed {
+        Sealed {
+            base_offset: self.base_offset,
+            generation: self.generation,
+            path: self.path.clone(),
+        }
+    }
+
+    pub fn compression(&self) -> Compression {
+        self.compression
+    }
+
+    /// Whether the Segment can no longer be appended to
+    pub fn is_sealed(&self) -> bool {
+        self.writer.is_none()
+    }
+
+    /// The offset just past the Segment's last Message, which is where the next
+    /// Segment starts.
+    pub fn next_offset(&self) -> usize {
+        self.next_offset
+    }
+
+    /// Number of bytes the Segment's records take up on disk
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Number of bytes in the Segment's file, including its header
+    pub fn file_len(&self) -> u64 {
+        FILE_HEADER_SIZE + self.size
+    }
+
+    /// Number of bytes a Message will take up once appended
+    pub fn entry_size(&self, key: &[u8], msg: &[u8]) -> u64 {
+        let overhead = match self.keyring.current() {
+            PLAINTEXT => 0,
+            _ => crypto::OVERHEAD,
+        };
+        (HEADER_SIZE + overhead + key.len() + msg.len()) as u64
+    }
+
+    /// The timestamp of the Segment's first Message, if it has any
+    pub fn first_timestamp(&self) -> Option<u64> {
+        self.index.first().map(|e| e.timestamp)
+    }
+
+    /// The timestamp of the Segment's last Message, if it has any
+    pub fn last_timestamp(&self) -> io::Result<Option<u64>> {
+        Ok(self.tail()?.map(|r| r.timestamp))
+    }
+
+    /// Reads the Segment's last Message, starting from its last index entry.
+    fn tail(&self) -> io::Result<Option<Record<'_>>> {
+        let entry = match self.index.last() {
+            Some(entry) => *entry,
+            None => return Ok(None),
+        };
+        self.reader_at(entry)?.last().transpose()
+    }
+
+    /// Appends `msg` to the Segment, recording that it was added at
+    /// `timestamp`. An empty key is the same as no key.
+    pub fn append(&mut self, key: &[u8], msg: &[u8], timestamp: u64) -> io::Result<()> {
+        let writer = match self.writer.as_mut() {
+            Some(w) => w,
+            None => return Err(io::Error::other("segment is sealed")),
+        };
+
+        let offset = (self.next_offset - self.base_offset) as u32;
+        let record = encode_record(offset, timestamp, key, msg, &self.keyring)?;
+        writer.write_all(&record)?;
+
+        if self.index.needs_entry(self.size) {
+            self.index.append(IndexEntry {
+                offset,
+                position: self.size,
+                timestamp,
+            })?;
+        }
+
+        self.size += record.len() as u64;
+        self.next_offset += 1;
+        Ok(())
+    }
+
+    /// When the Segment was last appended to
+    pub fn modified(&self) -> io::Result<SystemTime> {
+        self.path.metadata()?.modified()
+    }
+
+    /// Removes the Segment and its index from disk.
+    pub fn delete(self) -> io::Result<()> {
+        remove(&self.path)
+    }
+
+    /// Flushes everything appended so far to disk
+    pub fn sync(&self) -> io::Result<()> {
+        match &self.writer {
+            Some(w) => w.sync_data(),
+            None => Ok(()),
+        }
+    }
+
+    /// Drops every Message at or after `offset` from the active Segment. The
+    /// Segment is opened again afterwards, which rebuilds its index.
+    pub fn truncate(&mut self, offset: usize) -> io::Result<()> {
+        let writer = match &self.writer {
+            Some(w) => w,
+            None => return Err(io::Error::other("segment is sealed")),
+        };
+        if offset >= self.next_offset {
+            return Ok(());
+        }
+
+        let relative = offset.saturating_sub(self.base_offset) as u32;
+        let mut reader = self.reader_at(self.index.lookup(relative))?;
+        let size = loop {
+            let position = match &reader.source {
+                Source::File { position, .. } => *position,
+                Source::Mapped { position, .. } => *position as u64,
+            };
+            match reader.read() {
+                Some(Ok(record)) if record.offset < offset => continue,
+                Some(Err(e)) => return Err(e),
+                _ => break position,
+            }
+        };
+        writer.set_len(FILE_HEADER_SIZE + size)?;
+        writer.sync_all()?;
+        *self = Segment::open_active(self.path.clone(), self.base_offset, self.keyring.clone())?;
+        Ok(())
+    }
+
+    /// Another handle to the file the Segment is appended to, so it can be
+    /// synced without holding onto the Segment.
+    pub fn try_clone_writer(&self) -> io::Result<File> {
+        match &self.writer {
+            Some(w) => w.try_clone(),
+            None => Err(io::Error::other("segment is sealed")),
+        }
+    }
+
+    /// Stops the Segment from being appended to
+    pub fn seal(&mut self) -> io::Result<()> {
+        self.writer = None;
+        self.index.seal()?;
+        if self.compression == Compression::None {
+            self.map = map(&self.path, self.size)?;
+        }
+        Ok(())
+    }
+
+    /// Returns a reader over the Segment's Messages, starting at the first
+    /// Message at or after `offset`.
+    pub fn reader(&self, offset: usize) -> io::Result<SegmentReader<'_>> {
+        let relative = offset.saturating_sub(self.base_offset);
+        let entry = self.index.lookup(relative.min(u32::MAX as usize) as u32);
+        let mut reader = self.reader_at(entry)?;
+        reader.from = offset;
+        Ok(reader)
+    }
+
+    /// Finds the offset of the first Message added at or after `timestamp`.
+    /// Returns None if every Message in the Segment is older.
+    pub fn offset_for_time(&self, timestamp: u64) -> io::Result<Option<usize>> {
+        let entry = self.index.lookup_time(timestamp);
+        for record in self.reader_at(entry)? {
+            let record = record?;
+            if record.timestamp >= timestamp {
+                return Ok(Some(record.offset));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Returns a reader starting at the record pointed to by `entry`.
+    fn reader_at(&self, entry: IndexEntry) -> io::Result<SegmentReader<'_>> {
+        let source = match &self.map {
+            Some(map) => Source::Mapped {
+                data: &map[FILE_HEADER_SIZE as usize..][..self.size as usize],
+                position: entry.position as usize,
+            },
+            None => {
+                let mut file = File::open(&self.path)?;
+                file.seek(SeekFrom::Start(FILE_HEADER_SIZE + entry.position))?;
+                Source::File {
+                    inner: BufReader::new(file),
+                    position: entry.position,
+                    end: self.size,
+                    compression: self.compression,
+                    block: VecDeque::new(),
+                }
+            }
+        };
+
+        Ok(SegmentReader {
+            base_offset: self.base_offset,
+            keyring: self.keyring.clone(),
+            source,
+            from: 0,
+        })
+    }
+}
+
+fn file_header() -> Vec<u8> {
+    let mut header = FILE_MAGIC.to_vec();
+    header.extend_from_slice(&VERSION.to_be_bytes());
+    header
+}
+
+/// Checks that the Segment file at `path` starts with a header naming the
+/// current format, leaving `file` at its first record.
+fn check_version(file: &mut File, path: &Path) -> io::Result<()> {
+    let mut header = vec![];
+    file.take(FILE_HEADER_SIZE).read_to_end(&mut header)?;
+    if header.len() < FILE_HEADER_SIZE as usize || !header.starts_with(FILE_MAGIC) {
+        let msg = format!("segment {:?} has no header", path);
+        return Err(io::Error::new(ErrorKind::InvalidData, msg));
+    }
+    match read_u32(&header[4..]) {
+        VERSION => Ok(()),
+        version => {
+            let msg = format!("segment {:?} has unknown version {}", path, version);
+            Err(io::Error::new(ErrorKind::InvalidData, msg))
+        }
+    }
+}
+
+/// A sealed Segment picked out for work done on its file in the background,
+/// such as compressing or archiving it, as it was when it was picked.
+///
+/// Every Segment gets a new generation whenever it is created or opened, and
+/// a Segment that is rewritten is opened again once the copy is installed. So
+/// even though a rewrite keeps the Segment's path, the generation tells the
+/// Log whether the file the work was based on is still the one in use.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Sealed {
+    pub base_offset: usize,
+    pub generation: u64,
+    pub path: PathBuf,
+}
+
+/// Maps a sealed Segment into memory. Empty Segments are not mapped.
+fn map(path: &Path, size: u64) -> io::Result<Option<Mmap>> {
+    if size == 0 {
+        return Ok(None);
+    }
+    let file = File::open(path)?;
+    // Safety: sealed Segments are never written to or truncated again. They
+    // are only ever replaced by renaming a new file over them, which leaves
+    // the mapped file intact until the map is dropped.
+    let map = unsafe { Mmap::map(&file)? };
+    Ok(Some(map))
+}
+
+/// A Message read back from a Segment, along with its offset, key and when it
+/// was added
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Record<'a> {
+    pub offset: usize,
+    pub timestamp: u64,
+    pub key: Option<Cow<'a, [u8]>>,
+    pub msg: Cow<'a, [u8]>,
+}
+
+impl<'a> Record<'a> {
+    /// Whether the Message has been replaced by a tombstone with `redact`.
+    /// Every Message added to a Log is valid CBOR, so only a tombstone is
+    /// ever empty.
+    pub fn is_redacted(&self) -> bool {
+        self.msg.is_empty()
+    }
+
+    pub fn into_owned(self) -> Record<'static> {
+        Record {
+            offset: self.offset,
+            timestamp: self.timestamp,
+            key: self.key.map(|k| Cow::Owned(k.into_owned())),
+            msg: Cow::Owned(self.msg.into_owned()),
+        }
+    }
+}
+
+/// Iterates over the Messages in a Segment. Only the Messages that were in the
+/// Segment when the reader was created are returned.
+///
+/// Messages read from a memory mapped Segment are borrowed from the map.
+/// Everything else is read into an owned buffer.
+pub struct SegmentReader<'a> {
+    base_offset: usize,
+    keyring: Arc<Keyring>,
+    source: Source<'a>,
+    /// Messages before this offset are skipped
+    from: usize,
+}
+
+enum Source<'a> {
+    File {
+        inner: BufReader<File>,
+        position: u64,
+        end: u64,
+        compression: Compression,
+        block: VecDeque<Record<'static>>,
+    },
+    Mapped {
+        data: &'a [u8],
+        position: usize,
+    },
+}
+
+impl<'a> SegmentReader<'a> {
+    fn read(&mut self) -> Option<io::Result<Record<'a>>> {
+        let res = match &mut self.source {
+            Source::Mapped { data, position } => {
+                if *position >= data.len() {
+                    return None;
+                }
+                parse_record(data, position, self.base_offset, &self.keyring)
+            }
+            Source::File {
+                inner,
+                position,
+                end,
+                compression,
+                block,
+            } => {
+                if let Some(record) = block.pop_front() {
+                    return Some(Ok(record));
+                }
+                if *position >= *end {
+                    return None;
+                }
+                let base_offset = self.base_offset;
+                read_file_record(
+                    inner,
+                    position,
+                    *compression,
+                    block,
+                    base_offset,
+                    &self.keyring,
+                )
+            }
+        };
+
+        // Nothing after a bad record can be trusted, so stop reading.
+        if res.is_err() {
+            self.stop();
+        }
+        Some(res)
+    }
+
+    fn stop(&mut self) {
+        match &mut self.source {
+            Source::Mapped { data, position } => *position = data.len(),
+            Source::File {
+                position,
+                end,
+                block,
+                ..
+            } => {
+                *position = *end;
+                block.clear();
+            }
+        }
+    }
+}
+
+impl<'a> Iterator for SegmentReader<'a> {
+    type Item = io::Result<Record<'a>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.read()? {
+                Ok(record) if record.offset < self.from => continue,
+                res => return Some(res),
+            }
+        }
+    }
+}
+
+/// Opens a reader over every Message in the Segment file at `path`, without
+/// opening the Segment itself.
+pub fn read_file(path: &Path, keyring: Arc<Keyring>) -> io::Result<SegmentReader<'static>> {
+    let (base_offset, compression) = parse_path(path).ok_or_else(|| {
+        let msg = format!("{:?} is not a segment", path);
+        io::Error::new(ErrorKind::InvalidInput, msg)
+    })?;
+    let mut file = File::open(path)?;
+    check_version(&mut file, path)?;
+    let end = file.metadata()?.len() - FILE_HEADER_SIZE;
+
+    Ok(SegmentReader {
+        base_offset,
+        keyring,
+        source: Source::File {
+            inner: BufReader::new(file),
+            position: 0,
+            end,
+            compression,
+            block: VecDeque::new(),
+        },
+        from: 0,
+    })
+}
+
+/// The fixed size part of a record
+struct Header {
+    len: u32,
+    crc: u32,
+    timestamp: u64,
+    offset: u32,
+    key_len: u16,
+    key_id: u16,
+}
+
+impl Header {
+    fn decode(buf: &[u8]) -> Self {
+        Header {
+            len: read_u32(&buf[..4]),
+            crc: read_u32(&buf[4..8]),
+            timestamp: read_u64(&buf[8..16]),
+            offset: read_u32(&buf[16..20]),
+            key_len: u16::from_be_bytes([buf[20], buf[21]]),
+            key_id: u16::from_be_bytes([buf[22], buf[23]]),
+        }
+    }
+}
+
+/// The part of a record's header covered by its checksum, which is also
+/// authenticated when the record is encrypted.
+fn authenticated(header: &[u8]) -> &[u8] {
+    &header[8..HEADER_SIZE]
+}
+
+/// Encodes a record, encrypting it with the Keyring's current key if it has
+/// one.
+fn encode_record(
+    offset: u32,
+    timestamp: u64,
+    key: &[u8],
+    msg: &[u8],
+    keyring: &Keyring,
+) -> io::Result<Vec<u8>> {
+    if key.len() > u16::MAX as usize {
+        return Err(io::Error::new(ErrorKind::InvalidInput, "key is too long"));
+    }
+
+    let key_id = keyring.current();
+    let mut buf = Vec::with_capacity(HEADER_SIZE + key.len() + msg.len());
+    buf.extend_from_slice(&[0; 8]);
+    buf.extend_from_slice(&timestamp.to_be_bytes());
+    buf.extend_from_slice(&offset.to_be_bytes());
+    buf.extend_from_slice(&(key.len() as u16).to_be_bytes());
+    buf.extend_from_slice(&key_id.to_be_bytes());
+
+    if key_id == PLAINTEXT {
+        buf.extend_from_slice(key);
+        buf.extend_from_slice(msg);
+    } else {
+        let mut plain = Vec::with_capacity(key.len() + msg.len());
+        plain.extend_from_slice(key);
+        plain.extend_from_slice(msg);
+        let encrypted = keyring.encrypt(key_id, &plain, authenticated(&buf))?;
+        buf.extend_from_slice(&encrypted);
+    }
+
+    let len = (buf.len() - HEADER_SIZE) as u32;
+    buf[..4].copy_from_slice(&len.to_be_bytes());
+    let crc = crc32fast::hash(&buf[8..]);
+    buf[4..8].copy_from_slice(&crc.to_be_bytes());
+    Ok(buf)
+}
+
+fn read_u32(buf: &[u8]) -> u32 {
+    u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]])
+}
+
+fn read_u64(buf: &[u8]) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&buf[..8]);
+    u64::from_be_bytes(bytes)
+}
+
+/// Checks a record's body against the checksum in its header.
+fn check(header: &[u8], body: &[u8]) -> io::Result<Header> {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(authenticated(header));
+    hasher.update(body);
+    let header_ = Header::decode(header);
+    if hasher.finalize() != header_.crc {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "record failed checksum",
+        ));
+    }
+    Ok(header_)
+}
+
+/// Turns a checked record back into a Record, decrypting it if needed. Only
+/// unencrypted records can borrow from `body`.
+fn decode<'a>(
+    header: &[u8],
+    body: Cow<'a, [u8]>,
+    base_offset: usize,
+    keyring: &Keyring,
+) -> io::Result<Record<'a>> {
+    let fields = Header::decode(header);
+    let body = match fields.key_id {
+        PLAINTEXT => body,
+        id => Cow::Owned(keyring.decrypt(id, &body, authenticated(header))?),
+    };
+
+    let key_len = fields.key_len as usize;
+    if key_len > body.len() {
+        return Err(io::Error::new(ErrorKind::InvalidData, "key is too long"));
+    }
+    let (key, msg) = match body {
+        Cow::Borrowed(body) => {
+            let (key, msg) = body.split_at(key_len);
+            (Cow::Borrowed(key), Cow::Borrowed(msg))
+        }
+        Cow::Owned(mut key) => {
+            let msg = key.split_off(key_len);
+            (Cow::Owned(key), Cow::Owned(msg))
+        }
+    };
+
+    Ok(Record {
+        offset: base_offset + fields.offset as usize,
+        timestamp: fields.timestamp,
+        key: Some(key).filter(|k| !k.is_empty()),
+        msg,
+    })
+}
+
+/// Reads the record at `position` in `data`, moving `position` on to the next
+/// record.
+fn parse_record<'a>(
+    data: &'a [u8],
+    position: &mut usize,
+    base_offset: usize,
+    keyring: &Keyring,
+) -> io::Result<Record<'a>> {
+    let start = *position + HEADER_SIZE;
+    let header = data.get(*position..start).ok_or(ErrorKind::UnexpectedEof)?;
+    let end = start + Header::decode(header).len as usize;
+    let body = data.get(start..end).ok_or(ErrorKind::UnexpectedEof)?;
+    check(header, body)?;
+    *position = end;
+
+    decode(header, Cow::Borrowed(body), base_offset, keyring)
+}
+
+/// Reads the next record from a Segment file at `position`. Blocks in a
+/// compressed Segment are decompressed into `block`, and their first Message
+/// returned.
+fn read_file_record(
+    inner: &mut impl Read,
+    position: &mut u64,
+    compression: Compression,
+    block: &mut VecDeque<Record<'static>>,
+    base_offset: usize,
+    keyring: &Keyring,
+) -> io::Result<Record<'static>> {
+    let (header, body) = match read_record(inner)? {
+        Some((header, body)) => (header, body),
+        None => return Err(ErrorKind::UnexpectedEof.into()),
+    };
+    *position += (HEADER_SIZE + body.len()) as u64;
+    check(&header, &body)?;
+    let mut record = decode(&header, Cow::Owned(body), base_offset, keyring)?;
+
+    if compression == Compression::None {
+        return Ok(record);
+    }
+    *block = decode_block(compression, &record.msg, base_offset)?;
+    record = block
+        .pop_front()
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "block is empty"))?;
+    Ok(record)
+}
+
+/// Reads the header and body of a single record. Returns None if there is not
+/// a complete record left to read.
+fn read_record(reader: &mut impl Read) -> io::Result<Option<([u8; HEADER_SIZE], Vec<u8>)>> {
+    let mut header = [0; HEADER_SIZE];
+    match reader.read_exact(&mut header) {
+        Ok(_) => (),
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let len = Header::decode(&header).len;
+
+    // The length itself may be garbage, so read through `take` rather than
+    // allocating whatever it claims up front.
+    let mut body = vec![];
+    reader.take(len as u64).read_to_end(&mut body)?;
+    if body.len() < len as usize {
+        return Ok(None);
+    }
+    Ok(Some((header, body)))
+}
+
+/// What was found while reading through a Segment's records
+struct Scan {
+    size: u64,
+    /// One more than the relative offset of the last record
+    span: usize,
+    entries: Vec<IndexEntry>,
+}
+
+/// Reads through every record in the Segment at `path`, building its index
+/// along the way. Stops at the end of the file or at a record that was only
+/// partially written. Also returns the size of the file, which will be larger
+/// than the scanned size if the scan stopped early.
+///
+/// A record running past the end of the file is only taken to be a torn write
+/// if nothing intact follows its header, since a torn write is always the last
+/// thing in the file. Otherwise its length must have been corrupted.
+fn scan(path: &Path) -> io::Result<(Scan, u64)> {
+    let mut file = File::open(path)?;
+    check_version(&mut file, path)?;
+    let file_size = file.metadata()?.len() - FILE_HEADER_SIZE;
+    let mut reader = BufReader::new(file);
+
+    let mut scan = Scan {
+        size: 0,
+        span: 0,
+        entries: vec![],
+    };
+    while let Some((header, body)) = read_record(&mut reader)? {
+        let end = scan.size + (HEADER_SIZE + body.len()) as u64;
+        let record = match check(&header, &body) {
+            Ok(record) if record.offset as usize >= scan.span || scan.size == 0 => record,
+            Err(e) if e.kind() == ErrorKind::InvalidData && end >= file_size => break,
+            _ => {
+                let msg = format!("corrupt record at byte {} of segment {:?}", scan.size, path);
+                return Err(io::Error::new(ErrorKind::InvalidData, msg));
+            }
+        };
+
+        if index::needs_entry(scan.entries.last(), scan.size) {
+            scan.entries.push(IndexEntry {
+                offset: record.offset,
+                position: scan.size,
+                timestamp: record.timestamp,
+            });
+        }
+        scan.size = end;
+        scan.span = record.offset as usize + 1;
+    }
+
+    if scan.size < file_size {
+        let mut file = File::open(path)?;
+        file.seek(SeekFrom::Start(FILE_HEADER_SIZE + scan.size))?;
+        let mut tail = vec![];
+        file.read_to_end(&mut tail)?;
+        if intact_record_after(&tail, scan.span) {
+            let msg = format!("corrupt record at byte {} of segment {:?}", scan.size, path);
+            return Err(io::Error::new(ErrorKind::InvalidData, msg));
+        }
+    }
+
+    Ok((scan, file_size))
+}
+
+/// Whether a record that passes its checksum starts anywhere in `tail` after
+/// the header of its first record. Records with offsets below `span` are
+/// ignored, since they can't come after the records already scanned.
+fn intact_record_after(tail: &[u8], span: usize) -> bool {
+    let last = tail.len().saturating_sub(HEADER_SIZE);
+    (HEADER_SIZE..=last).any(|start| {
+        let header = &tail[start..start + HEADER_SIZE];
+        let fields = Header::decode(header);
+        let end = start + HEADER_SIZE + fields.len as usize;
+        fields.offset as usize >= span
+            && end <= tail.len()
+            && check(header, &tail[start + HEADER_SIZE..end]).is_ok()
+    })
+}
+
+/// Writes a copy of the sealed Segment at `path` holding only the Messages
+/// `keep` returns true for, compressed with `codec`. Returns the path of the
+/// copy, which is left under a temporary name so that the original can still
+/// be read until the copy is moved into place with `install`. The original's
+/// modification time is kept, since Retention relies on it.
+///
+/// The copy is encrypted with the Keyring's current key, so rewriting also
+/// moves Messages off of keys that have been rotated out.
+pub fn rewrite(
+    path: &Path,
+    codec: Compression,
+    keyring: &Arc<Keyring>,
+    mut keep: impl FnMut(&Record) -> bool,
+) -> io::Result<PathBuf> {
+    rewrite_with(path, codec, keyring, |record| {
+        if keep(&record) {
+            Some(record)
+        } else {
+            None
+        }
+    })
+}
+
+/// Writes a copy of the sealed Segment at `path` with the Message at `offset`
+/// replaced by a tombstone, keeping its offset, timestamp and key. Returns the
+/// path of the copy, just like `rewrite`. Fails with `NotFound` if the Segment
+/// has no Message at `offset`.
+pub fn redact(
+    path: &Path,
+    codec: Compression,
+    keyring: &Arc<Keyring>,
+    offset: usize,
+) -> io::Result<PathBuf> {
+    let mut found = false;
+    let copy = rewrite_with(path, codec, keyring, |mut record| {
+        if record.offset == offset {
+            record.msg = Cow::Borrowed(&[]);
+            found = true;
+        }
+        Some(record)
+    })?;
+    if !found {
+        fs::remove_file(&copy)?;
+        let msg = format!("segment {:?} has no message {}", path, offset);
+        return Err(io::Error::new(ErrorKind::NotFound, msg));
+    }
+    Ok(copy)
+}
+
+/// Like `rewrite`, but each Message is passed through `edit`, which may change
+/// anything but its offset, or drop it by returning None.
+fn rewrite_with(
+    path: &Path,
+    codec: Compression,
+    keyring: &Arc<Keyring>,
+    mut edit: impl FnMut(Record<'static>) -> Option<Record<'static>>,
+) -> io::Result<PathBuf> {
+    // Every copy gets a name of its own, so rewrites of the same Segment that
+    // overlap never write to the same file.
+    let tmp = path.with_extension(format!(
+        "{}.{}.{}",
+        compression::extension(codec),
+        NEXT_COPY.fetch_add(1, Ordering::Relaxed),
+        TMP_EXT
+    ));
+
+    let modified = path.metadata()?.modified()?;
+    let mut writer = BufWriter::new(File::create(&tmp)?);
+    writer.write_all(&file_header())?;
+    let mut block = Vec::with_capacity(BLOCK_SIZE);
+    let mut first: Option<(u32, u64)> = None;
+    let mut count: u32 = 0;
+    let base_offset = parse_path(path).map(|(base, _)| base).unwrap_or(0);
+    let plain = Keyring::default();
+    for record in read_file(path, keyring.clone())? {
+        let record = match edit(record?) {
+            Some(record) => record,
+            None => continue,
+        };
+
+        let offset = (record.offset - base_offset) as u32;
+        let key = record.key.as_deref().unwrap_or(&[]);
+        if codec == Compression::None {
+            writer.write_all(&encode_record(
+                offset,
+                record.timestamp,
+                key,
+                &record.msg,
+                keyring,
+            )?)?;
+            continue;
+        }
+
+        let encoded = encode_record(offset, record.timestamp, key, &record.msg, &plain)?;
+        first.get_or_insert((offset, record.timestamp));
+        block.extend_from_slice(&encoded);
+        count += 1;
+        if block.len() >= BLOCK_SIZE {
+            let (offset, timestamp) = first.take().expect("block should have a first message");
+            write_block(
+                &mut writer,
+                codec,
+                keyring,
+                count,
+                offset,
+                timestamp,
+                &block,
+            )?;
+            block.clear();
+            count = 0;
+        }
+    }
+    if let Some((offset, timestamp)) = first {
+        write_block(
+            &mut writer,
+            codec,
+            keyring,
+            count,
+            offset,
+            timestamp,
+            &block,
+        )?;
+    }
+
+    let file = writer.into_inner()?;
+    file.set_modified(modified)?;
+    file.sync_all()?;
+    Ok(tmp)
+}
+
+/// Moves the copy of the Segment at `source` written by `rewrite` into place,
+/// returning its new path. A copy with the same codec replaces the original,
+/// whose index is removed first so a stale index is never left behind.
+pub fn install(copy: &Path, source: &Path) -> io::Result<PathBuf> {
+    let dest = copy.with_extension("").with_extension("");
+    if dest == source {
+        match fs::remove_file(source.with_extension(INDEX_EXT)) {
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+            _ => (),
+        }
+    }
+    fs::rename(copy, &dest)?;
+    if let Some(dir) = dest.parent() {
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(dest)
+}
+
+/// Writes a compressed copy of the sealed Segment at `path`, returning the
+/// path of the copy. The original Segment is left untouched.
+pub fn compress(path: &Path, codec: Compression, keyring: &Arc<Keyring>) -> io::Result<PathBuf> {
+    rewrite(path, codec, keyring, |_| true)
+}
+
+fn write_block(
+    writer: &mut impl Write,
+    codec: Compression,
+    keyring: &Keyring,
+    count: u32,
+    offset: u32,
+    timestamp: u64,
+    block: &[u8],
+) -> io::Result<()> {
+    let mut payload = count.to_be_bytes().to_vec();
+    payload.extend_from_slice(&compression::compress(codec, block)?);
+    writer.write_all(&encode_record(offset, timestamp, &[], &payload, keyring)?)
+}
+
+/// Splits a block written by `write_block` back into its records.
+fn decode_block(
+    codec: Compression,
+    block: &[u8],
+    base_offset: usize,
+) -> io::Result<VecDeque<Record<'static>>> {
+    if block.len() < 4 {
+        return Err(io::Error::new(ErrorKind::InvalidData, "malformed block"));
+    }
+
+    let count = read_u32(block) as usize;
+    let data = compression::decompress(codec, &block[4..])?;
+    let mut records = VecDeque::with_capacity(count);
+    let mut position = 0;
+    for _ in 0..count {
+        let record = parse_record(&data, &mut position, base_offset, &Keyring::default())?;
+        records.push_back(record.into_owned());
+    }
+    Ok(records)
+}
+
+fn segment_path(dir: &Path, base_offset: usize, codec: Compression) -> PathBuf {
+    dir.join(format!(
+        "{:020}.{}",
+        base_offset,
+        compression::extension(codec)
+    ))
+}
+
+/// Parses the base offset and compression out of a Segment's file name.
+/// Returns None if the path is not a Segment.
+pub fn parse_path(path: &Path) -> Option<(usize, Compression)> {
+    let codec = compression::from_extension(path.extension()?.to_str()?)?;
+    let base = path.file_stem()?.to_str()?.parse().ok()?;
+    Some((base, codec))
+}
+
+/// The path of the index file kept alongside the uncompressed Segment at
+/// `path`
+pub fn index_path(path: &Path) -> PathBuf {
+    path.with_extension(INDEX_EXT)
+}
+
+/// Removes the Segment file at `path`, along with its index if it has one.
+pub fn remove(path: &Path) -> io::Result<()> {
+    fs::remove_file(path)?;
+    match fs::remove_file(path.with_extension(INDEX_EXT)) {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Whether `path` is a temporary file left behind by an interrupted write
+pub fn is_tmp(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == TMP_EXT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn plain() -> Arc<Keyring> {
+        Arc::new(Keyring::default())
+    }
+
+    #[test]
+    fn test_segment_append_and_read() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut seg = Segment::create(dir.path(), 0, plain()).unwrap();
+        seg.append(&[], b"one", 1).unwrap();
+        seg.append(&[], b"two", 1).unwrap();
+        seg.append(&[], b"three", 1).unwrap();
+        assert_eq!(seg.next_offset(), 3);
+        assert_eq!(seg.size(), 3 * 24 + 11);
+
+        let msgs: Vec<Vec<u8>> = seg
+            .reader(1)
+            .unwrap()
+            .map(|m| m.unwrap().msg.into_owned())
+            .collect();
+        assert_eq!(msgs, vec![b"two".to_vec(), b"three".to_vec()]);
+    }
+
+    #[test]
+    fn test_segment_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut seg = Segment::create(dir.path(), 42, plain()).unwrap();
+        seg.append(&[], b"one", 1).unwrap();
+        seg.append(&[], b"two", 1).unwrap();
+        let path = segment_path(dir.path(), 42, Compression::None);
+        assert_eq!(parse_path(&path), Some((42, Compression::None)));
+
+        let mut seg = Segment::open_active(path, 42, plain()).unwrap();
+        assert_eq!(seg.next_offset(), 44);
+        seg.append(&[], b"three", 1).unwrap();
+
+        let msgs: Vec<Vec<u8>> = seg
+            .reader(0)
+            .unwrap()
+            .map(|m| m.unwrap().msg.into_owned())
+            .collect();
+        assert_eq!(msgs.len(), 3);
+        assert_eq!(msgs[2], b"three".to_vec());
+    }
+
+    #[test]
+    fn test_segment_truncate() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut seg = Segment::create(dir.path(), 10, plain()).unwrap();
+        for msg in [&b"one"[..], b"two", b"three"].iter() {
+            seg.append(&[], msg, 1).unwrap();
+        }
+        seg.truncate(11).unwrap();
+        assert_eq!((seg.next_offset(), seg.size()), (11, 24 + 3));
+        seg.append(&[], b"four", 1).unwrap();
+
+        let path = segment_path(dir.path(), 10, Compression::None);
+        let seg = Segment::open_active(path, 10, plain()).unwrap();
+        let msgs: Vec<Vec<u8>> = seg
+            .reader(0)
+            .unwrap()
+            .map(|m| m.unwrap().msg.into_owned())
+            .collect();
+        assert_eq!(msgs, vec![b"one".to_vec(), b"four".to_vec()]);
+    }
+
+    #[test]
+    fn test_segment_truncates_torn_write() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut seg = Segment::create(dir.path(), 0, plain()).unwrap();
+        seg.append(&[], b"one", 1).unwrap();
+        seg.append(&[], b"two", 1).unwrap();
+        let size = seg.size();
+        let path = segment_path(dir.path(), 0, Compression::None);
+
+        // A header claiming more bytes than were written
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[0, 0, 0, 9, 1, 2, 3, 4, b't', b'h'])
+            .unwrap();
+
+        let mut seg = Segment::open_active(path.clone(), 0, plain()).unwrap();
+        assert_eq!(seg.next_offset(), 2);
+        assert_eq!(path.metadata().unwrap().len(), FILE_HEADER_SIZE + size);
+
+        // A complete record whose bytes never made it to disk
+        seg.append(&[], b"three", 1).unwrap();
+        let mut bytes = fs::read(&path).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] = 0;
+        fs::write(&path, &bytes).unwrap();
+
+        let mut seg = Segment::open_active(path.clone(), 0, plain()).unwrap();
+        assert_eq!(seg.next_offset(), 2);
+        seg.append(&[], b"four", 1).unwrap();
+        let msgs: Vec<Vec<u8>> = seg
+            .reader(0)
+            .unwrap()
+            .map(|m| m.unwrap().msg.into_owned())
+            .collect();
+        assert_eq!(msgs[2], b"four".to_vec());
+    }
+
+    #[test]
+    fn test_segment_refuses_corruption() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut seg = Segment::create(dir.path(), 0, plain()).unwrap();
+        seg.append(&[], b"one", 1).unwrap();
+        seg.append(&[], b"two", 1).unwrap();
+        let path = segment_path(dir.path(), 0, Compression::None);
+
+        let mut bytes = fs::read(&path).unwrap();
+        bytes[FILE_HEADER_SIZE as usize + HEADER_SIZE] = b'x';
+        fs::write(&path, &bytes).unwrap();
+
+        let err = Segment::open_active(path.clone(), 0, plain()).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+        let sealed = Segment::open_sealed(path, 0, 2, plain()).unwrap();
+        let err = sealed.reader(0).unwrap().next().unwrap().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_segment_refuses_unknown_format() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut seg = Segment::create(dir.path(), 0, plain()).unwrap();
+        seg.append(&[], b"one", 1).unwrap();
+        let path = segment_path(dir.path(), 0, Compression::None);
+        let bytes = fs::read(&path).unwrap();
+
+        // Files without a header aren't guessed at
+        fs::write(&path, &bytes[FILE_HEADER_SIZE as usize..]).unwrap();
+        let err = Segment::open_active(path.clone(), 0, plain()).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        let err = Segment::open_sealed(path.clone(), 0, 1, plain()).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+        let mut newer = bytes.clone();
+        newer[4..8].copy_from_slice(&(VERSION + 1).to_be_bytes());
+        fs::write(&path, &newer).unwrap();
+        let err = Segment::open_active(path, 0, plain()).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_segment_refuses_corrupt_length() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut seg = Segment::create(dir.path(), 0, plain()).unwrap();
+        seg.append(&[], b"one", 1).unwrap();
+        seg.append(&[], b"two", 1).unwrap();
+        seg.append(&[], b"three", 1).unwrap();
+        let path = segment_path(dir.path(), 0, Compression::None);
+
+        // The second record claims to run past the end of the file, which
+        // would look like a torn write if the third weren't intact after it
+        let mut bytes = fs::read(&path).unwrap();
+        let second = FILE_HEADER_SIZE as usize + HEADER_SIZE + 3;
+        bytes[second..second + 4].copy_from_slice(&1000u32.to_be_bytes());
+        fs::write(&path, &bytes).unwrap();
+
+        let err = Segment::open_active(path.clone(), 0, plain()).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert_eq!(path.metadata().unwrap().len(), bytes.len() as u64);
+    }
+
+    #[test]
+    fn test_segment_index() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut seg = Segment::create(dir.path(), 0, plain()).unwrap();
+        for i in 0..2000u32 {
+            seg.append(&[], &i.to_be_bytes(), i as u64).unwrap();
+        }
+        seg.seal().unwrap();
+        assert!(seg.index.lookup(1500).offset > 0);
+
+        let check = |seg: &Segment| {
+            for &skip in [0, 1, 340, 341, 1500, 1999].iter() {
+                let record = seg.reader(skip).unwrap().next().unwrap().unwrap();
+                assert_eq!(record.msg, (skip as u32).to_be_bytes().to_vec());
+                assert_eq!(record.timestamp, skip as u64);
+            }
+            assert!(seg.reader(2000).unwrap().next().is_none());
+        };
+        check(&seg);
+
+        let path = segment_path(dir.path(), 0, Compression::None);
+        let index_path = path.with_extension(INDEX_EXT);
+        let loaded = Segment::open_sealed(path.clone(), 0, 2000, plain()).unwrap();
+        check(&loaded);
+
+        // A missing index is rebuilt from the Segment
+        let index_bytes = fs::read(&index_path).unwrap();
+        fs::remove_file(&index_path).unwrap();
+        let rebuilt = Segment::open_sealed(path.clone(), 0, 2000, plain()).unwrap();
+        check(&rebuilt);
+        assert_eq!(fs::read(&index_path).unwrap(), index_bytes);
+
+        // As is the index of the active Segment
+        let active = Segment::open_active(path, 0, plain()).unwrap();
+        check(&active);
+    }
+
+    #[test]
+    fn test_segment_compress() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut seg = Segment::create(dir.path(), 100, plain()).unwrap();
+        for i in 0..20_000u32 {
+            seg.append(&[], &i.to_be_bytes(), i as u64).unwrap();
+        }
+        seg.seal().unwrap();
+
+        for &codec in [Compression::Lz4, Compression::Zstd].iter() {
+            let copy = compress(seg.path(), codec, &plain()).unwrap();
+            let path = install(&copy, seg.path()).unwrap();
+            assert_eq!(parse_path(&path), Some((100, codec)));
+
+            let compressed =
+                Segment::open_compressed(path, 100, codec, Some(20_000), plain()).unwrap();
+            assert!(compressed.size() < seg.size());
+            for &skip in [0, 1, 6000, 12_000, 19_999].iter() {
+                let mut reader = compressed.reader(100 + skip).unwrap();
+                let record = reader.next().unwrap().unwrap();
+                assert_eq!(record.msg, (skip as u32).to_be_bytes().to_vec());
+                assert_eq!(record.timestamp, skip as u64);
+                assert_eq!(record.offset, 100 + skip);
+                assert_eq!(reader.count(), 20_000 - skip - 1);
+            }
+        }
+    }
+
+    #[test]
+    fn test_segment_compressed_corruption() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut seg = Segment::create(dir.path(), 0, plain()).unwrap();
+        seg.append(&[], b"one", 1).unwrap();
+        seg.append(&[], b"two", 1).unwrap();
+        seg.seal().unwrap();
+
+        let path = compress(seg.path(), Compression::Lz4, &plain()).unwrap();
+        let mut bytes = fs::read(&path).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xff;
+        fs::write(&path, &bytes).unwrap();
+
+        let compressed =
+            Segment::open_compressed(path.clone(), 0, Compression::Lz4, Some(2), plain()).unwrap();
+        let err = compressed.reader(0).unwrap().next().unwrap().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+        // Finding where the Segment ends means reading its last block
+        let err =
+            Segment::open_compressed(path.clone(), 0, Compression::Lz4, None, plain()).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+        // Blocks past the end of the Segment are refused
+        assert!(Segment::open_compressed(path, 0, Compression::Lz4, Some(0), plain()).is_err());
+    }
+
+    #[test]
+    fn test_segment_sealed_reads_are_borrowed() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut seg = Segment::create(dir.path(), 0, plain()).unwrap();
+        seg.append(&[], b"one", 1).unwrap();
+        seg.append(&[], b"two", 1).unwrap();
+        let record = seg.reader(0).unwrap().next().unwrap().unwrap();
+        assert!(matches!(record.msg, Cow::Owned(_)));
+
+        seg.seal().unwrap();
+        let msgs: Vec<Cow<[u8]>> = seg.reader(1).unwrap().map(|m| m.unwrap().msg).collect();
+        assert_eq!(msgs, vec![Cow::Borrowed(&b"two"[..])]);
+        assert!(matches!(msgs[0], Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn test_segment_offset_for_time() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut seg = Segment::create(dir.path(), 0, plain()).unwrap();
+        assert_eq!(seg.first_timestamp(), None);
+        assert_eq!(seg.last_timestamp().unwrap(), None);
+
+        // Each timestamp is shared by two Messages
+        for i in 0..2000u32 {
+            seg.append(&[], &i.to_be_bytes(), 100 + (i / 2) as u64)
+                .unwrap();
+        }
+        assert_eq!(seg.first_timestamp(), Some(100));
+        assert_eq!(seg.last_timestamp().unwrap(), Some(1099));
+
+        assert_eq!(seg.offset_for_time(0).unwrap(), Some(0));
+        assert_eq!(seg.offset_for_time(101).unwrap(), Some(2));
+        assert_eq!(seg.offset_for_time(850).unwrap(), Some(1500));
+        assert_eq!(seg.offset_for_time(1099).unwrap(), Some(1998));
+        assert_eq!(seg.offset_for_time(1100).unwrap(), None);
+
+        seg.seal().unwrap();
+        let path = compress(seg.path(), Compression::Zstd, &plain()).unwrap();
+        let compressed =
+            Segment::open_compressed(path, 0, Compression::Zstd, None, plain()).unwrap();
+        assert_eq!(compressed.first_timestamp(), Some(100));
+        assert_eq!(compressed.last_timestamp().unwrap(), Some(1099));
+        assert_eq!(compressed.offset_for_time(850).unwrap(), Some(1500));
+    }
+
+    #[test]
+    fn test_segment_sealed_rejects_append() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut seg = Segment::create(dir.path(), 0, plain()).unwrap();
+        seg.seal().unwrap();
+        assert!(seg.append(&[], b"one", 1).is_err());
+    }
+
+    #[test]
+    fn test_segment_keys() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut seg = Segment::create(dir.path(), 0, plain()).unwrap();
+        seg.append(b"a", b"one", 1).unwrap();
+        seg.append(&[], b"two", 1).unwrap();
+        assert_eq!(seg.size(), 2 * 24 + 7);
+
+        let check = |seg: &Segment| {
+            let records: Vec<Record> = seg.reader(0).unwrap().map(|r| r.unwrap()).collect();
+            assert_eq!(records[0].key.as_deref(), Some(&b"a"[..]));
+            assert_eq!(records[0].msg, b"one".to_vec());
+            assert_eq!(records[1].key, None);
+            assert_eq!(records[1].msg, b"two".to_vec());
+        };
+        check(&seg);
+        seg.seal().unwrap();
+        check(&seg);
+    }
+
+    #[test]
+    fn test_segment_encryption() {
+        let dir = tempfile::tempdir().unwrap();
+        let keys = Arc::new(Keyring::with_test_keys(&[1]));
+        let mut seg = Segment::create(dir.path(), 0, keys.clone()).unwrap();
+        assert_eq!(seg.entry_size(b"k", b"secret"), (24 + 40 + 7) as u64);
+        seg.append(b"k", b"secret", 1).unwrap();
+        seg.append(&[], b"two", 1).unwrap();
+        assert_eq!(seg.size(), 2 * (24 + 40) + 10);
+        let path = seg.path().to_path_buf();
+        let bytes = fs::read(&path).unwrap();
+        assert!(!bytes.windows(6).any(|w| w == b"secret"));
+
+        let read = |seg: &Segment| -> Vec<Record<'static>> {
+            seg.reader(0)
+                .unwrap()
+                .map(|r| r.unwrap().into_owned())
+                .collect()
+        };
+        let records = read(&seg);
+        assert_eq!(records[0].key.as_deref(), Some(&b"k"[..]));
+        assert_eq!(records[0].msg, b"secret".to_vec());
+        assert_eq!(records[1].msg, b"two".to_vec());
+
+        // Messages can't be read without their key
+        let seg = Segment::open_active(path.clone(), 0, plain()).unwrap();
+        let err = seg.reader(0).unwrap().next().unwrap().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+        // After a rotation, new Messages use the new key and old ones can
+        // still be read
+        let rotated = Arc::new(Keyring::with_test_keys(&[1, 2]));
+        let mut seg = Segment::open_active(path.clone(), 0, rotated.clone()).unwrap();
+        seg.append(&[], b"three", 1).unwrap();
+        seg.seal().unwrap();
+        assert_eq!(read(&seg).len(), 3);
+        let only_new = Arc::new(Keyring::with_test_keys(&[2]));
+        let seg = Segment::open_sealed(path.clone(), 0, 3, only_new.clone()).unwrap();
+        assert!(seg.reader(0).unwrap().next().unwrap().is_err());
+
+        // Rewriting moves every Message onto the current key
+        let compressed = compress(&path, Compression::Zstd, &rotated).unwrap();
+        let seg =
+            Segment::open_compressed(compressed, 0, Compression::Zstd, Some(3), only_new).unwrap();
+        assert_eq!(
+            read(&seg),
+            read(&Segment::open_sealed(path, 0, 3, rotated).unwrap())
+        );
+    }
+
+    #[test]
+    fn test_segment_rewrite() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut seg = Segment::create(dir.path(), 10, plain()).unwrap();
+        for i in 0..6u32 {
+            seg.append(&[], &i.to_be_bytes(), i as u64).unwrap();
+        }
+        seg.seal().unwrap();
+        let path = seg.path().to_path_buf();
+        drop(seg);
+
+        // Dropped Messages leave gaps in the offsets. The original is left as
+        // it is until the copy is installed.
+        let original = fs::read(&path).unwrap();
+        let keep = |r: &Record| r.offset.is_multiple_of(2);
+        let copy = rewrite(&path, Compression::None, &plain(), keep).unwrap();
+        let again = rewrite(&path, Compression::None, &plain(), keep).unwrap();
+        assert_ne!(copy, again);
+        assert_eq!(fs::read(&path).unwrap(), original);
+        fs::remove_file(again).unwrap();
+        assert_eq!(install(&copy, &path).unwrap(), path);
+        assert!(!copy.exists());
+        let seg = Segment::open_sealed(path.clone(), 10, 16, plain()).unwrap();
+        let offsets: Vec<usize> = seg.reader(11).unwrap().map(|r| r.unwrap().offset).collect();
+        assert_eq!(offsets, vec![12, 14]);
+        assert_eq!(seg.offset_for_time(3).unwrap(), Some(14));
+        assert_eq!(seg.next_offset(), 16);
+
+        let copy = rewrite(&path, Compression::Lz4, &plain(), |r| r.offset != 12).unwrap();
+        let compressed = install(&copy, &path).unwrap();
+        assert!(path.exists());
+        let seg =
+            Segment::open_compressed(compressed, 10, Compression::Lz4, None, plain()).unwrap();
+        let offsets: Vec<usize> = seg.reader(0).unwrap().map(|r| r.unwrap().offset).collect();
+        assert_eq!(offsets, vec![10, 14]);
+        assert_eq!(seg.next_offset(), 15);
+    }
+
+    #[test]
+    fn test_segment_redact() {
+        let dir = tempfile::tempdir().unwrap();
+        let keys = Arc::new(Keyring::with_test_keys(&[1]));
+        let mut seg = Segment::create(dir.path(), 10, keys.clone()).unwrap();
+        seg.append(b"a", b"one", 1).unwrap();
+        seg.append(b"b", b"secret", 2).unwrap();
+        seg.append(&[], b"three", 3).unwrap();
+        seg.seal().unwrap();
+        let path = seg.path().to_path_buf();
+        drop(seg);
+
+        let redacted = redact(&path, Compression::None, &keys, 11).unwrap();
+        assert_eq!(install(&redacted, &path).unwrap(), path);
+        assert!(!fs::read(&path).unwrap().windows(6).any(|w| w == b"secret"));
+        let seg = Segment::open_sealed(path.clone(), 10, 13, keys.clone()).unwrap();
+        let records: Vec<Record> = seg.reader(10).unwrap().map(|r| r.unwrap()).collect();
+        assert_eq!(records.len(), 3);
+        assert!(!records[0].is_redacted());
+        assert!(records[1].is_redacted());
+        assert_eq!((records[1].offset, records[1].timestamp), (11, 2));
+        assert_eq!(records[1].key.as_deref(), Some(&b"b"[..]));
+
+        // Compressed Segments keep their codec
+        let compressed =
+            install(&compress(&path, Compression::Zstd, &keys).unwrap(), &path).unwrap();
+        let redacted = redact(&compressed, Compression::Zstd, &keys, 12).unwrap();
+        assert_eq!(install(&redacted, &compressed).unwrap(), compressed);
+        let redacted: Vec<bool> = read_file(&compressed, keys.clone())
+            .unwrap()
+            .map(|r| r.unwrap().is_redacted())
+            .collect();
+        assert_eq!(redacted, vec![false, true, true]);
+
+        let err = redact(&compressed, Compression::Zstd, &keys, 13).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 2);
+    }
+}
@@ -0,0 +1,416 @@
+use super::archive::Archive;
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{self, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Lists every file in a Snapshot, so it can be verified before it is used
+pub const CHECKSUMS_FILE: &str = "CHECKSUMS";
+const TMP_EXT: &str = "tmp";
+
+/// A point-in-time copy of the database, laid out like the data directory so
+/// the server can be started from it.
+///
+/// A Snapshot is taken in two steps. While the DB is locked, files that are
+/// never modified in place, like sealed Segments and the Manifest, are hard
+/// linked in, and the active Segments are opened and their current sizes
+/// noted. Everything slower is left to `finish`, which runs without the lock:
+/// copying the active Segments up to the noted sizes, and fetching archived
+/// Segments so the Snapshot doesn't depend on the Archive.
+///
+/// The Snapshot is built in a temporary directory that is only renamed into
+/// place once every file, and the CHECKSUMS file listing them, is durable.
+#[derive(Debug)]
+pub struct Snapshot {
+    tmp: PathBuf,
+    path: PathBuf,
+    tails: Vec<Tail>,
+    fetches: Vec<Fetch>,
+}
+
+/// The start of a file that is still being appended to
+#[derive(Debug)]
+struct Tail {
+    file: File,
+    len: u64,
+    dest: PathBuf,
+}
+
+#[derive(Debug)]
+struct Fetch {
+    archive: Arc<dyn Archive>,
+    name: String,
+    dest: PathBuf,
+}
+
+impl Snapshot {
+    /// Starts a Snapshot called `name` in `dir`. Fails with `AlreadyExists` if
+    /// there is already a Snapshot with that name.
+    pub fn begin(dir: &Path, name: &str) -> io::Result<Self> {
+        let path = dir.join(name);
+        if path.exists() {
+            let msg = format!("snapshot {:?} already exists", path);
+            return Err(io::Error::new(ErrorKind::AlreadyExists, msg));
+        }
+
+        // Anything left from an interrupted Snapshot with the same name is
+        // incomplete.
+        let tmp = path.with_extension(TMP_EXT);
+        match fs::remove_dir_all(&tmp) {
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+            _ => (),
+        }
+        fs::create_dir_all(&tmp)?;
+
+        Ok(Snapshot {
+            tmp,
+            path,
+            tails: vec![],
+            fetches: vec![],
+        })
+    }
+
+    /// Adds the file at `src`, which must never be modified in place, as
+    /// `dest` within the Snapshot. The file is hard linked if it can be, and
+    /// copied otherwise.
+    pub fn link(&mut self, src: &Path, dest: &Path) -> io::Result<()> {
+        let dest = self.dest(dest)?;
+        if let Err(e) = fs::hard_link(src, &dest) {
+            debug!("could not link {:?} into snapshot, copying: {}", src, e);
+            fs::copy(src, &dest)?;
+            File::open(&dest)?.sync_all()?;
+        }
+        Ok(())
+    }
+
+    /// Adds the first `len` bytes of the file at `src` as `dest`. The file is
+    /// opened now, so it is copied even if it is removed before `finish`.
+    pub fn copy_tail(&mut self, src: &Path, len: u64, dest: &Path) -> io::Result<()> {
+        let file = File::open(src)?;
+        let dest = self.dest(dest)?;
+        self.tails.push(Tail { file, len, dest });
+        Ok(())
+    }
+
+    /// Adds the file stored in `archive` as `name` as `dest`.
+    pub fn fetch(
+        &mut self,
+        archive: Arc<dyn Archive>,
+        name: String,
+        dest: &Path,
+    ) -> io::Result<()> {
+        let dest = self.dest(dest)?;
+        self.fetches.push(Fetch {
+            archive,
+            name,
+            dest,
+        });
+        Ok(())
+    }
+
+    /// Copies everything left to copy, then moves the finished Snapshot into
+    /// place and returns its path. The Snapshot is removed if anything fails.
+    pub fn finish(self) -> io::Result<PathBuf> {
+        let tmp = self.tmp.clone();
+        self.write().inspect_err(|_| {
+            if let Err(e) = fs::remove_dir_all(&tmp) {
+                error!("could not remove incomplete snapshot {:?}: {}", tmp, e);
+            }
+        })
+    }
+
+    fn write(self) -> io::Result<PathBuf> {
+        for tail in self.tails {
+            let mut dest = File::create(&tail.dest)?;
+            let copied = io::copy(&mut tail.file.take(tail.len), &mut dest)?;
+            if copied != tail.len {
+                let msg = format!("{:?} was truncated while being copied", tail.dest);
+                return Err(io::Error::new(ErrorKind::UnexpectedEof, msg));
+            }
+            dest.sync_all()?;
+        }
+        for fetch in self.fetches {
+            fetch.archive.fetch(&fetch.name, &fetch.dest)?;
+        }
+
+        let (files, dirs) = walk(&self.tmp)?;
+        let mut checksums = String::new();
+        for path in files {
+            let (crc, size) = checksum(&self.tmp.join(&path))?;
+            checksums.push_str(&format!("{:08x} {} {}\n", crc, size, path.display()));
+        }
+        for dir in dirs {
+            File::open(dir)?.sync_all()?;
+        }
+        let mut file = File::create(self.tmp.join(CHECKSUMS_FILE))?;
+        file.write_all(checksums.as_bytes())?;
+        file.sync_all()?;
+        File::open(&self.tmp)?.sync_all()?;
+
+        fs::rename(&self.tmp, &self.path)?;
+        if let Some(dir) = self.path.parent() {
+            File::open(dir)?.sync_all()?;
+        }
+        Ok(self.path)
+    }
+
+    /// The path of `dest` within the temporary directory, creating its parent
+    /// directories.
+    fn dest(&self, dest: &Path) -> io::Result<PathBuf> {
+        let path = self.tmp.join(dest);
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        Ok(path)
+    }
+}
+
+/// Checks every file in the Snapshot at `path` against its CHECKSUMS file.
+/// Fails with `NotFound` if there is no Snapshot there, or it was never
+/// finished, and with `InvalidData` if any file is missing, changed, or not
+/// listed.
+pub fn verify(path: &Path) -> io::Result<()> {
+    let listed = fs::read_to_string(path.join(CHECKSUMS_FILE))?;
+    let invalid = |msg: String| io::Error::new(ErrorKind::InvalidData, msg);
+
+    let mut expected = HashSet::new();
+    for line in listed.lines() {
+        let mut fields = line.splitn(3, ' ');
+        let parsed = match (fields.next(), fields.next(), fields.next()) {
+            (Some(crc), Some(size), Some(rel)) => u32::from_str_radix(crc, 16)
+                .ok()
+                .zip(size.parse::<u64>().ok())
+                .map(|sum| (sum, PathBuf::from(rel))),
+            _ => None,
+        };
+        let (sum, rel) = match parsed {
+            Some(parsed) => parsed,
+            None => return Err(invalid(format!("bad line in {:?}: {:?}", path, line))),
+        };
+
+        match checksum(&path.join(&rel)) {
+            Ok(actual) if actual == sum => (),
+            Ok(_) => return Err(invalid(format!("{:?} in snapshot has changed", rel))),
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                return Err(invalid(format!("{:?} is missing from snapshot", rel)))
+            }
+            Err(e) => return Err(e),
+        }
+        expected.insert(rel);
+    }
+
+    let (files, _) = walk(path)?;
+    for rel in files {
+        if rel != Path::new(CHECKSUMS_FILE) && !expected.contains(&rel) {
+            return Err(invalid(format!("{:?} in snapshot is not listed", rel)));
+        }
+    }
+    Ok(())
+}
+
+/// Durably copies every file under `src` to the same place under `dest`.
+/// Files are always copied rather than linked, since a restored Log appends to
+/// its last Segment.
+pub fn copy_tree(src: &Path, dest: &Path) -> io::Result<()> {
+    let (files, _) = walk(src)?;
+    fs::create_dir_all(dest)?;
+    for rel in files {
+        let to = dest.join(&rel);
+        if let Some(dir) = to.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::copy(src.join(&rel), &to)?;
+        File::open(&to)?.sync_all()?;
+    }
+
+    let (_, dirs) = walk(dest)?;
+    for dir in dirs {
+        File::open(dir)?.sync_all()?;
+    }
+    if let Some(dir) = dest.parent() {
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
+/// Every file under `root`, relative to it and in a stable order, along with
+/// every directory under it.
+fn walk(root: &Path) -> io::Result<(Vec<PathBuf>, Vec<PathBuf>)> {
+    let mut files = vec![];
+    let mut dirs = vec![];
+    let mut pending = vec![root.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        let mut entries = fs::read_dir(&dir)?.collect::<io::Result<Vec<_>>>()?;
+        entries.sort_by_key(|e| e.file_name());
+        for entry in entries {
+            let path = entry.path();
+            if entry.file_type()?.is_dir() {
+                pending.push(path);
+            } else if let Ok(rel) = path.strip_prefix(root) {
+                files.push(rel.to_path_buf());
+            }
+        }
+        dirs.push(dir);
+    }
+    Ok((files, dirs))
+}
+
+/// The CRC32 and size of the file at `path`
+pub fn checksum(path: &Path) -> io::Result<(u32, u64)> {
+    let mut file = File::open(path)?;
+    let mut hasher = crc32fast::Hasher::new();
+    let mut buf = vec![0; 64 * 1024];
+    let mut size = 0;
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+        size += n as u64;
+    }
+    Ok((hasher.finalize(), size))
+}
+
+/// Returns the names of the Snapshots in `dir` that hold `rel`, including
+/// any that are still being taken, sorted by name.
+pub fn holding(dir: &Path, rel: &Path) -> io::Result<Vec<String>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e),
+    };
+    let mut names = vec![];
+    for entry in entries {
+        let path = entry?.path();
+        let name = match path.file_stem().and_then(|n| n.to_str()) {
+            Some(name) if valid_name(name) => name,
+            _ => continue,
+        };
+        if path.join(rel).exists() {
+            names.push(name.to_string());
+        }
+    }
+    names.sort();
+    names.dedup();
+    Ok(names)
+}
+
+/// Snapshot names become directory names, so they are kept to letters,
+/// digits, `-` and `_`.
+pub fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::archive::LocalDir;
+
+    #[test]
+    fn test_snapshot() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("src");
+        fs::create_dir_all(&src).unwrap();
+        fs::write(src.join("sealed"), b"sealed").unwrap();
+        fs::write(src.join("active"), b"active").unwrap();
+        let archive = Arc::new(LocalDir::new(dir.path().join("cold")));
+        archive.put("log/old", &src.join("sealed")).unwrap();
+
+        let snapshots = dir.path().join("snapshots");
+        let mut snap = Snapshot::begin(&snapshots, "s1").unwrap();
+        snap.link(&src.join("sealed"), Path::new("log/sealed"))
+            .unwrap();
+        snap.copy_tail(&src.join("active"), 3, Path::new("log/active"))
+            .unwrap();
+        snap.fetch(archive, "log/old".into(), Path::new("log/old"))
+            .unwrap();
+
+        // Later writes and deletes don't reach the Snapshot
+        fs::remove_file(src.join("sealed")).unwrap();
+        fs::write(src.join("active"), b"active and more").unwrap();
+        let path = snap.finish().unwrap();
+        assert_eq!(path, snapshots.join("s1"));
+        assert!(!snapshots.join("s1.tmp").exists());
+        assert_eq!(fs::read(path.join("log/sealed")).unwrap(), b"sealed");
+        assert_eq!(fs::read(path.join("log/active")).unwrap(), b"act");
+        assert_eq!(fs::read(path.join("log/old")).unwrap(), b"sealed");
+
+        let checksums = fs::read_to_string(path.join(CHECKSUMS_FILE)).unwrap();
+        let (crc, _) = checksum(&path.join("log/active")).unwrap();
+        assert!(checksums.contains(&format!("{:08x} 3 log/active\n", crc)));
+        assert_eq!(checksums.lines().count(), 3);
+
+        let err = Snapshot::begin(&snapshots, "s1").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+    }
+
+    #[test]
+    fn test_snapshot_verify() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("src");
+        fs::create_dir_all(&src).unwrap();
+        fs::write(src.join("segment"), b"segment").unwrap();
+        let mut snap = Snapshot::begin(dir.path(), "s1").unwrap();
+        snap.link(&src.join("segment"), Path::new("log/segment"))
+            .unwrap();
+        let path = snap.finish().unwrap();
+        verify(&path).unwrap();
+
+        let copy = dir.path().join("copy");
+        copy_tree(&path, &copy).unwrap();
+        verify(&copy).unwrap();
+
+        fs::write(copy.join("log/extra"), b"extra").unwrap();
+        assert_eq!(verify(&copy).unwrap_err().kind(), ErrorKind::InvalidData);
+        fs::remove_file(copy.join("log/extra")).unwrap();
+        fs::write(copy.join("log/segment"), b"changed").unwrap();
+        assert_eq!(verify(&copy).unwrap_err().kind(), ErrorKind::InvalidData);
+        fs::remove_file(copy.join("log/segment")).unwrap();
+        assert_eq!(verify(&copy).unwrap_err().kind(), ErrorKind::InvalidData);
+
+        let missing = dir.path().join("missing");
+        assert_eq!(verify(&missing).unwrap_err().kind(), ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_snapshot_holding() {
+        let dir = tempfile::tempdir().unwrap();
+        let snapshots = dir.path().join("snapshots");
+        assert!(holding(&snapshots, Path::new("log")).unwrap().is_empty());
+
+        let src = dir.path().join("src");
+        fs::create_dir_all(&src).unwrap();
+        fs::write(src.join("segment"), b"segment").unwrap();
+        for name in ["s1", "s2"].iter() {
+            let mut snap = Snapshot::begin(&snapshots, name).unwrap();
+            snap.link(&src.join("segment"), Path::new("log/segment"))
+                .unwrap();
+            if *name == "s1" {
+                snap.finish().unwrap();
+            }
+        }
+        Snapshot::begin(&snapshots, "other")
+            .unwrap()
+            .finish()
+            .unwrap();
+
+        // Snapshots still being taken are included
+        assert_eq!(
+            holding(&snapshots, Path::new("log")).unwrap(),
+            vec!["s1", "s2"]
+        );
+    }
+
+    #[test]
+    fn test_snapshot_valid_name() {
+        assert!(valid_name("nightly-2020_01"));
+        assert!(!valid_name(""));
+        assert!(!valid_name("../data"));
+        assert!(!valid_name("a.tmp"));
+    }
+}
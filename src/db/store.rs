@@ -0,0 +1,325 @@
+use super::archive::Fetch;
+use super::logs::{self, Commit};
+use super::segment::{Record, Sealed};
+use super::snapshot::Snapshot;
+use crate::commands::LogOptions;
+use crate::errors::Error;
+use std::borrow::Cow;
+use std::collections::VecDeque;
+use std::fmt::Debug;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The Messages read back from a LogStore, in offset order
+pub type MessageIter<'a> = Box<dyn Iterator<Item = io::Result<Record<'a>>> + 'a>;
+
+/// Where a Log's Messages are stored. The DB only works with Logs through this
+/// trait, so they can live in Segment files on disk, as a `logs::Log`, or in
+/// memory, as a `MemoryLog`.
+pub trait LogStore: Debug + Send + Sync {
+    fn options(&self) -> LogOptions;
+
+    /// The offset of the oldest Message still held by the Log
+    fn first_offset(&self) -> usize;
+
+    /// Number of Messages ever written to the Log. This is also the offset the
+    /// next Message will be written to.
+    fn len(&self) -> usize;
+
+    /// Finds the offset of the first Message added at or after `timestamp`, in
+    /// milliseconds since the Unix epoch. Returns the Log's length if every
+    /// Message is older.
+    fn offset_for_time(&self, timestamp: u64) -> io::Result<usize>;
+
+    /// Returns an iterator over the Log's Messages, starting at the first one
+    /// at or after `offset`.
+    fn messages(&self, offset: usize) -> io::Result<MessageIter<'_>>;
+
+    /// Appends `msg` to the Log. An empty key is the same as no key.
+    fn add_msg(&mut self, key: Option<&[u8]>, msg: Vec<u8>) -> Result<(), Error>;
+
+    /// Replaces the Message at `offset` with a tombstone that keeps its offset,
+    /// timestamp and key. Fails with `NotFound` if there is no Message at
+    /// `offset`.
+    fn redact(&mut self, offset: usize) -> io::Result<()>;
+
+    /// Drops Messages that fall outside the Log's Retention limits. Returns the
+    /// number of Segments, or Messages for stores without them, dropped.
+    fn enforce_retention(&mut self) -> io::Result<usize>;
+
+    /// Removes the Log and everything stored for it.
+    fn destroy(self: Box<Self>) -> io::Result<()>;
+
+    /// The Log's Segment files, if it is stored in them
+    fn segmented(&self) -> Option<&dyn Segmented> {
+        None
+    }
+
+    /// Like `segmented`, for upkeep that changes the Log
+    fn segmented_mut(&mut self) -> Option<&mut dyn Segmented> {
+        None
+    }
+}
+
+/// The upkeep of a Log stored in Segment files, which only a `logs::Log` has.
+/// Syncing, group commits, compression, compaction, archiving and Snapshots
+/// all work on Segment files, so the DB reaches them through
+/// `LogStore::segmented` and skips Logs stored any other way.
+pub trait Segmented: LogStore {
+    /// Appends `msg`, leaving syncing it to a later group commit if the Log
+    /// syncs every Message. Returns the offset to wait on with `synced_len` in
+    /// that case, or None if the Message is already durable enough.
+    fn add_msg_deferred(
+        &mut self,
+        key: Option<&[u8]>,
+        msg: Vec<u8>,
+    ) -> Result<Option<usize>, Error>;
+
+    /// The offset just past the last Message known to be durable
+    fn synced_len(&self) -> usize;
+
+    /// Starts a group commit of every Message appended but not yet synced,
+    /// or returns None if there aren't any.
+    fn begin_commit(&self) -> io::Result<Option<Commit>>;
+
+    /// Marks the Messages synced by `commit` as durable.
+    fn finish_commit(&mut self, commit: Commit);
+
+    /// Drops every Message appended since the last sync, after a failed
+    /// commit.
+    fn discard_unsynced(&mut self) -> io::Result<()>;
+
+    /// Syncs the Log if its fsync interval has passed. Returns how long to wait
+    /// before flushing again, or None if it never needs flushing.
+    fn flush(&mut self) -> io::Result<Option<Duration>>;
+
+    /// Sealed Segments waiting to be compressed
+    fn uncompressed_segments(&self) -> Vec<Sealed>;
+
+    /// Sealed Segments waiting to be compacted
+    fn compaction_due(&self) -> Vec<Sealed>;
+
+    /// Records that every sealed Segment before `offset` has been compacted.
+    fn set_compacted_through(&mut self, offset: usize);
+
+    /// Swaps a sealed Segment for a rewritten copy of it.
+    fn install_rewritten(&mut self, source: &Sealed, path: PathBuf) -> io::Result<()>;
+
+    /// Sealed Segments ready to be moved to the Archive, along with the name to
+    /// store each as
+    fn archive_due(&self, after: Duration) -> Vec<(Sealed, String)>;
+
+    /// Replaces a sealed Segment with its copy in the Archive.
+    fn install_archived(&mut self, source: &Sealed, name: String) -> io::Result<()>;
+
+    /// Drops fetched copies of archived Segments that haven't been read lately.
+    /// Returns the number dropped.
+    fn evict_fetched(&mut self) -> io::Result<usize>;
+
+    /// Fetches of archived Segments that reads stopped at, to be run without
+    /// the DB locked
+    fn pending_fetches(&self) -> Vec<Fetch>;
+
+    /// Adds the Log's files to `snap` under `dest`, as they are right now.
+    fn snapshot(&self, snap: &mut Snapshot, dest: &Path) -> io::Result<()>;
+}
+
+/// A Log kept entirely in memory, which is lost when the server stops. It is
+/// meant for tests and for Logs used as ephemeral caches.
+///
+/// Retention limits are applied a Message at a time rather than a Segment at
+/// a time. Compression and compaction are ignored, and MemoryLogs can't be
+/// archived or included in Snapshots.
+#[derive(Debug, Default)]
+pub struct MemoryLog {
+    options: LogOptions,
+    /// The Messages still held by the Log, starting at `first_offset`
+    records: VecDeque<Record<'static>>,
+    first_offset: usize,
+    last_timestamp: u64,
+}
+
+impl MemoryLog {
+    pub fn new(options: LogOptions) -> Self {
+        MemoryLog {
+            options,
+            ..Default::default()
+        }
+    }
+
+    /// Where the Message at `offset` is in `records`, if the Log holds it
+    fn index(&self, offset: usize) -> Option<usize> {
+        offset
+            .checked_sub(self.first_offset)
+            .filter(|&i| i < self.records.len())
+    }
+
+    /// Whether the oldest Message falls outside one of the Retention limits
+    fn front_expired(&self, bytes: u64, now: u64) -> bool {
+        let retention = self.options.retention;
+        let oldest = match self.records.front() {
+            Some(oldest) => oldest,
+            None => return false,
+        };
+        retention
+            .max_messages
+            .is_some_and(|max| self.records.len() as u64 > max)
+            || retention.max_bytes.is_some_and(|max| bytes > max)
+            || retention
+                .max_age_secs
+                .is_some_and(|max| now.saturating_sub(oldest.timestamp) > max * 1000)
+    }
+}
+
+fn record_size(record: &Record) -> u64 {
+    (record.key.as_ref().map_or(0, |k| k.len()) + record.msg.len()) as u64
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+impl LogStore for MemoryLog {
+    fn options(&self) -> LogOptions {
+        self.options
+    }
+
+    fn first_offset(&self) -> usize {
+        self.first_offset
+    }
+
+    fn len(&self) -> usize {
+        self.first_offset + self.records.len()
+    }
+
+    fn offset_for_time(&self, timestamp: u64) -> io::Result<usize> {
+        let idx = self.records.partition_point(|r| r.timestamp < timestamp);
+        Ok(self.first_offset + idx)
+    }
+
+    fn messages(&self, offset: usize) -> io::Result<MessageIter<'_>> {
+        let start = offset
+            .saturating_sub(self.first_offset)
+            .min(self.records.len());
+        let records = self.records.range(start..).map(|r| {
+            Ok(Record {
+                offset: r.offset,
+                timestamp: r.timestamp,
+                key: r.key.as_deref().map(Cow::Borrowed),
+                msg: Cow::Borrowed(&r.msg),
+            })
+        });
+        Ok(Box::new(records))
+    }
+
+    fn add_msg(&mut self, key: Option<&[u8]>, msg: Vec<u8>) -> Result<(), Error> {
+        logs::check_msg(&self.options, key, &msg)?;
+        let timestamp = now_millis().max(self.last_timestamp);
+        self.records.push_back(Record {
+            offset: self.len(),
+            timestamp,
+            key: key
+                .filter(|k| !k.is_empty())
+                .map(|k| Cow::Owned(k.to_vec())),
+            msg: Cow::Owned(msg),
+        });
+        self.last_timestamp = timestamp;
+        Ok(())
+    }
+
+    fn redact(&mut self, offset: usize) -> io::Result<()> {
+        match self.index(offset) {
+            Some(i) => {
+                self.records[i].msg = Cow::Owned(vec![]);
+                Ok(())
+            }
+            None => {
+                let msg = format!("log has no message {}", offset);
+                Err(io::Error::new(io::ErrorKind::NotFound, msg))
+            }
+        }
+    }
+
+    fn enforce_retention(&mut self) -> io::Result<usize> {
+        let now = now_millis();
+        let mut bytes: u64 = self.records.iter().map(record_size).sum();
+        let mut dropped = 0;
+        while self.front_expired(bytes, now) {
+            if let Some(oldest) = self.records.pop_front() {
+                bytes -= record_size(&oldest);
+                self.first_offset += 1;
+                dropped += 1;
+            }
+        }
+        Ok(dropped)
+    }
+
+    fn destroy(self: Box<Self>) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::Retention;
+
+    fn offsets(log: &dyn LogStore, from: usize) -> Vec<usize> {
+        log.messages(from)
+            .unwrap()
+            .map(|r| r.unwrap().offset)
+            .collect()
+    }
+
+    #[test]
+    fn test_memory_log() {
+        let mut log = MemoryLog::new(LogOptions::default());
+        for i in 0..4 {
+            log.add_msg(None, vec![0x19, 0x03, i]).unwrap();
+        }
+        log.add_msg(Some(b"k"), vec![0x01]).unwrap();
+        assert_eq!(log.add_msg(None, vec![0xFF]), Err(Error::MsgNotValidCbor));
+
+        assert_eq!(log.len(), 5);
+        assert_eq!(offsets(&log, 2), vec![2, 3, 4]);
+        let last = log.messages(4).unwrap().next().unwrap().unwrap();
+        assert_eq!(last.key.as_deref(), Some(&b"k"[..]));
+        assert_eq!(log.offset_for_time(0).unwrap(), 0);
+        assert_eq!(log.offset_for_time(u64::MAX).unwrap(), 5);
+
+        log.redact(1).unwrap();
+        assert!(log
+            .messages(1)
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap()
+            .is_redacted());
+        let err = log.redact(5).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_memory_log_retention() {
+        let options = LogOptions {
+            retention: Retention {
+                max_messages: Some(3),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let mut log = MemoryLog::new(options);
+        for i in 0..5 {
+            log.add_msg(None, vec![0x19, 0x03, i]).unwrap();
+        }
+        assert_eq!(log.enforce_retention().unwrap(), 2);
+        assert_eq!(log.first_offset(), 2);
+        assert_eq!(log.len(), 5);
+        assert_eq!(offsets(&log, 0), vec![2, 3, 4]);
+        assert_eq!(log.redact(1).unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+}
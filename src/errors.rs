@@ -1,7 +1,7 @@
 use bytes::Bytes;
 use serde::Serialize;
 
-#[derive(Debug, PartialEq, Eq, ToPrimitive, Serialize)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ToPrimitive, Serialize)]
 pub enum Error {
     // DB Errors
     LogDoesNotExist = 0x00,
@@ -26,6 +26,37 @@ pub enum Error {
     ItrFuncNotUtf8 = 0x0F,
     ItrTypeInvalid = 0x10,
     MsgIdNotNumber = 0x11,
+
+    // Storage Errors
+    ErrReadingLog = 0x12,
+    ErrWritingLog = 0x13,
+    ErrWritingManifest = 0x14,
+    LogCorrupted = 0x15,
+    MsgExpired = 0x16,
+    MsgKeyTooLong = 0x17,
+
+    // Snapshot Errors
+    SnapshotNameInvalid = 0x18,
+    SnapshotExists = 0x19,
+    ErrWritingSnapshot = 0x1A,
+    SnapshotDoesNotExist = 0x1B,
+    SnapshotCorrupted = 0x1C,
+    LogAlreadyExists = 0x1D,
+
+    // Admin Errors
+    NotAuthorized = 0x1E,
+    MsgDoesNotExist = 0x1F,
+
+    // Size Limit Errors
+    FrameTooLarge = 0x20,
+    MsgTooLarge = 0x21,
+
+    // Iterator Source Errors
+    ItrSourceInvalid = 0x22,
+    ItrHasDependents = 0x23,
+
+    // Log Option Errors
+    LogOptionsInvalid = 0x24,
 }
 
 impl Error {
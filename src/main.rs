@@ -3,8 +3,10 @@ extern crate log;
 #[macro_use]
 extern crate num_derive;
 
+use commands::Command;
 use protocol::Connection;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use tokio::net::TcpListener;
 
 mod commands;
@@ -13,6 +15,8 @@ mod db;
 mod errors;
 mod protocol;
 
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(30);
+
 async fn handle(db: Arc<Mutex<db::DB>>, mut conn: Connection) {
     debug!("accepting connection");
 
@@ -26,13 +30,103 @@ async fn handle(db: Arc<Mutex<db::DB>>, mut conn: Connection) {
         };
         debug!("received command: {:?}", &cmd);
 
-        let resp = db.lock().unwrap().exec(cmd);
+        let resp = match cmd {
+            // Snapshots take a while, and only need the DB locked briefly.
+            Command::Snapshot(commands::Snapshot { snapshot_name }) => {
+                let db = db.clone();
+                let res =
+                    tokio::task::spawn_blocking(move || db::snapshot(&db, &snapshot_name)).await;
+                res.unwrap_or_else(|e| {
+                    error!("snapshot failed: {}", e);
+                    errors::Error::ErrWritingSnapshot.into()
+                })
+            }
+            Command::SnapshotImport(commands::SnapshotImport {
+                snapshot_name,
+                log_names,
+            }) => {
+                let db = db.clone();
+                let res =
+                    tokio::task::spawn_blocking(move || db::import(&db, &snapshot_name, log_names))
+                        .await;
+                res.unwrap_or_else(|e| {
+                    error!("snapshot import failed: {}", e);
+                    errors::Error::ErrWritingLog.into()
+                })
+            }
+            // Reads and redactions may have to fetch archived Segments,
+            // which is done without holding the DB.
+            cmd @ (Command::IteratorNext(_) | Command::MessageRedact(_)) => {
+                let db = db.clone();
+                let res = tokio::task::spawn_blocking(move || db::exec_fetching(&db, cmd)).await;
+                res.unwrap_or_else(|e| {
+                    error!("reading from archived segments failed: {}", e);
+                    errors::Error::ErrReadingLog.into()
+                })
+            }
+            // Indexes of new Iterators are backfilled a batch at a time.
+            Command::IteratorAdd(add) => {
+                let db = db.clone();
+                let res = tokio::task::spawn_blocking(move || db::itr_add(&db, add)).await;
+                res.unwrap_or_else(|e| {
+                    error!("adding iterator failed: {}", e);
+                    errors::Error::ErrWritingLog.into()
+                })
+            }
+            // Appends wait on a group commit without holding the DB.
+            Command::MessageAdd(add) => {
+                let done = db.lock().unwrap().msg_add_grouped(add);
+                done.await
+                    .unwrap_or_else(|_| errors::Error::ErrWritingLog.into())
+            }
+            cmd => db.lock().unwrap().exec(cmd),
+        };
         conn.respond(resp).await;
     }
 
     debug!("closing connection");
 }
 
+async fn flush_logs(db: Arc<Mutex<db::DB>>) {
+    loop {
+        let wait = db.lock().unwrap().flush_logs();
+        tokio::time::delay_for(wait).await;
+    }
+}
+
+/// Runs a group commit whenever MessageAdds are waiting on one. Appends that
+/// come in while a commit is running are batched into the next one.
+async fn commit_logs(db: Arc<Mutex<db::DB>>) {
+    let wanted = db.lock().unwrap().commit_wanted();
+    loop {
+        wanted.notified().await;
+        let db = db.clone();
+        if let Err(e) = tokio::task::spawn_blocking(move || db::commit_logs(&db)).await {
+            error!("group commit failed: {}", e);
+        }
+    }
+}
+
+/// Periodically drops expired Segments, then compresses, compacts and archives
+/// sealed ones. All of these touch the disk, so they are run on the blocking
+/// thread pool.
+async fn maintain_logs(db: Arc<Mutex<db::DB>>) {
+    loop {
+        let db_ = db.clone();
+        let res = tokio::task::spawn_blocking(move || {
+            db_.lock().unwrap().enforce_retention();
+            db::compress_segments(&db_);
+            db::compact_logs(&db_);
+            db::archive_segments(&db_);
+        })
+        .await;
+        if let Err(e) = res {
+            error!("log maintenance failed: {}", e);
+        }
+        tokio::time::delay_for(MAINTENANCE_INTERVAL).await;
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let cfg = config::load();
@@ -41,11 +135,21 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let mut listener = TcpListener::bind(cfg.addr()).await?;
     info!("listening on {}", cfg.addr());
 
-    let db = Arc::new(Mutex::new(db::DB::new()));
+    if let Some(from) = &cfg.restore_from {
+        info!("restoring from snapshot {:?}", from);
+        db::restore(&cfg, from)?;
+    }
+    let db = Arc::new(Mutex::new(db::DB::new(&cfg)?));
+    tokio::spawn(flush_logs(db.clone()));
+    tokio::spawn(commit_logs(db.clone()));
+    tokio::spawn(maintain_logs(db.clone()));
+
+    let max_frame_size = cfg.max_frame_size();
     loop {
         match listener.accept().await {
             Ok((socket, _)) => {
-                tokio::spawn(handle(db.clone(), socket.into()));
+                let conn = Connection::new(socket, max_frame_size);
+                tokio::spawn(handle(db.clone(), conn));
             }
             Err(e) => error!("error accepting listener: {}", e),
         }
@@ -0,0 +1,109 @@
+use crate::errors::Error;
+use bytes::{Buf, Bytes, BytesMut};
+use std::io;
+use tokio_util::codec::{Decoder, Encoder, LengthDelimitedCodec};
+
+/// Frames are prefixed with their length as a 4 byte big endian integer.
+const LEN_SIZE: usize = 4;
+
+/// Splits a connection into length delimited Frames, like
+/// `LengthDelimitedCodec`, but with a limit on the size of incoming Frames.
+///
+/// A Frame over the limit is decoded as a `FrameTooLarge` error and its
+/// contents are skipped as they arrive, so the client can be told why it was
+/// rejected and the connection stays usable. Outgoing Frames aren't limited.
+#[derive(Debug)]
+pub struct FrameCodec {
+    inner: LengthDelimitedCodec,
+    max_frame_size: usize,
+    /// How many more bytes of a rejected Frame are still to be skipped
+    skipping: usize,
+}
+
+impl FrameCodec {
+    pub fn new(max_frame_size: usize) -> Self {
+        let inner = LengthDelimitedCodec::builder()
+            .max_frame_length(u32::MAX as usize)
+            .new_codec();
+        FrameCodec {
+            inner,
+            max_frame_size,
+            skipping: 0,
+        }
+    }
+}
+
+impl Decoder for FrameCodec {
+    type Item = Result<BytesMut, Error>;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<Self::Item>> {
+        if self.skipping > 0 {
+            let n = self.skipping.min(src.len());
+            src.advance(n);
+            self.skipping -= n;
+            if self.skipping > 0 {
+                return Ok(None);
+            }
+        }
+        if src.len() < LEN_SIZE {
+            return Ok(None);
+        }
+
+        let len = u32::from_be_bytes([src[0], src[1], src[2], src[3]]) as usize;
+        if len > self.max_frame_size {
+            warn!(
+                "rejecting frame of {} bytes, over the limit of {}",
+                len, self.max_frame_size
+            );
+            let n = (LEN_SIZE + len).min(src.len());
+            src.advance(n);
+            self.skipping = LEN_SIZE + len - n;
+            return Ok(Some(Err(Error::FrameTooLarge)));
+        }
+
+        Ok(self.inner.decode(src)?.map(Ok))
+    }
+}
+
+impl Encoder<Bytes> for FrameCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, data: Bytes, dst: &mut BytesMut) -> io::Result<()> {
+        self.inner.encode(data, dst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(body: &[u8]) -> Vec<u8> {
+        [&(body.len() as u32).to_be_bytes()[..], body].concat()
+    }
+
+    #[test]
+    fn test_codec_frame_too_large() {
+        let mut codec = FrameCodec::new(4);
+        let mut src = BytesMut::new();
+
+        src.extend_from_slice(&frame(b"ok"));
+        assert_eq!(codec.decode(&mut src).unwrap(), Some(Ok(b"ok"[..].into())));
+
+        // The rejected Frame is skipped even when it arrives in pieces
+        let big = frame(b"too large");
+        src.extend_from_slice(&big[..6]);
+        assert_eq!(
+            codec.decode(&mut src).unwrap(),
+            Some(Err(Error::FrameTooLarge))
+        );
+        assert_eq!(codec.decode(&mut src).unwrap(), None);
+        src.extend_from_slice(&big[6..]);
+        src.extend_from_slice(&frame(b"next"));
+        assert_eq!(
+            codec.decode(&mut src).unwrap(),
+            Some(Ok(b"next"[..].into()))
+        );
+        assert!(src.is_empty());
+    }
+}
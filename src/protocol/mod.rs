@@ -5,7 +5,10 @@ use futures::SinkExt;
 use num_traits::{FromPrimitive, ToPrimitive};
 use tokio::net::TcpStream;
 use tokio::stream::StreamExt;
-use tokio_util::codec::{Framed, LengthDelimitedCodec};
+use tokio_util::codec::Framed;
+
+mod codec;
+use codec::FrameCodec;
 
 #[derive(FromPrimitive, ToPrimitive, PartialEq, Eq)]
 enum FrameKind {
@@ -26,13 +29,24 @@ enum RequestCodes {
     IteratorList = 0x06,
     IteratorNext = 0x07,
     IteratorDelete = 0x08,
+    Snapshot = 0x09,
+    SnapshotImport = 0x0A,
+    MessageRedact = 0x0B,
+    RedactionList = 0x0C,
 }
 
 pub struct Connection {
-    framer: Framed<TcpStream, LengthDelimitedCodec>,
+    framer: Framed<TcpStream, FrameCodec>,
 }
 
 impl Connection {
+    /// Wraps `socket`, rejecting any request Frame larger than
+    /// `max_frame_size` bytes.
+    pub fn new(socket: TcpStream, max_frame_size: usize) -> Connection {
+        let framer = Framed::new(socket, FrameCodec::new(max_frame_size));
+        Connection { framer }
+    }
+
     pub async fn next_request(&mut self) -> Option<Result<Command, Error>> {
         let frame = match self.framer.next().await {
             Some(f) => f,
@@ -40,7 +54,8 @@ impl Connection {
         };
 
         let result = match frame {
-            Ok(bytes) => read_command(bytes),
+            Ok(Ok(bytes)) => read_command(bytes),
+            Ok(Err(e)) => Err(e),
             Err(_) => Err(Error::FailedToReadBytes),
         };
 
@@ -55,13 +70,6 @@ impl Connection {
     }
 }
 
-impl From<TcpStream> for Connection {
-    fn from(socket: TcpStream) -> Connection {
-        let framer = Framed::new(socket, LengthDelimitedCodec::new());
-        Connection { framer }
-    }
-}
-
 macro_rules! parse_cbor {
     ($cmd:ident, $data:expr) => {{
         let c = match serde_cbor::from_slice($data) {
@@ -100,6 +108,10 @@ fn read_command(bytes: BytesMut) -> Result<Command, Error> {
         IteratorList => parse_cbor!(IteratorList, data),
         IteratorNext => parse_cbor!(IteratorNext, data),
         IteratorDelete => parse_cbor!(IteratorDelete, data),
+        Snapshot => parse_cbor!(Snapshot, data),
+        SnapshotImport => parse_cbor!(SnapshotImport, data),
+        MessageRedact => parse_cbor!(MessageRedact, data),
+        RedactionList => parse_cbor!(RedactionList, data),
     };
 
     Ok(cmd)
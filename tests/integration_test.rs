@@ -71,12 +71,47 @@ async fn integration_tests() {
     assert_eq!(kind, 0x02);
     assert_eq!(code, 0x00);
 
-    // Remove byte length
-    let mut msg = &payload[4..];
+    #[derive(Debug, Eq, PartialEq, Deserialize)]
+    struct Cursor {
+        next_offset: usize,
+        end_of_log: bool,
+        high_water_mark: usize,
+    }
 
+    // The first item is the page's cursor, each prefixed by its byte length
+    let len = u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]]) as usize;
+    let cursor: Cursor = serde_cbor::from_slice(&payload[4..4 + len]).unwrap();
+    assert_eq!(
+        cursor,
+        Cursor {
+            next_offset: 1,
+            end_of_log: true,
+            high_water_mark: 1,
+        }
+    );
+
+    let mut msg = &payload[4 + len + 4..];
     let resp: Msg = serde_cbor::from_reader(&mut msg).unwrap();
     assert_eq!(resp, test_msg);
 
+    println!("test: negative message ids count back from the end of the log");
+    let (kind, code, tail) = send_req(framer, new_itr_next_req("itr", -1, 1)).await;
+    assert_eq!(kind, 0x02);
+    assert_eq!(code, 0x00);
+    let items = split_items(&tail);
+    assert_eq!(items.len(), 2);
+    let resp: Msg = serde_cbor::from_slice(items[1]).unwrap();
+    assert_eq!(resp, test_msg);
+
+    println!("test: can read from a timestamp");
+    let (kind, code, since) = send_req(framer, new_itr_next_time_req("itr", 0, 1)).await;
+    assert_eq!(kind, 0x02);
+    assert_eq!(code, 0x00);
+    let items = split_items(&since);
+    assert_eq!(items.len(), 2);
+    let resp: Msg = serde_cbor::from_slice(items[1]).unwrap();
+    assert_eq!(resp, test_msg);
+
     let (kind, code, payload) = send_req(framer, new_log_list_req()).await;
     assert_eq!(kind, 0x02);
     assert_eq!(code, 0x00);
@@ -141,11 +176,11 @@ fn new_log_list_req() -> Vec<u8> {
     vec![0x00, 0x03]
 }
 
-fn new_itr_next_req(name: &str, message_id: usize, count: usize) -> Vec<u8> {
+fn new_itr_next_req(name: &str, message_id: i64, count: usize) -> Vec<u8> {
     #[derive(Serialize)]
     struct Body {
         iterator_name: String,
-        message_id: usize,
+        message_id: i64,
         count: usize,
     }
 
@@ -160,6 +195,36 @@ fn new_itr_next_req(name: &str, message_id: usize, count: usize) -> Vec<u8> {
     body
 }
 
+fn new_itr_next_time_req(name: &str, timestamp: u64, count: usize) -> Vec<u8> {
+    #[derive(Serialize)]
+    struct Body {
+        iterator_name: String,
+        timestamp: u64,
+        count: usize,
+    }
+
+    let mut body = vec![0x00, 0x07];
+    let req = serde_cbor::to_vec(&Body {
+        iterator_name: name.into(),
+        timestamp,
+        count,
+    })
+    .unwrap();
+    body.extend(req);
+    body
+}
+
+/// Splits a Data Response payload into its length prefixed items
+fn split_items(mut payload: &[u8]) -> Vec<&[u8]> {
+    let mut items = vec![];
+    while payload.len() >= 4 {
+        let len = u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]]) as usize;
+        items.push(&payload[4..4 + len]);
+        payload = &payload[4 + len..];
+    }
+    items
+}
+
 // returns Kind, Code, and Payload
 async fn send_req(
     framer: &mut Framed<TcpStream, LengthDelimitedCodec>,